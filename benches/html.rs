@@ -0,0 +1,71 @@
+//! Block HTML is given to the writers a line at a time. These check that converting it takes time
+//! in proportion to its size: the throughput should stay the same as the row count grows.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use markrust::{Converter, RenderOptions};
+
+/// An HTML table with a line for each row
+///
+/// # Arguments
+///
+/// * `rows` - how many rows the table has
+fn table(rows: usize) -> String {
+    let mut html = String::from("<table>\n<tr><th>name</th><th>value</th></tr>\n");
+    for row in 0..rows {
+        html.push_str(&format!(
+            "<tr><td>row {}</td><td><b>{}</b></td></tr>\n",
+            row, row
+        ));
+    }
+    html.push_str("</table>\n\nafter the table\n");
+    html
+}
+
+/// HTML whose first element is never closed, so everything after it is held until the end
+///
+/// # Arguments
+///
+/// * `rows` - how many lines follow the unclosed element
+fn unclosed(rows: usize) -> String {
+    let mut html = String::from("<div>\n");
+    for row in 0..rows {
+        html.push_str(&format!("<span>line {}</span>\n", row));
+    }
+    html
+}
+
+/// A comment with a line for each row, which ends after all of them
+///
+/// # Arguments
+///
+/// * `rows` - how many lines the comment has
+fn comment(rows: usize) -> String {
+    let mut html = String::from("<!--\n");
+    for row in 0..rows {
+        html.push_str(&format!("<span>line {}</span> - ->\n", row));
+    }
+    html.push_str("-->\n\nafter the comment\n");
+    html
+}
+
+fn bench_html(c: &mut Criterion) {
+    let converter = Converter::new(RenderOptions::new());
+    for (name, document) in [
+        ("table", table as fn(usize) -> String),
+        ("unclosed", unclosed),
+        ("comment", comment),
+    ] {
+        let mut group = c.benchmark_group(name);
+        for rows in [100, 1_000, 10_000] {
+            let input = document(rows);
+            group.throughput(Throughput::Bytes(input.len() as u64));
+            group.bench_with_input(BenchmarkId::from_parameter(rows), &input, |b, input| {
+                b.iter(|| converter.convert(black_box(input)).unwrap())
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_html);
+criterion_main!(benches);
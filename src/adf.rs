@@ -0,0 +1,2192 @@
+use ego_tree::NodeRef;
+use markup5ever::local_name;
+use pulldown_cmark::*;
+use scraper::{Html, Node};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::iter::Peekable;
+
+use crate::atlassian::invert_lang_map;
+use crate::footnotes::{self, Footnotes};
+use crate::html::{self, DetailsPart, HtmlBuffer};
+use crate::image::{Align, Image};
+use crate::languages::LanguageMapper;
+use crate::links::Resolver;
+use crate::options::RenderOptions;
+use crate::properties::Directive;
+
+/// Nodes whose content is inline, so text can be added to them directly
+const INLINE_CONTAINERS: [&str; 4] = ["paragraph", "heading", "codeBlock", "taskItem"];
+
+/// Makes an empty ADF node
+///
+/// # Arguments
+///
+/// * `kind` - the ADF node type
+///
+/// # Returns
+///
+/// * `Value` - the node, with an empty content array
+fn new_node(kind: &str) -> Value {
+    json!({ "type": kind, "content": [] })
+}
+
+/// Makes the Confluence macro a page properties directive names
+///
+/// # Arguments
+///
+/// * `kind` - `extension`, or `bodiedExtension` for a macro with content
+/// * `directive` - the directive
+///
+/// # Returns
+///
+/// * `Value` - the node, with the directive's parameters
+fn macro_node(kind: &str, directive: &Directive) -> Value {
+    let params: serde_json::Map<String, Value> = directive
+        .params()
+        .iter()
+        .map(|(name, value)| (name.clone(), json!({ "value": value })))
+        .collect();
+    json!({
+        "type": kind,
+        "attrs": {
+            "extensionType": "com.atlassian.confluence.macro.core",
+            "extensionKey": directive.name(),
+            "parameters": { "macroParams": params },
+        },
+    })
+}
+
+/// Makes a Confluence anchor macro, which `#name` links jump to
+///
+/// # Arguments
+///
+/// * `name` - the anchor name
+///
+/// # Returns
+///
+/// * `Value` - the `inlineExtension` node
+fn anchor_node(name: &str) -> Value {
+    json!({
+        "type": "inlineExtension",
+        "attrs": {
+            "extensionType": "com.atlassian.confluence.macro.core",
+            "extensionKey": "anchor",
+            "parameters": { "macroParams": { "": { "value": name } } },
+        },
+    })
+}
+
+/// Turns a task list that also has normal items back into the bullet or ordered list it was,
+/// since a task list can only hold tasks. Each task keeps its state as `[ ] ` or `[x] ` at the
+/// start of its text
+///
+/// # Arguments
+///
+/// * `list` - the `taskList` node
+fn mixed_task_list(list: &mut Value) {
+    let items = std::mem::take(content_mut(list));
+    let original = list
+        .as_object_mut()
+        .and_then(|list| list.remove("original"));
+    if items
+        .iter()
+        .all(|item| matches!(node_type(item), "taskItem" | "taskList"))
+    {
+        *content_mut(list) = items;
+        return;
+    }
+    *list = original.unwrap_or_else(|| new_node("bulletList"));
+    for mut item in items {
+        match node_type(&item) {
+            "taskItem" => {
+                let checkbox = match item["attrs"]["state"].as_str() {
+                    Some("DONE") => "[x] ",
+                    _ => "[ ] ",
+                };
+                let mut inline = vec![json!({ "type": "text", "text": checkbox })];
+                inline.append(content_mut(&mut item));
+                let paragraph = json!({ "type": "paragraph", "content": inline });
+                content_mut(list).push(json!({ "type": "listItem", "content": [paragraph] }));
+            }
+            // lists nested in a task are its siblings, but belong in the item before them
+            "taskList" | "bulletList" | "orderedList" => match content_mut(list).last_mut() {
+                Some(previous) => content_mut(previous).push(item),
+                None => content_mut(list).push(json!({ "type": "listItem", "content": [item] })),
+            },
+            _ => content_mut(list).push(item),
+        }
+    }
+}
+
+/// Makes a list item's blocks ones a list item can hold. Headings become paragraphs, quotes and
+/// panels are replaced by their content, and each table row becomes a paragraph
+///
+/// # Arguments
+///
+/// * `blocks` - the list item's content
+///
+/// # Returns
+///
+/// * `Vec<Value>` - the blocks, flattened
+fn list_item_blocks(blocks: Vec<Value>) -> Vec<Value> {
+    let mut flattened = vec![];
+    for mut block in blocks {
+        match node_type(&block) {
+            "heading" => {
+                // headings stand out as strong text
+                let mut inline = std::mem::take(content_mut(&mut block));
+                for node in &mut inline {
+                    let marks = node["marks"].as_array().cloned().unwrap_or_default();
+                    // code can only be combined with links
+                    if node_type(node) == "text"
+                        && !marks
+                            .iter()
+                            .any(|mark| matches!(node_type(mark), "strong" | "code"))
+                    {
+                        let mut marks = marks;
+                        marks.push(json!({ "type": "strong" }));
+                        node["marks"] = json!(marks);
+                    }
+                }
+                flattened.push(json!({ "type": "paragraph", "content": inline }));
+            }
+            "blockquote" | "panel" => {
+                flattened.extend(list_item_blocks(std::mem::take(content_mut(&mut block))))
+            }
+            "table" => {
+                for mut row in std::mem::take(content_mut(&mut block)) {
+                    let mut inline = vec![];
+                    for mut cell in std::mem::take(content_mut(&mut row)) {
+                        if !inline.is_empty() {
+                            inline.push(json!({ "type": "text", "text": " | " }));
+                        }
+                        for mut paragraph in std::mem::take(content_mut(&mut cell)) {
+                            inline.append(content_mut(&mut paragraph));
+                        }
+                    }
+                    flattened.push(json!({ "type": "paragraph", "content": inline }));
+                }
+            }
+            _ => flattened.push(block),
+        }
+    }
+    flattened
+}
+
+/// Returns the type of an ADF node
+///
+/// # Arguments
+///
+/// * `node` - the ADF node
+fn node_type(node: &Value) -> &str {
+    node["type"].as_str().unwrap_or_default()
+}
+
+/// Formats the timestamp of an ADF `date` node, milliseconds since the epoch as a string, as a
+/// `YYYY-MM-DD` date in UTC, like the `<time>` elements of storage format
+///
+/// # Arguments
+///
+/// * `timestamp` - the timestamp
+///
+/// # Returns
+///
+/// * `Option<String>` - the date, or None if the timestamp isn't a number
+fn format_date(timestamp: &str) -> Option<String> {
+    let days = timestamp.trim().parse::<i64>().ok()?.div_euclid(86_400_000);
+    // civil date from days since 1970-01-01, in 400 year eras starting on March 1st
+    let shifted = days + 719_468;
+    let era = shifted.div_euclid(146_097);
+    let day_of_era = shifted.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    Some(format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+/// Returns the content array of an ADF node, creating it if necessary
+///
+/// # Arguments
+///
+/// * `node` - the ADF node
+fn content_mut(node: &mut Value) -> &mut Vec<Value> {
+    if !node["content"].is_array() {
+        node["content"] = json!([]);
+    }
+    node["content"].as_array_mut().unwrap()
+}
+
+/// Collects the text content of an HTML node and its children
+///
+/// # Arguments
+///
+/// * `node` - node to collect text from
+/// * `text` - where to collect the text
+fn collect_text(node: NodeRef<Node>, text: &mut String) {
+    for descendant in node.descendants() {
+        if let Node::Text(t) = descendant.value() {
+            text.push_str(t.trim_start_matches('\n').trim_start_matches(' '));
+        }
+    }
+}
+
+/// The AdfWriter takes events from pulldown-cmark and builds an Atlassian Document Format tree,
+/// which is written out as JSON once the document ends
+struct AdfWriter<I: Iterator, W> {
+    iter: Peekable<I>,
+    writer: W,
+    // open nodes, innermost last. The flag marks paragraphs opened only to hold inline content
+    stack: Vec<(Value, bool)>,
+    // marks applied to text, outermost first
+    marks: Vec<Value>,
+    // if we're on a table header cell
+    table_header: bool,
+    // column alignments of the current table
+    table_alignments: Vec<Alignment>,
+    // index of the current table cell in its row
+    table_cell_index: usize,
+    // where relative links point
+    resolver: Resolver,
+    // map between markdown/confluence code block langs
+    languages: LanguageMapper,
+    // add modify_headers to header level
+    modify_headers: i8,
+    // prepend the TOC macro
+    toc: bool,
+    // if the current heading should be output. Skips headers whose level is <= 0
+    should_output_line: bool,
+    // the current image, whose alt text comes as text events before the end tag
+    image: Option<Image>,
+    // ADF task lists and items need unique ids
+    local_id: usize,
+    // block HTML that is parsed once its tags are balanced
+    html_buffer: HtmlBuffer,
+    // depth of the stack below each `<details>` that has been opened in one HTML block, to be
+    // closed in a later one
+    open_details: Vec<usize>,
+    // the page properties macro the next table is written in, from a directive before it
+    properties: Option<Directive>,
+    // if the current table is in a page properties macro
+    properties_table: bool,
+    // footnote references and their definitions' blocks
+    footnotes: Footnotes<Vec<Value>>,
+}
+
+impl<'a, I, W> AdfWriter<I, W>
+where
+    I: Iterator<Item = Event<'a>>,
+    W: Write,
+{
+    /// return a new AdfWriter
+    ///
+    /// # Arguments
+    ///
+    /// * `iter` - iterator of elements provided by `pulldowm_cmark`
+    /// * `writer` - something implementing Write to write output to
+    /// * `options` - language map, header offset and TOC to render with
+    fn new(iter: I, writer: W, options: &RenderOptions) -> Self {
+        AdfWriter {
+            iter: iter.peekable(),
+            writer,
+            stack: vec![(json!({ "version": 1, "type": "doc", "content": [] }), false)],
+            marks: vec![],
+            table_header: false,
+            table_alignments: vec![],
+            table_cell_index: 0,
+            resolver: Resolver::new(options),
+            languages: LanguageMapper::new(options),
+            modify_headers: options.modify_headers,
+            toc: options.toc,
+            should_output_line: true,
+            image: None,
+            local_id: 0,
+            html_buffer: HtmlBuffer::default(),
+            open_details: vec![],
+            properties: None,
+            properties_table: false,
+            footnotes: Footnotes::default(),
+        }
+    }
+
+    /// Returns a new unique id for task lists and items
+    fn next_local_id(&mut self) -> String {
+        self.local_id += 1;
+        format!("task-{}", self.local_id)
+    }
+
+    /// The innermost open node
+    fn top(&mut self) -> &mut Value {
+        &mut self.stack.last_mut().unwrap().0
+    }
+
+    /// Opens a block node, closing any paragraph opened for loose inline content
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the node to open
+    fn push(&mut self, node: Value) {
+        self.close_implicit();
+        self.stack.push((node, false));
+    }
+
+    /// Closes any paragraph opened for loose inline content
+    fn close_implicit(&mut self) {
+        if self.stack.last().is_some_and(|(_, implicit)| *implicit) {
+            self.pop_node();
+        }
+    }
+
+    /// Closes the innermost node opened by a tag, and adds it to its parent
+    fn pop(&mut self) {
+        self.close_implicit();
+        self.pop_node();
+    }
+
+    /// Closes the innermost node and adds it to its parent
+    fn pop_node(&mut self) {
+        if self.stack.len() < 2 {
+            return;
+        }
+        let (mut node, _) = self.stack.pop().unwrap();
+        if node_type(&node) == "taskList" {
+            mixed_task_list(&mut node);
+        }
+        let parent_type = node_type(self.top()).to_string();
+        match node_type(&node) {
+            "paragraph" => {
+                let content = std::mem::take(content_mut(&mut node));
+                if parent_type == "taskItem" {
+                    // task items hold inline content directly, so paragraphs are split by breaks
+                    let task = content_mut(self.top());
+                    if !task.is_empty() && !content.is_empty() {
+                        task.push(json!({ "type": "hardBreak" }));
+                    }
+                    task.extend(content);
+                    return;
+                }
+                // media can't be inline, so split the paragraph around images
+                let paragraph = |inline| {
+                    let mut paragraph = json!({ "type": "paragraph", "content": inline });
+                    if let Some(marks) = node.get("marks") {
+                        paragraph["marks"] = marks.clone();
+                    }
+                    paragraph
+                };
+                let mut inline = vec![];
+                for child in content {
+                    if node_type(&child) == "mediaSingle" {
+                        if !inline.is_empty() {
+                            self.append(paragraph(std::mem::take(&mut inline)));
+                        }
+                        self.append(child);
+                    } else {
+                        inline.push(child);
+                    }
+                }
+                if !inline.is_empty() {
+                    self.append(paragraph(inline));
+                }
+            }
+            "codeBlock" => {
+                // the closing fence newline isn't part of the code
+                if let Some(last) = content_mut(&mut node).last_mut() {
+                    if let Some(text) = last["text"].as_str() {
+                        last["text"] = json!(text.strip_suffix('\n').unwrap_or(text));
+                    }
+                }
+                self.append(node);
+            }
+            "heading" if node.get("skip").is_none() => {
+                // media can't be in a heading, so images go after it
+                let (media, inline): (Vec<Value>, Vec<Value>) =
+                    std::mem::take(content_mut(&mut node))
+                        .into_iter()
+                        .partition(|child| node_type(child) == "mediaSingle");
+                *content_mut(&mut node) = inline;
+                self.append(node);
+                for child in media {
+                    self.append(child);
+                }
+            }
+            "listItem" => {
+                let content = list_item_blocks(std::mem::take(content_mut(&mut node)));
+                *content_mut(&mut node) = content;
+                self.append(node);
+            }
+            "footnote" => {
+                // definitions are written at the end of the document
+                let label = node["label"].as_str().unwrap_or_default().to_string();
+                let content = std::mem::take(content_mut(&mut node));
+                self.footnotes.define(label, content);
+            }
+            "taskList" | "bulletList" | "orderedList" if parent_type == "taskItem" => {
+                // nested lists in a task item are siblings of the item in ADF
+                let (item, implicit) = self.stack.pop().unwrap();
+                self.append(item);
+                self.append(node);
+                // keep an (empty) item open for the end tag to close
+                self.stack
+                    .push((json!({ "type": "taskItem", "skip": true }), implicit));
+            }
+            _ => {
+                if node.get("skip").is_none() {
+                    self.append(node)
+                }
+            }
+        }
+    }
+
+    /// Adds a node to the innermost open node
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the node to add
+    fn append(&mut self, node: Value) {
+        content_mut(self.top()).push(node);
+    }
+
+    /// Adds an inline node, opening a paragraph if the innermost node holds blocks
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the inline node to add
+    fn append_inline(&mut self, node: Value) {
+        if !self.should_output_line {
+            return;
+        }
+        let top_type = node_type(self.top()).to_string();
+        if !INLINE_CONTAINERS.contains(&top_type.as_str()) {
+            self.stack.push((new_node("paragraph"), true));
+        }
+        self.append(node);
+    }
+
+    /// Adds a text node with the current marks
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - the text to add
+    /// * `extra_mark` - an additional mark for this text only, like `code`
+    fn write_text(&mut self, text: &str, extra_mark: Option<Value>) {
+        if let Some(image) = self.image.as_mut() {
+            image.alt.push_str(text);
+            return;
+        }
+        if text.is_empty() {
+            return;
+        }
+        let mut marks = self.marks.clone();
+        marks.extend(extra_mark);
+        let mut text_node = json!({ "type": "text", "text": text });
+        // code blocks can't have marks
+        if !marks.is_empty() && node_type(self.top()) != "codeBlock" {
+            text_node["marks"] = json!(marks);
+        }
+        self.append_inline(text_node);
+    }
+
+    /// Converts the innermost list item into a task item
+    ///
+    /// # Arguments
+    ///
+    /// * `checked` - if the task is done
+    fn start_task(&mut self, checked: bool) {
+        let item_id = self.next_local_id();
+        let list_id = self.next_local_id();
+        let Some(index) = self
+            .stack
+            .iter()
+            .rposition(|(node, _)| node_type(node) == "listItem")
+        else {
+            return;
+        };
+        let state = if checked { "DONE" } else { "TODO" };
+        self.stack[index].0 = json!({
+            "type": "taskItem",
+            "attrs": { "localId": item_id, "state": state },
+            "content": [],
+        });
+        if index > 0 {
+            let list = &mut self.stack[index - 1].0;
+            if matches!(node_type(list), "bulletList" | "orderedList") {
+                // kept in case the list turns out to have normal items as well
+                let mut original = new_node(node_type(list));
+                if let Some(attrs) = list.get("attrs") {
+                    original["attrs"] = attrs.clone();
+                }
+                list["type"] = json!("taskList");
+                list["attrs"] = json!({ "localId": list_id });
+                list["original"] = original;
+            }
+        }
+        // a loose task item has opened a paragraph, which stays open for its end tag and adds its
+        // content to the task when it's closed
+    }
+
+    /// Adds an image, with its size and alignment
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - the image to add
+    fn write_image(&mut self, image: &Image) {
+        let mut media = json!({ "type": "external", "url": image.source, "alt": image.alt });
+        if let Some(width) = image.width {
+            media["width"] = json!(width);
+        }
+        if let Some(height) = image.height {
+            media["height"] = json!(height);
+        }
+        let mut media_single = json!({
+            "type": "mediaSingle",
+            "content": [{ "type": "media", "attrs": media }],
+        });
+        if let Some(align) = image.align {
+            let layout = match align {
+                Align::Left => "align-start",
+                Align::Center => "center",
+                Align::Right => "align-end",
+            };
+            media_single["attrs"] = json!({ "layout": layout });
+        }
+        self.append_inline(media_single);
+    }
+
+    /// Adds block HTML that opens or closes `<details>`. The markdown between the tags is the
+    /// content of the expand, so it is added as usual
+    ///
+    /// # Arguments
+    ///
+    /// * `html` - the HTML, which doesn't have to have as many closing tags as opening ones
+    fn write_details_html(&mut self, html: &str) {
+        for part in html::split_details(html) {
+            match part {
+                DetailsPart::Open(summary) => {
+                    let mut title = String::new();
+                    if let Some(summary) = summary {
+                        collect_text(Html::parse_fragment(summary).tree.root(), &mut title);
+                    }
+                    // an expand can't hold another one, but can hold a nested expand
+                    let kind = if self.open_details.is_empty() {
+                        "expand"
+                    } else {
+                        "nestedExpand"
+                    };
+                    let mut expand = new_node(kind);
+                    expand["attrs"] = json!({ "title": title.trim() });
+                    self.close_implicit();
+                    self.open_details.push(self.stack.len());
+                    self.push(expand);
+                }
+                // a closing tag without an opening one has nothing to close
+                DetailsPart::Close => {
+                    if let Some(depth) = self.open_details.pop() {
+                        while self.stack.len() > depth {
+                            self.pop();
+                        }
+                    }
+                }
+                DetailsPart::Html(html) => self.parse_html(Html::parse_fragment(html).tree.root()),
+            }
+        }
+    }
+
+    /// Adds block HTML whose tags are balanced, which is either a page properties directive or
+    /// HTML to parse
+    ///
+    /// # Arguments
+    ///
+    /// * `html` - the HTML
+    fn write_block_html(&mut self, html: &str) {
+        match Directive::parse(html) {
+            // added when the table starts
+            Some(directive @ Directive::Details(_)) => self.properties = Some(directive),
+            Some(directive) => {
+                self.close_implicit();
+                self.append(macro_node("extension", &directive));
+            }
+            None => self.parse_html(Html::parse_fragment(html).tree.root()),
+        }
+    }
+
+    /// Parses HTML to ADF nodes
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - node to parse
+    fn parse_html(&mut self, node: NodeRef<Node>) {
+        match node.value() {
+            Node::Element(elem) if matches!(elem.name.local, local_name!("details")) => {
+                // the summary becomes the title, everything else is the body
+                let mut title = String::new();
+                let mut body = String::new();
+                for child in node.children() {
+                    match child.value() {
+                        Node::Element(child_elem)
+                            if matches!(child_elem.name.local, local_name!("summary")) =>
+                        {
+                            collect_text(child, &mut title)
+                        }
+                        _ => collect_text(child, &mut body),
+                    }
+                }
+                let mut expand = new_node("expand");
+                expand["attrs"] = json!({ "title": title });
+                self.push(expand);
+                self.write_text(body.trim_end(), None);
+                self.pop();
+            }
+            Node::Element(elem) if matches!(elem.name.local, local_name!("img")) => {
+                self.write_image(&Image::from_element(elem))
+            }
+            Node::Element(_) | Node::Fragment => {
+                for child in node.children() {
+                    self.parse_html(child);
+                }
+            }
+            Node::Text(text) => {
+                self.write_text(text.trim_start_matches('\n').trim_start_matches(' '), None)
+            }
+            // comments don't make it to the output
+            _ => (),
+        }
+    }
+
+    /// Main part of the parser, outputting to underlying `writer`.
+    ///
+    /// Passes start/end tags out to `start_tag` and `end_tag`, respectively.
+    /// Adds the rest of the inline content as necessary, then writes the document.
+    fn run(&mut self) -> io::Result<()> {
+        if self.toc {
+            self.append(json!({
+                "type": "extension",
+                "attrs": {
+                    "extensionType": "com.atlassian.confluence.macro.core",
+                    "extensionKey": "toc",
+                },
+            }));
+        }
+        while let Some(event) = self.iter.next() {
+            // HTML that is still open is written where it was, before the markdown after it
+            if html::ends_html(&event) {
+                if let Some(html) = self.html_buffer.interrupt() {
+                    self.write_block_html(&html);
+                }
+            }
+            match event {
+                Event::Start(Tag::MetadataBlock(_)) => {
+                    // front matter isn't content
+                    for event in self.iter.by_ref() {
+                        if matches!(event, Event::End(TagEnd::MetadataBlock(_))) {
+                            break;
+                        }
+                    }
+                }
+                Event::Start(tag) => self.start_tag(tag),
+                Event::End(tag) => self.end_tag(tag),
+                Event::Text(text) => self.write_text(&text, None),
+                Event::Code(text) => self.write_text(&text, Some(json!({ "type": "code" }))),
+                Event::SoftBreak => self.write_text(" ", None),
+                Event::HardBreak => self.append_inline(json!({ "type": "hardBreak" })),
+                Event::InlineHtml(html) if matches!(html.trim(), "<br>" | "<br/>" | "<br />") => {
+                    self.append_inline(json!({ "type": "hardBreak" }))
+                }
+                Event::InlineHtml(html) => {
+                    if let Some(image) = Image::from_html(&html) {
+                        self.write_image(&image);
+                    }
+                }
+                Event::Rule => {
+                    self.close_implicit();
+                    self.append(json!({ "type": "rule" }))
+                }
+                Event::TaskListMarker(checked) => self.start_task(checked),
+                Event::FootnoteReference(label) => {
+                    let (number, first) = self.footnotes.reference(&label);
+                    let anchor = footnotes::anchor(&label);
+                    if first {
+                        // only the first reference can be jumped back to
+                        self.append_inline(anchor_node(&format!("fnref-{}", anchor)));
+                    }
+                    let mut marks = self.marks.clone();
+                    marks.push(
+                        json!({ "type": "link", "attrs": { "href": format!("#fn-{}", anchor) } }),
+                    );
+                    marks.push(json!({ "type": "subsup", "attrs": { "type": "sup" } }));
+                    self.append_inline(
+                        json!({ "type": "text", "text": number.to_string(), "marks": marks }),
+                    );
+                }
+                Event::Html(string) => {
+                    self.html_buffer.push(&string);
+                    if html::has_details(&string) {
+                        // the summary can be on the next line, so take the rest of the block too
+                        while let Some(Event::Html(more)) =
+                            self.iter.next_if(|event| matches!(event, Event::Html(_)))
+                        {
+                            self.html_buffer.push(&more);
+                        }
+                        let html = self.html_buffer.take();
+                        self.write_details_html(&html);
+                    } else if let Some(html) = self.html_buffer.complete() {
+                        self.write_block_html(&html);
+                    }
+                }
+                _ => (),
+            }
+        }
+        if let Some(html) = self.html_buffer.finish() {
+            self.write_block_html(&html);
+        }
+        while self.stack.len() > 1 {
+            self.pop();
+        }
+        self.write_footnotes();
+
+        serde_json::to_writer(&mut self.writer, &self.stack[0].0)?;
+        Ok(())
+    }
+
+    /// Closes the footnote definition being written, if there is one
+    fn end_footnote(&mut self) {
+        self.close_implicit();
+        if node_type(self.top()) == "footnote" {
+            self.pop_node();
+        }
+    }
+
+    /// Adds the collected footnote definitions as an ordered list under a "Notes" heading, in
+    /// the order they were referenced. Definitions that were never referenced go last.
+    fn write_footnotes(&mut self) {
+        let notes = self.footnotes.take_notes();
+        if notes.is_empty() {
+            return;
+        }
+        let level = 2 + self.modify_headers;
+        if (1..7).contains(&level) {
+            self.append(json!({
+                "type": "heading",
+                "attrs": { "level": level },
+                "content": [{ "type": "text", "text": "Notes" }],
+            }));
+        } else {
+            self.append(json!({
+                "type": "paragraph",
+                "content": [{ "type": "text", "text": "Notes", "marks": [{ "type": "strong" }] }],
+            }));
+        }
+        let mut list = new_node("orderedList");
+        list["attrs"] = json!({ "order": 1 });
+        for note in notes {
+            let mut blocks = note.content;
+            // the anchor and back link go in the first paragraph, if the note starts with one
+            if blocks.first().map(node_type) != Some("paragraph") {
+                blocks.insert(0, new_node("paragraph"));
+            }
+            let paragraph = content_mut(&mut blocks[0]);
+            paragraph.insert(0, anchor_node(&format!("fn-{}", note.anchor)));
+            if note.referenced {
+                let href = format!("#fnref-{}", note.anchor);
+                if paragraph.len() > 1 {
+                    paragraph.push(json!({ "type": "text", "text": " " }));
+                }
+                paragraph.push(json!({
+                    "type": "text",
+                    "text": "↩",
+                    "marks": [{ "type": "link", "attrs": { "href": href } }],
+                }));
+            }
+            content_mut(&mut list).push(json!({ "type": "listItem", "content": blocks }));
+        }
+        self.append(list);
+    }
+
+    /// Handles opening tags
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - tag to open
+    fn start_tag(&mut self, tag: Tag<'a>) {
+        if self.properties.is_some() && !matches!(tag, Tag::Table(_) | Tag::HtmlBlock) {
+            self.properties = None;
+            log::warn!("`<!-- details -->` isn't followed by a table, so it is left out");
+        }
+        match tag {
+            Tag::Paragraph => self.push(new_node("paragraph")),
+            Tag::Heading { level, .. } => {
+                let parsed_level = level as i8 + self.modify_headers;
+                if parsed_level <= 0 {
+                    // skip header contents if header level <= 0
+                    self.should_output_line = false;
+                    self.push(json!({ "type": "heading", "skip": true }));
+                } else if parsed_level < 7 {
+                    let mut heading = new_node("heading");
+                    heading["attrs"] = json!({ "level": parsed_level });
+                    self.push(heading);
+                } else {
+                    // if the header is > 6, then just treat it as regular text.
+                    self.push(new_node("paragraph"));
+                }
+            }
+            Tag::BlockQuote(None) => self.push(new_node("blockquote")),
+            Tag::BlockQuote(Some(kind)) => {
+                let mut panel = new_node("panel");
+                let panel_type = match kind {
+                    BlockQuoteKind::Note => "info",
+                    BlockQuoteKind::Tip => "success",
+                    BlockQuoteKind::Important => "note",
+                    BlockQuoteKind::Warning => "warning",
+                    BlockQuoteKind::Caution => "error",
+                };
+                panel["attrs"] = json!({ "panelType": panel_type });
+                self.push(panel);
+            }
+            Tag::CodeBlock(code_block_kind) => {
+                let mut code_block = new_node("codeBlock");
+                if let CodeBlockKind::Fenced(language) = code_block_kind {
+                    code_block["attrs"] = json!({ "language": self.languages.map(&language) });
+                }
+                self.push(code_block);
+            }
+            Tag::List(Some(first_number)) => {
+                let mut list = new_node("orderedList");
+                list["attrs"] = json!({ "order": first_number });
+                self.push(list);
+            }
+            Tag::List(None) => self.push(new_node("bulletList")),
+            Tag::Item => self.push(new_node("listItem")),
+            Tag::Table(alignments) => {
+                self.table_alignments = alignments;
+                if let Some(directive) = self.properties.take() {
+                    let mut properties = macro_node("bodiedExtension", &directive);
+                    properties["content"] = json!([]);
+                    self.push(properties);
+                    self.properties_table = true;
+                }
+                self.push(new_node("table"));
+            }
+            Tag::TableHead => {
+                self.table_header = true;
+                self.table_cell_index = 0;
+                self.push(new_node("tableRow"));
+            }
+            Tag::TableRow => {
+                self.table_cell_index = 0;
+                self.push(new_node("tableRow"));
+            }
+            Tag::TableCell => {
+                let cell = if self.table_header {
+                    "tableHeader"
+                } else {
+                    "tableCell"
+                };
+                self.push(new_node(cell));
+                // cells hold blocks, not inline content
+                let mut paragraph = new_node("paragraph");
+                // left is the default, so it has no mark
+                let align = match self.table_alignments.get(self.table_cell_index) {
+                    Some(Alignment::Center) => Some("center"),
+                    Some(Alignment::Right) => Some("end"),
+                    _ => None,
+                };
+                if let Some(align) = align {
+                    paragraph["marks"] =
+                        json!([{ "type": "alignment", "attrs": { "align": align } }]);
+                }
+                self.table_cell_index += 1;
+                self.push(paragraph);
+            }
+            Tag::Emphasis => self.marks.push(json!({ "type": "em" })),
+            Tag::Strong => self.marks.push(json!({ "type": "strong" })),
+            Tag::Strikethrough => self.marks.push(json!({ "type": "strike" })),
+            Tag::Link { dest_url, .. } => {
+                // ADF can't link to a page by title, so relative links only use the base url
+                let href = self.resolver.url(&dest_url);
+                self.marks
+                    .push(json!({ "type": "link", "attrs": { "href": href } }))
+            }
+            Tag::Image {
+                dest_url, title, ..
+            } => self.image = Some(Image::new(&dest_url, "", &title)),
+            Tag::FootnoteDefinition(label) => {
+                // the parser can start a definition before the last one has ended
+                self.end_footnote();
+                self.push(json!({ "type": "footnote", "label": label.to_string(), "content": [] }));
+            }
+            _ => (),
+        }
+    }
+
+    /// Handles closing tags
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - tag to close
+    fn end_tag(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Heading(..) => {
+                self.pop();
+                self.should_output_line = true;
+            }
+            TagEnd::Paragraph
+            | TagEnd::BlockQuote
+            | TagEnd::CodeBlock
+            | TagEnd::List(_)
+            | TagEnd::Item
+            | TagEnd::TableRow => self.pop(),
+            TagEnd::Table => {
+                self.pop();
+                if std::mem::take(&mut self.properties_table) {
+                    self.pop();
+                }
+            }
+            TagEnd::TableHead => {
+                self.table_header = false;
+                self.pop();
+            }
+            TagEnd::TableCell => {
+                // close the paragraph and the cell
+                self.pop();
+                self.pop();
+            }
+            TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough | TagEnd::Link => {
+                self.marks.pop();
+            }
+            TagEnd::HtmlBlock => {
+                // elements like `<p>` end with the block
+                self.html_buffer.end_block();
+                if let Some(html) = self.html_buffer.complete() {
+                    self.write_block_html(&html);
+                }
+            }
+            TagEnd::FootnoteDefinition => self.end_footnote(),
+            TagEnd::Image => {
+                let Some(mut image) = self.image.take() else {
+                    return;
+                };
+                // `{width=300}` right after the image
+                if let Some(Event::Text(text)) = self.iter.peek_mut() {
+                    let read = image.read_attributes(text);
+                    if read == text.len() {
+                        self.iter.next();
+                    } else if read > 0 {
+                        *text = CowStr::from(text[read..].to_string());
+                    }
+                }
+                self.write_image(&image);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Writes Atlassian Document Format (ADF) JSON output
+///
+/// # Arguments
+///
+/// * `writer` - something implementing the Write trait
+/// * `iter` - an iterator of Events from pulldown-cmark
+/// * `options` - the render options to use
+///
+/// # Returns
+///
+/// * `Result` - if the AdfWriter wrote successfully to `writer`
+pub fn write<'a, I, W>(writer: W, iter: I, options: &RenderOptions) -> io::Result<()>
+where
+    I: Iterator<Item = Event<'a>>,
+    W: Write,
+{
+    AdfWriter::new(iter, writer, options).run()
+}
+
+/// Parses an ADF document into pulldown-cmark events
+struct AdfParser {
+    events: Vec<Event<'static>>,
+    // map between confluence/markdown code block langs
+    lang_map: HashMap<String, String>,
+}
+
+impl AdfParser {
+    /// return a new AdfParser
+    ///
+    /// # Arguments
+    ///
+    /// * `lang_map` - map from markdown to Atlassian code block langs, which is inverted
+    fn new(lang_map: &HashMap<String, String>) -> Self {
+        AdfParser {
+            events: vec![],
+            lang_map: invert_lang_map(lang_map),
+        }
+    }
+
+    /// Makes an owned text event
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - the text
+    fn text(&mut self, s: &str) {
+        self.events.push(Event::Text(CowStr::from(s.to_string())));
+    }
+
+    /// Parses the children of an ADF node as blocks
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the parent node
+    fn blocks(&mut self, node: &Value) {
+        for child in node["content"].as_array().into_iter().flatten() {
+            self.block(child);
+        }
+    }
+
+    /// Parses the children of an ADF node as inline content
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the parent node
+    fn inlines(&mut self, node: &Value) {
+        for child in node["content"].as_array().into_iter().flatten() {
+            self.inline(child);
+        }
+    }
+
+    /// Parses the content of a list item or table cell, unwrapping paragraphs so the list stays
+    /// tight and the cell stays on one line
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the item or cell
+    /// * `in_cell` - if paragraphs should be joined with line breaks instead
+    fn unwrapped(&mut self, node: &Value, in_cell: bool) {
+        let children = node["content"].as_array().cloned().unwrap_or_default();
+        let paragraphs = children
+            .iter()
+            .filter(|child| node_type(child) == "paragraph")
+            .count();
+        if paragraphs > 1 && !in_cell {
+            return self.blocks(node);
+        }
+        for (i, child) in children.iter().enumerate() {
+            if node_type(child) == "paragraph" {
+                if i > 0 && in_cell {
+                    self.events.push(Event::HardBreak);
+                }
+                self.inlines(child);
+            } else {
+                self.block(child);
+            }
+        }
+    }
+
+    /// Parses the items of a list, attaching nested lists to the item before them
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the list
+    fn list_items(&mut self, node: &Value) {
+        let mut item_open = false;
+        for child in node["content"].as_array().into_iter().flatten() {
+            match node_type(child) {
+                "taskList" | "decisionList" if item_open => self.block(child),
+                "taskItem" | "decisionItem" => {
+                    if item_open {
+                        self.events.push(Event::End(TagEnd::Item));
+                    }
+                    self.events.push(Event::Start(Tag::Item));
+                    if node_type(child) == "taskItem" {
+                        self.events
+                            .push(Event::TaskListMarker(child["attrs"]["state"] == "DONE"));
+                    }
+                    self.inlines(child);
+                    item_open = true;
+                }
+                _ => {
+                    if item_open {
+                        self.events.push(Event::End(TagEnd::Item));
+                    }
+                    self.events.push(Event::Start(Tag::Item));
+                    self.unwrapped(child, false);
+                    item_open = true;
+                }
+            }
+        }
+        if item_open {
+            self.events.push(Event::End(TagEnd::Item));
+        }
+    }
+
+    /// Parses a table
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the table
+    fn table(&mut self, node: &Value) {
+        let rows = node["content"].as_array().cloned().unwrap_or_default();
+        let columns = rows
+            .iter()
+            .map(|row| row["content"].as_array().map_or(0, |cells| cells.len()))
+            .max()
+            .unwrap_or(0);
+        // alignment is a mark on the paragraphs in a cell, so read it from the first row
+        let mut alignments = vec![Alignment::None; columns];
+        let first_cells = rows
+            .first()
+            .and_then(|row| row["content"].as_array())
+            .into_iter()
+            .flatten();
+        for (alignment, cell) in alignments.iter_mut().zip(first_cells) {
+            let marks = cell["content"][0]["marks"].as_array().into_iter().flatten();
+            for mark in marks.filter(|mark| node_type(mark) == "alignment") {
+                *alignment = match mark["attrs"]["align"].as_str() {
+                    Some("center") => Alignment::Center,
+                    Some("end") => Alignment::Right,
+                    _ => Alignment::None,
+                };
+            }
+        }
+        self.events.push(Event::Start(Tag::Table(alignments)));
+        let header = rows.first().is_some_and(|row| {
+            row["content"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .all(|cell| node_type(cell) == "tableHeader")
+        });
+        self.events.push(Event::Start(Tag::TableHead));
+        if header {
+            self.cells(&rows[0], columns);
+        } else {
+            // markdown tables need a header, so give it an empty one
+            for _ in 0..columns {
+                self.events.push(Event::Start(Tag::TableCell));
+                self.events.push(Event::End(TagEnd::TableCell));
+            }
+        }
+        self.events.push(Event::End(TagEnd::TableHead));
+        for row in rows.iter().skip(if header { 1 } else { 0 }) {
+            self.events.push(Event::Start(Tag::TableRow));
+            self.cells(row, columns);
+            self.events.push(Event::End(TagEnd::TableRow));
+        }
+        self.events.push(Event::End(TagEnd::Table));
+    }
+
+    /// Parses the cells of a table row, padding it to `columns` cells
+    ///
+    /// # Arguments
+    ///
+    /// * `row` - the row
+    /// * `columns` - how many columns the table has
+    fn cells(&mut self, row: &Value, columns: usize) {
+        let cells = row["content"].as_array().cloned().unwrap_or_default();
+        for i in 0..columns {
+            self.events.push(Event::Start(Tag::TableCell));
+            if let Some(cell) = cells.get(i) {
+                self.unwrapped(cell, true);
+            }
+            self.events.push(Event::End(TagEnd::TableCell));
+        }
+    }
+
+    /// Parses an image
+    ///
+    /// # Arguments
+    ///
+    /// * `media` - the media node
+    /// * `layout` - the layout of the `mediaSingle` around it, if any
+    fn media(&mut self, media: &Value, layout: Option<&str>) {
+        let attrs = &media["attrs"];
+        // external media have a url, uploaded files only have an id
+        let url = attrs["url"]
+            .as_str()
+            .or(attrs["id"].as_str())
+            .unwrap_or_default();
+        let mut image = Image::new(url, attrs["alt"].as_str().unwrap_or_default(), "");
+        for key in ["width", "height"] {
+            if let Some(pixels) = attrs[key].as_u64() {
+                image.set(key, &pixels.to_string());
+            }
+        }
+        // centered is the default, so it isn't kept
+        match layout {
+            Some("align-start" | "wrap-left") => image.align = Some(Align::Left),
+            Some("align-end" | "wrap-right") => image.align = Some(Align::Right),
+            _ => (),
+        }
+        // markdown images can't have a size or alignment, but HTML ones can
+        if image.has_attributes() {
+            self.events
+                .push(Event::InlineHtml(CowStr::from(image.to_html())));
+            return;
+        }
+        self.events.push(Event::Start(Tag::Image {
+            link_type: LinkType::Inline,
+            dest_url: CowStr::from(image.source),
+            title: CowStr::from(""),
+            id: CowStr::from(""),
+        }));
+        if !image.alt.is_empty() {
+            self.text(&image.alt);
+        }
+        self.events.push(Event::End(TagEnd::Image));
+    }
+
+    /// Parses a block node
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the node
+    fn block(&mut self, node: &Value) {
+        match node_type(node) {
+            "paragraph" => {
+                self.events.push(Event::Start(Tag::Paragraph));
+                self.inlines(node);
+                self.events.push(Event::End(TagEnd::Paragraph));
+            }
+            "heading" => {
+                let level = node["attrs"]["level"].as_u64().unwrap_or(1).clamp(1, 6);
+                let level = HeadingLevel::try_from(level as usize).unwrap();
+                self.events.push(Event::Start(Tag::Heading {
+                    level,
+                    id: None,
+                    classes: vec![],
+                    attrs: vec![],
+                }));
+                self.inlines(node);
+                self.events.push(Event::End(TagEnd::Heading(level)));
+            }
+            "bulletList" | "taskList" | "decisionList" => {
+                self.events.push(Event::Start(Tag::List(None)));
+                self.list_items(node);
+                self.events.push(Event::End(TagEnd::List(false)));
+            }
+            "orderedList" => {
+                let order = node["attrs"]["order"].as_u64().unwrap_or(1);
+                self.events.push(Event::Start(Tag::List(Some(order))));
+                self.list_items(node);
+                self.events.push(Event::End(TagEnd::List(true)));
+            }
+            "codeBlock" => {
+                let lang = node["attrs"]["language"]
+                    .as_str()
+                    .map(|lang| {
+                        let lang = lang.to_lowercase();
+                        self.lang_map.get(&lang).cloned().unwrap_or(lang)
+                    })
+                    .unwrap_or_default();
+                let mut code: String = node["content"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|text| text["text"].as_str())
+                    .collect();
+                if !code.ends_with('\n') {
+                    code.push('\n');
+                }
+                self.events
+                    .push(Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(
+                        CowStr::from(lang),
+                    ))));
+                self.text(&code);
+                self.events.push(Event::End(TagEnd::CodeBlock));
+            }
+            "blockquote" => {
+                self.events.push(Event::Start(Tag::BlockQuote(None)));
+                self.blocks(node);
+                self.events.push(Event::End(TagEnd::BlockQuote));
+            }
+            "panel" => {
+                let kind = match node["attrs"]["panelType"].as_str() {
+                    Some("note") => BlockQuoteKind::Important,
+                    Some("success") | Some("tip") => BlockQuoteKind::Tip,
+                    Some("warning") => BlockQuoteKind::Warning,
+                    Some("error") => BlockQuoteKind::Caution,
+                    _ => BlockQuoteKind::Note,
+                };
+                self.events.push(Event::Start(Tag::BlockQuote(Some(kind))));
+                self.blocks(node);
+                self.events.push(Event::End(TagEnd::BlockQuote));
+            }
+            "rule" => self.events.push(Event::Rule),
+            "table" => self.table(node),
+            "extension" | "bodiedExtension"
+                if matches!(
+                    node["attrs"]["extensionKey"].as_str(),
+                    Some("details" | "detailssummary")
+                ) =>
+            {
+                let params: Vec<(String, String)> = node["attrs"]["parameters"]["macroParams"]
+                    .as_object()
+                    .into_iter()
+                    .flatten()
+                    .map(|(name, param)| {
+                        let value = param["value"].as_str().unwrap_or_default();
+                        (name.clone(), value.to_string())
+                    })
+                    .collect();
+                let directive = match node["attrs"]["extensionKey"].as_str() {
+                    Some("details") => Directive::Details(params),
+                    _ => Directive::Report(params),
+                };
+                self.events
+                    .push(Event::Html(CowStr::from(directive.to_html())));
+                self.blocks(node);
+            }
+            "expand" | "nestedExpand" => {
+                let title = node["attrs"]["title"].as_str().unwrap_or_default();
+                self.events.push(Event::Html(CowStr::from(format!(
+                    "<details>\n<summary>{}</summary>\n",
+                    title
+                ))));
+                self.blocks(node);
+                self.events.push(Event::Html(CowStr::from("</details>\n")));
+            }
+            "mediaSingle" | "mediaGroup" => {
+                self.events.push(Event::Start(Tag::Paragraph));
+                let layout = node["attrs"]["layout"].as_str();
+                for media in node["content"].as_array().into_iter().flatten() {
+                    self.media(media, layout);
+                }
+                self.events.push(Event::End(TagEnd::Paragraph));
+            }
+            "blockCard" | "embedCard" => {
+                self.events.push(Event::Start(Tag::Paragraph));
+                self.inline(node);
+                self.events.push(Event::End(TagEnd::Paragraph));
+            }
+            // extensions and unknown nodes only keep whatever content they have
+            _ => self.blocks(node),
+        }
+    }
+
+    /// Parses an inline node
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the node
+    fn inline(&mut self, node: &Value) {
+        match node_type(node) {
+            "text" => self.marked_text(node),
+            "hardBreak" => self.events.push(Event::HardBreak),
+            "mention" => {
+                let name = node["attrs"]["text"]
+                    .as_str()
+                    .or(node["attrs"]["id"].as_str())
+                    .unwrap_or_default();
+                if name.starts_with('@') {
+                    self.text(name);
+                } else {
+                    self.text(&format!("@{}", name));
+                }
+            }
+            "emoji" => {
+                let emoji = node["attrs"]["text"]
+                    .as_str()
+                    .or(node["attrs"]["shortName"].as_str())
+                    .unwrap_or_default();
+                self.text(emoji);
+            }
+            "date" => {
+                let timestamp = node["attrs"]["timestamp"].as_str().unwrap_or_default();
+                match format_date(timestamp) {
+                    Some(date) => self.text(&date),
+                    None => self.text(timestamp),
+                }
+            }
+            "status" => {
+                let status = node["attrs"]["text"].as_str().unwrap_or_default();
+                self.events.push(Event::Start(Tag::Strong));
+                self.text(&status.to_uppercase());
+                self.events.push(Event::End(TagEnd::Strong));
+            }
+            "inlineCard" | "blockCard" | "embedCard" => {
+                let url = node["attrs"]["url"].as_str().unwrap_or_default();
+                self.events.push(Event::Start(Tag::Link {
+                    link_type: LinkType::Autolink,
+                    dest_url: CowStr::from(url.to_string()),
+                    title: CowStr::from(""),
+                    id: CowStr::from(""),
+                }));
+                self.text(url);
+                self.events.push(Event::End(TagEnd::Link));
+            }
+            "media" | "mediaInline" => self.media(node, None),
+            _ => self.inlines(node),
+        }
+    }
+
+    /// Parses a text node, wrapping it in its marks
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the text node
+    fn marked_text(&mut self, node: &Value) {
+        let text = node["text"].as_str().unwrap_or_default();
+        let marks = node["marks"].as_array().cloned().unwrap_or_default();
+        let mut is_code = false;
+        let mut closing = vec![];
+        for mark in &marks {
+            let (open, close) = match node_type(mark) {
+                "strong" => (Event::Start(Tag::Strong), Event::End(TagEnd::Strong)),
+                "em" => (Event::Start(Tag::Emphasis), Event::End(TagEnd::Emphasis)),
+                "strike" => (
+                    Event::Start(Tag::Strikethrough),
+                    Event::End(TagEnd::Strikethrough),
+                ),
+                "underline" => (
+                    Event::InlineHtml(CowStr::from("<ins>")),
+                    Event::InlineHtml(CowStr::from("</ins>")),
+                ),
+                "subsup" => {
+                    let tag = if mark["attrs"]["type"] == "sub" {
+                        "sub"
+                    } else {
+                        "sup"
+                    };
+                    (
+                        Event::InlineHtml(CowStr::from(format!("<{}>", tag))),
+                        Event::InlineHtml(CowStr::from(format!("</{}>", tag))),
+                    )
+                }
+                "link" => (
+                    Event::Start(Tag::Link {
+                        link_type: LinkType::Inline,
+                        dest_url: CowStr::from(
+                            mark["attrs"]["href"]
+                                .as_str()
+                                .unwrap_or_default()
+                                .to_string(),
+                        ),
+                        title: CowStr::from(
+                            mark["attrs"]["title"]
+                                .as_str()
+                                .unwrap_or_default()
+                                .to_string(),
+                        ),
+                        id: CowStr::from(""),
+                    }),
+                    Event::End(TagEnd::Link),
+                ),
+                "code" => {
+                    is_code = true;
+                    continue;
+                }
+                // colors and the like have no markdown equivalent
+                _ => continue,
+            };
+            self.events.push(open);
+            closing.push(close);
+        }
+        if is_code {
+            self.events
+                .push(Event::Code(CowStr::from(text.to_string())));
+        } else {
+            self.text(text);
+        }
+        while let Some(close) = closing.pop() {
+            self.events.push(close);
+        }
+    }
+}
+
+/// Parses an Atlassian Document Format (ADF) JSON document into pulldown-cmark events
+///
+/// # Arguments
+///
+/// * `input` - ADF JSON
+/// * `lang_map` - map from markdown to Atlassian code block langs, used in reverse
+///
+/// # Returns
+///
+/// * `Result<Vec<Event>>` - events that can be given to any of the writers, or an error if the
+///   input isn't JSON
+pub fn parse(input: &str, lang_map: &HashMap<String, String>) -> io::Result<Vec<Event<'static>>> {
+    let doc: Value = serde_json::from_str(input)?;
+    let mut parser = AdfParser::new(lang_map);
+    parser.blocks(&doc);
+    Ok(parser.events)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Renders `input` to ADF with `options`, returning the document's content
+    fn render_with(input: &str, options: &RenderOptions) -> Value {
+        let mut output = Vec::new();
+        assert!(write(&mut output, Parser::new_ext(input, Options::all()), options).is_ok());
+        let doc: Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(json!(1), doc["version"]);
+        assert_eq!(json!("doc"), doc["type"]);
+        doc["content"].clone()
+    }
+
+    /// Renders `input` to ADF with default options, returning the document's content
+    fn render(input: &str) -> Value {
+        render_with(input, &RenderOptions::new())
+    }
+
+    #[test]
+    fn test_heading() {
+        assert_eq!(
+            json!([{
+                "type": "heading",
+                "attrs": { "level": 2 },
+                "content": [{ "type": "text", "text": "hello world" }],
+            }]),
+            render("## hello world")
+        );
+        assert_eq!(
+            json!([]),
+            render_with("# hello world", &RenderOptions::new().modify_headers(-1))
+        );
+    }
+
+    #[test]
+    fn test_marks() {
+        assert_eq!(
+            json!([{
+                "type": "paragraph",
+                "content": [
+                    { "type": "text", "text": "bold", "marks": [{ "type": "strong" }] },
+                    { "type": "text", "text": " " },
+                    {
+                        "type": "text",
+                        "text": "both",
+                        "marks": [{ "type": "em" }, { "type": "strike" }],
+                    },
+                    { "type": "text", "text": " " },
+                    { "type": "text", "text": "code", "marks": [{ "type": "code" }] },
+                    { "type": "text", "text": " " },
+                    {
+                        "type": "text",
+                        "text": "link",
+                        "marks": [{ "type": "link", "attrs": { "href": "https://example.com" } }],
+                    },
+                ],
+            }]),
+            render("**bold** _~~both~~_ `code` [link](https://example.com)")
+        );
+    }
+
+    #[test]
+    fn test_lists() {
+        assert_eq!(
+            json!([{
+                "type": "orderedList",
+                "attrs": { "order": 1 },
+                "content": [{
+                    "type": "listItem",
+                    "content": [
+                        { "type": "paragraph", "content": [{ "type": "text", "text": "one" }] },
+                        {
+                            "type": "bulletList",
+                            "content": [{
+                                "type": "listItem",
+                                "content": [{
+                                    "type": "paragraph",
+                                    "content": [{ "type": "text", "text": "nested" }],
+                                }],
+                            }],
+                        },
+                    ],
+                }],
+            }]),
+            render("1. one\n\t* nested")
+        );
+    }
+
+    #[test]
+    fn test_codeblock() {
+        assert_eq!(
+            json!([{
+                "type": "codeBlock",
+                "attrs": { "language": "bash" },
+                "content": [{ "type": "text", "text": "$ ls" }],
+            }]),
+            render("```console\n$ ls\n```")
+        );
+    }
+
+    #[test]
+    fn test_table() {
+        let input = "\
+        | header |\n\
+        |--------|\n\
+        | item   |";
+        assert_eq!(
+            json!([{
+                "type": "table",
+                "content": [
+                    {
+                        "type": "tableRow",
+                        "content": [{
+                            "type": "tableHeader",
+                            "content": [{
+                                "type": "paragraph",
+                                "content": [{ "type": "text", "text": "header" }],
+                            }],
+                        }],
+                    },
+                    {
+                        "type": "tableRow",
+                        "content": [{
+                            "type": "tableCell",
+                            "content": [{
+                                "type": "paragraph",
+                                "content": [{ "type": "text", "text": "item" }],
+                            }],
+                        }],
+                    },
+                ],
+            }]),
+            render(input)
+        );
+    }
+
+    #[test]
+    fn test_table_alignment() {
+        let input = "\
+        | left | center | right |\n\
+        |:-----|:------:|------:|\n\
+        | a<br>b | c | d |";
+        let table = render(input);
+        let row = |i: usize| table[0]["content"][i]["content"].clone();
+        let align = |align: &str| json!([{ "type": "alignment", "attrs": { "align": align } }]);
+        for i in 0..2 {
+            assert_eq!(Value::Null, row(i)[0]["content"][0]["marks"]);
+            assert_eq!(align("center"), row(i)[1]["content"][0]["marks"]);
+            assert_eq!(align("end"), row(i)[2]["content"][0]["marks"]);
+        }
+        assert_eq!(
+            json!([
+                { "type": "text", "text": "a" },
+                { "type": "hardBreak" },
+                { "type": "text", "text": "b" },
+            ]),
+            row(1)[0]["content"][0]["content"]
+        );
+    }
+
+    #[test]
+    fn test_task_list() {
+        assert_eq!(
+            json!([{
+                "type": "taskList",
+                "attrs": { "localId": "task-2" },
+                "content": [
+                    {
+                        "type": "taskItem",
+                        "attrs": { "localId": "task-1", "state": "TODO" },
+                        "content": [{ "type": "text", "text": "todo" }],
+                    },
+                    {
+                        "type": "taskItem",
+                        "attrs": { "localId": "task-3", "state": "DONE" },
+                        "content": [{ "type": "text", "text": "done" }],
+                    },
+                ],
+            }]),
+            render("* [ ] todo\n* [x] done")
+        );
+    }
+
+    #[test]
+    fn test_loose_task_list() {
+        // each item's paragraphs become the task's inline content, and both tasks stay in the list
+        let tasks = render("- [ ] a\n\n- [x] b\n\n  c");
+        assert_eq!(1, tasks.as_array().unwrap().len());
+        assert_eq!(json!("taskList"), tasks[0]["type"]);
+        assert_eq!(
+            json!([
+                {
+                    "type": "taskItem",
+                    "attrs": { "localId": "task-1", "state": "TODO" },
+                    "content": [{ "type": "text", "text": "a" }],
+                },
+                {
+                    "type": "taskItem",
+                    "attrs": { "localId": "task-3", "state": "DONE" },
+                    "content": [
+                        { "type": "text", "text": "b" },
+                        { "type": "hardBreak" },
+                        { "type": "text", "text": "c" },
+                    ],
+                },
+            ]),
+            tasks[0]["content"]
+        );
+    }
+
+    #[test]
+    fn test_mixed_task_list() {
+        // a task list can only hold tasks, so the checkboxes are written as text
+        let item = |text: &str| {
+            json!({
+                "type": "listItem",
+                "content": [{ "type": "paragraph", "content": [{ "type": "text", "text": text }] }],
+            })
+        };
+        let task = |checkbox: &str, text: &str| {
+            json!({
+                "type": "listItem",
+                "content": [{
+                    "type": "paragraph",
+                    "content": [
+                        { "type": "text", "text": checkbox },
+                        { "type": "text", "text": text },
+                    ],
+                }],
+            })
+        };
+        assert_eq!(
+            json!([{
+                "type": "bulletList",
+                "content": [task("[ ] ", "a"), item("b"), task("[x] ", "c")],
+            }]),
+            render("- [ ] a\n- b\n- [x] c")
+        );
+    }
+
+    #[test]
+    fn test_ordered_task_list() {
+        // ADF task lists aren't numbered, and a mixed list stays numbered
+        let tasks = render("1. [ ] a\n2. [x] b");
+        assert_eq!(json!("taskList"), tasks[0]["type"]);
+        assert_eq!(json!({ "localId": "task-2" }), tasks[0]["attrs"]);
+        assert_eq!(json!("taskItem"), tasks[0]["content"][1]["type"]);
+        let mixed = render("3. [ ] a\n4. b");
+        assert_eq!(json!("orderedList"), mixed[0]["type"]);
+        assert_eq!(json!({ "order": 3 }), mixed[0]["attrs"]);
+        assert_eq!(
+            vec!["listItem", "listItem"],
+            mixed[0]["content"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(node_type)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_list_item_blocks() {
+        // list items can only hold paragraphs, lists, code and media
+        let paragraph = |content: Value| json!({ "type": "paragraph", "content": content });
+        let text = |text: &str| json!({ "type": "text", "text": text });
+        assert_eq!(
+            json!([{
+                "type": "bulletList",
+                "content": [{
+                    "type": "listItem",
+                    "content": [
+                        paragraph(json!([text("a")])),
+                        paragraph(json!([{
+                            "type": "text",
+                            "text": "Heading",
+                            "marks": [{ "type": "strong" }],
+                        }])),
+                        paragraph(json!([text("quoted")])),
+                        paragraph(json!([text("x"), text(" | "), text("y")])),
+                        paragraph(json!([text("1"), text(" | "), text("2")])),
+                    ],
+                }],
+            }]),
+            render("- a\n\n  ## Heading\n\n  > quoted\n\n  | x | y |\n  |---|---|\n  | 1 | 2 |")
+        );
+    }
+
+    #[test]
+    fn test_heading_image() {
+        // headings only hold inline content, so the image goes after the heading
+        let content = render("# Title ![logo](logo.png)");
+        assert_eq!(
+            vec!["heading", "mediaSingle"],
+            content
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(node_type)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            json!([{ "type": "text", "text": "Title " }]),
+            content[0]["content"]
+        );
+    }
+
+    #[test]
+    fn test_image_splits_paragraph() {
+        assert_eq!(
+            json!([
+                { "type": "paragraph", "content": [{ "type": "text", "text": "see " }] },
+                {
+                    "type": "mediaSingle",
+                    "content": [{
+                        "type": "media",
+                        "attrs": {
+                            "type": "external",
+                            "url": "https://example.com/a.png",
+                            "alt": "diagram",
+                        },
+                    }],
+                },
+            ]),
+            render("see ![diagram](https://example.com/a.png)")
+        );
+    }
+
+    #[test]
+    fn test_image_attributes() {
+        let image = json!([{
+            "type": "mediaSingle",
+            "attrs": { "layout": "align-end" },
+            "content": [{
+                "type": "media",
+                "attrs": {
+                    "type": "external",
+                    "url": "https://example.com/a.png",
+                    "alt": "diagram",
+                    "width": 300,
+                    "height": 200,
+                },
+            }],
+        }]);
+        assert_eq!(
+            image,
+            render("![diagram](https://example.com/a.png){width=300 height=200 align=right}")
+        );
+        assert_eq!(
+            "<img src=\"https://example.com/a.png\" alt=\"diagram\" width=\"300\" height=\"200\" align=\"right\" />\n",
+            import(doc(image))
+        );
+    }
+
+    #[test]
+    fn test_details_and_rule() {
+        assert_eq!(
+            json!([
+                {
+                    "type": "expand",
+                    "attrs": { "title": "Summary" },
+                    "content": [{
+                        "type": "paragraph",
+                        "content": [{ "type": "text", "text": "Content" }],
+                    }],
+                },
+                { "type": "rule" },
+            ]),
+            render("<details><summary>Summary</summary>Content</details>\n\n---")
+        );
+    }
+
+    #[test]
+    fn test_details_markdown() {
+        assert_eq!(
+            json!([{
+                "type": "expand",
+                "attrs": { "title": "Steps" },
+                "content": [
+                    {
+                        "type": "codeBlock",
+                        "content": [{ "type": "text", "text": "run" }],
+                    },
+                    {
+                        "type": "nestedExpand",
+                        "attrs": { "title": "" },
+                        "content": [{
+                            "type": "paragraph",
+                            "content": [{ "type": "text", "text": "more" }],
+                        }],
+                    },
+                ],
+            }]),
+            render(
+                "<details><summary>Steps</summary>\n\n    run\n\n<details>\n\nmore\n\n</details>\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_alerts() {
+        assert_eq!(
+            json!([{
+                "type": "panel",
+                "attrs": { "panelType": "error" },
+                "content": [{
+                    "type": "paragraph",
+                    "content": [{ "type": "text", "text": "careful" }],
+                }],
+            }]),
+            render("> [!CAUTION]\n> careful")
+        );
+    }
+
+    #[test]
+    fn test_footnotes() {
+        let paragraph = |content: Value| json!({ "type": "paragraph", "content": content });
+        let link = |text: &str, href: &str| json!({ "type": "text", "text": text, "marks": [{ "type": "link", "attrs": { "href": href } }] });
+        let mut reference = link("1", "#fn-a");
+        reference["marks"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({ "type": "subsup", "attrs": { "type": "sup" } }));
+        let text = |text: &str| json!({ "type": "text", "text": text });
+        let list = |item: Value| json!({ "type": "bulletList", "content": [{ "type": "listItem", "content": [item] }] });
+        assert_eq!(
+            json!([
+                paragraph(json!([text("claim"), anchor_node("fnref-a"), reference])),
+                { "type": "heading", "attrs": { "level": 2 }, "content": [text("Notes")] },
+                {
+                    "type": "orderedList",
+                    "attrs": { "order": 1 },
+                    "content": [
+                        {
+                            "type": "listItem",
+                            "content": [paragraph(json!([
+                                anchor_node("fn-a"),
+                                text("source"),
+                                text(" "),
+                                link("↩", "#fnref-a")
+                            ]))],
+                        },
+                        {
+                            "type": "listItem",
+                            "content": [
+                                paragraph(json!([anchor_node("fn-b")])),
+                                list(paragraph(json!([text("x")])))
+                            ],
+                        },
+                    ],
+                },
+            ]),
+            render("claim[^a]\n\n[^a]: source\n\n[^b]: - x\n")
+        );
+    }
+
+    #[test]
+    fn test_toc() {
+        assert_eq!(
+            json!([{
+                "type": "extension",
+                "attrs": {
+                    "extensionType": "com.atlassian.confluence.macro.core",
+                    "extensionKey": "toc",
+                },
+            }]),
+            render_with("", &RenderOptions::new().toc(true))
+        );
+    }
+
+    /// Converts an ADF document to markdown
+    fn import(doc: Value) -> String {
+        let mut output = Vec::new();
+        let events = parse(&doc.to_string(), &RenderOptions::new().language_map()).unwrap();
+        assert!(crate::markdown::write(&mut output, events.into_iter()).is_ok());
+        String::from_utf8(output).unwrap()
+    }
+
+    /// Wraps ADF nodes in a document
+    fn doc(content: Value) -> Value {
+        json!({ "version": 1, "type": "doc", "content": content })
+    }
+
+    /// Makes an ADF paragraph
+    fn paragraph(content: Value) -> Value {
+        json!({ "type": "paragraph", "content": content })
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse("{not json", &RenderOptions::new().language_map()).is_err());
+    }
+
+    #[test]
+    fn test_parse_marks() {
+        assert_eq!(
+            "**bold** _em_ ~~strike~~ `code` [link](https://example.com) <ins>u</ins> @jdoe\n",
+            import(doc(json!([paragraph(json!([
+                { "type": "text", "text": "bold", "marks": [{ "type": "strong" }] },
+                { "type": "text", "text": " " },
+                { "type": "text", "text": "em", "marks": [{ "type": "em" }] },
+                { "type": "text", "text": " " },
+                { "type": "text", "text": "strike", "marks": [{ "type": "strike" }] },
+                { "type": "text", "text": " " },
+                { "type": "text", "text": "code", "marks": [{ "type": "code" }] },
+                { "type": "text", "text": " " },
+                {
+                    "type": "text",
+                    "text": "link",
+                    "marks": [{ "type": "link", "attrs": { "href": "https://example.com" } }],
+                },
+                { "type": "text", "text": " " },
+                { "type": "text", "text": "u", "marks": [{ "type": "underline" }] },
+                { "type": "text", "text": " " },
+                { "type": "mention", "attrs": { "id": "123", "text": "@jdoe" } },
+            ]))])))
+        );
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(
+            "due 2024-02-29, 1969-12-31 or soon\n",
+            import(doc(json!([paragraph(json!([
+                { "type": "text", "text": "due " },
+                { "type": "date", "attrs": { "timestamp": "1709164800000" } },
+                { "type": "text", "text": ", " },
+                { "type": "date", "attrs": { "timestamp": "-1" } },
+                { "type": "text", "text": " or " },
+                { "type": "date", "attrs": { "timestamp": "soon" } },
+            ]))])))
+        );
+    }
+
+    #[test]
+    fn test_parse_blocks() {
+        assert_eq!(
+            "## title\n\n```bash\n$ ls\n```\n\n> [!WARNING]\n> careful\n\n---\n",
+            import(doc(json!([
+                {
+                    "type": "heading",
+                    "attrs": { "level": 2 },
+                    "content": [{ "type": "text", "text": "title" }],
+                },
+                {
+                    "type": "codeBlock",
+                    "attrs": { "language": "bash" },
+                    "content": [{ "type": "text", "text": "$ ls" }],
+                },
+                {
+                    "type": "panel",
+                    "attrs": { "panelType": "warning" },
+                    "content": [paragraph(json!([{ "type": "text", "text": "careful" }]))],
+                },
+                { "type": "rule" },
+            ])))
+        );
+    }
+
+    #[test]
+    fn test_parse_lists() {
+        assert_eq!(
+            "3. three\n   * nested\n\n* [x] done\n* [ ] todo\n",
+            import(doc(json!([
+                {
+                    "type": "orderedList",
+                    "attrs": { "order": 3 },
+                    "content": [{
+                        "type": "listItem",
+                        "content": [
+                            paragraph(json!([{ "type": "text", "text": "three" }])),
+                            {
+                                "type": "bulletList",
+                                "content": [{
+                                    "type": "listItem",
+                                    "content": [paragraph(json!([
+                                        { "type": "text", "text": "nested" },
+                                    ]))],
+                                }],
+                            },
+                        ],
+                    }],
+                },
+                {
+                    "type": "taskList",
+                    "attrs": { "localId": "a" },
+                    "content": [
+                        {
+                            "type": "taskItem",
+                            "attrs": { "localId": "b", "state": "DONE" },
+                            "content": [{ "type": "text", "text": "done" }],
+                        },
+                        {
+                            "type": "taskItem",
+                            "attrs": { "localId": "c", "state": "TODO" },
+                            "content": [{ "type": "text", "text": "todo" }],
+                        },
+                    ],
+                },
+            ])))
+        );
+    }
+
+    #[test]
+    fn test_parse_table() {
+        let cell = |kind: &str, text: &str| {
+            json!({
+                "type": kind,
+                "content": [paragraph(json!([{ "type": "text", "text": text }]))],
+            })
+        };
+        assert_eq!(
+            "| a | b |\n| --- | --- |\n| c | d |\n",
+            import(doc(json!([{
+                "type": "table",
+                "content": [
+                    { "type": "tableRow", "content": [cell("tableHeader", "a"), cell("tableHeader", "b")] },
+                    { "type": "tableRow", "content": [cell("tableCell", "c"), cell("tableCell", "d")] },
+                ],
+            }])))
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // markdown -> ADF -> markdown keeps the content
+        let input = "# title\n\n* one\n* two\n\n```bash\n$ ls\n```\n";
+        let mut output = Vec::new();
+        assert!(write(
+            &mut output,
+            Parser::new_ext(input, Options::all()),
+            &RenderOptions::new()
+        )
+        .is_ok());
+        let adf: Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(input, import(adf));
+    }
+
+    #[test]
+    fn test_table_alignment_round_trip() {
+        let input = "| a | b | c |\n| --- | :---: | ---: |\n| 1 | 2 | 3 |\n";
+        let mut output = Vec::new();
+        assert!(write(
+            &mut output,
+            Parser::new_ext(input, Options::all()),
+            &RenderOptions::new()
+        )
+        .is_ok());
+        let adf: Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(input, import(adf));
+    }
+
+    #[test]
+    fn test_page_properties() {
+        let input = "<!-- details id=adr -->\n\n| a |\n| --- |\n| 1 |\n\n\
+            <!-- detailssummary label=adr -->\n";
+        let content = render(input);
+        assert_eq!(2, content.as_array().unwrap().len());
+        assert_eq!(
+            json!({
+                "extensionType": "com.atlassian.confluence.macro.core",
+                "extensionKey": "details",
+                "parameters": { "macroParams": { "id": { "value": "adr" } } },
+            }),
+            content[0]["attrs"]
+        );
+        assert_eq!("bodiedExtension", node_type(&content[0]));
+        assert_eq!("table", node_type(&content[0]["content"][0]));
+        assert_eq!("extension", node_type(&content[1]));
+        assert_eq!("detailssummary", content[1]["attrs"]["extensionKey"]);
+        assert_eq!(input, import(doc(content)));
+    }
+}
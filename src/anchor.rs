@@ -0,0 +1,220 @@
+use pulldown_cmark::{Event, Tag, TagEnd};
+use std::collections::{HashMap, HashSet};
+
+/// Makes a GitHub style slug for a heading: lowercase, without punctuation, and with spaces
+/// replaced by hyphens
+///
+/// # Arguments
+///
+/// * `text` - the heading text
+///
+/// # Returns
+///
+/// * `String` - the slug, like `getting-started` for "Getting Started!"
+pub(crate) fn slugify(text: &str) -> String {
+    text.trim()
+        .to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('-'),
+            '-' | '_' => Some(c),
+            c if c.is_alphanumeric() => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Makes the anchor Confluence generates for a heading, which is the page title and the heading
+/// text without spaces, joined by a hyphen. Repeated headings get `.1`, `.2`, ... on the end
+///
+/// # Arguments
+///
+/// * `page_title` - the title of the page the heading is on
+/// * `text` - the heading text
+///
+/// # Returns
+///
+/// * `String` - the anchor, like `Runbook-GettingStarted`
+fn confluence_anchor(page_title: &str, text: &str) -> String {
+    let squash = |s: &str| s.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+    format!("{}-{}", squash(page_title), squash(text))
+}
+
+/// Where each heading in a document can be linked to, and what its `#fragment` links point at
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Anchors {
+    // the anchor macro to write at each heading, in document order. Only headings that are
+    // linked to get one
+    headings: Vec<Option<String>>,
+    // from a markdown fragment, like `setup`, to the anchor links to it should use
+    links: HashMap<String, String>,
+}
+
+impl Anchors {
+    /// Finds the headings in a document and gives each a GitHub style slug, or its custom
+    /// `{#id}`, so `#fragment` links to them can be rewritten
+    ///
+    /// # Arguments
+    ///
+    /// * `events` - the whole document
+    /// * `modify_headers` - the header offset. Headings that are dropped can't be linked to
+    /// * `page_title` - the Confluence page title. When it's known, links use the anchors
+    ///   Confluence generates for headings, and no anchor macros are needed
+    pub(crate) fn new(events: &[Event], modify_headers: i8, page_title: Option<&str>) -> Self {
+        let mut anchors = Anchors::default();
+        let mut used = HashSet::new();
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        let mut heading: Option<(String, Option<String>, bool)> = None;
+        let mut linked = HashSet::new();
+        let mut generated: HashMap<String, usize> = HashMap::new();
+        for event in events {
+            match event {
+                Event::Start(Tag::Link { dest_url, .. }) => {
+                    if let Some(fragment) = dest_url.strip_prefix('#') {
+                        linked.insert(fragment.to_string());
+                    }
+                }
+                Event::Start(Tag::Heading { level, id, .. }) => {
+                    let kept = *level as i8 + modify_headers > 0;
+                    heading = Some((String::new(), id.as_ref().map(|id| id.to_string()), kept));
+                }
+                Event::Text(text) | Event::Code(text) => {
+                    if let Some((heading_text, _, _)) = &mut heading {
+                        heading_text.push_str(text);
+                    }
+                }
+                Event::End(TagEnd::Heading(..)) => {
+                    let Some((text, id, kept)) = heading.take() else {
+                        continue;
+                    };
+                    let slug = match id {
+                        Some(id) => id,
+                        None => {
+                            // repeated headings get `-1`, `-2`, ... like on GitHub
+                            let base = slugify(&text);
+                            let mut slug = base.clone();
+                            while used.contains(&slug) {
+                                let count = counts.entry(base.clone()).or_insert(0);
+                                *count += 1;
+                                slug = format!("{}-{}", base, count);
+                            }
+                            slug
+                        }
+                    };
+                    used.insert(slug.clone());
+                    if !kept {
+                        anchors.headings.push(None);
+                        continue;
+                    }
+                    match page_title {
+                        Some(title) => {
+                            let anchor = confluence_anchor(title, &text);
+                            let count = generated.entry(anchor.clone()).or_insert(0);
+                            let anchor = match *count {
+                                0 => anchor,
+                                n => format!("{}.{}", anchor, n),
+                            };
+                            *count += 1;
+                            anchors.headings.push(None);
+                            anchors.links.insert(slug, anchor);
+                        }
+                        None => {
+                            anchors.headings.push(Some(slug.clone()));
+                            anchors.links.insert(slug.clone(), slug);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        for anchor in &mut anchors.headings {
+            if anchor.as_ref().is_some_and(|slug| !linked.contains(slug)) {
+                *anchor = None;
+            }
+        }
+        anchors
+    }
+
+    /// The anchor macro to write at a heading, if any
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - which heading, counting from 0 in document order
+    pub(crate) fn heading(&self, index: usize) -> Option<&str> {
+        self.headings.get(index)?.as_deref()
+    }
+
+    /// The anchor a `#fragment` link should point at, if the fragment is a heading
+    ///
+    /// # Arguments
+    ///
+    /// * `fragment` - the fragment, without the `#`
+    pub(crate) fn link(&self, fragment: &str) -> Option<&str> {
+        self.links.get(fragment).map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pulldown_cmark::{Options, Parser};
+
+    /// Finds the anchors in markdown
+    fn find_anchors(input: &str, modify_headers: i8, page_title: Option<&str>) -> Anchors {
+        let events: Vec<Event> = Parser::new_ext(input, Options::all()).collect();
+        Anchors::new(&events, modify_headers, page_title)
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!("getting-started", slugify("Getting Started!"));
+        assert_eq!("whats-new-in-v2", slugify("What's new in v2?"));
+        assert_eq!("snake_case---dashes", slugify("snake_case & -dashes"));
+        assert_eq!("ünïcode", slugify("Ünïcode"));
+    }
+
+    #[test]
+    fn test_duplicates_and_ids() {
+        let anchors = find_anchors(
+            "# Setup\n## Setup\n## `run` it\n## Custom {#install}\n\
+             [a](#setup) [b](#setup-1) [c](#run-it) [d](#install)\n",
+            0,
+            None,
+        );
+        assert_eq!(Some("setup"), anchors.heading(0));
+        assert_eq!(Some("setup-1"), anchors.heading(1));
+        assert_eq!(Some("run-it"), anchors.heading(2));
+        assert_eq!(Some("install"), anchors.heading(3));
+        assert_eq!(Some("install"), anchors.link("install"));
+        assert_eq!(None, anchors.link("custom"));
+        // headings that aren't linked to don't need an anchor
+        let anchors = find_anchors("# Setup\n## Usage\n[a](#usage)", 0, None);
+        assert_eq!(None, anchors.heading(0));
+        assert_eq!(Some("usage"), anchors.heading(1));
+    }
+
+    #[test]
+    fn test_modified_headings() {
+        let anchors = find_anchors("# Title\n## Setup\n[a](#title) [b](#setup)", -1, None);
+        assert_eq!(None, anchors.heading(0));
+        assert_eq!(None, anchors.link("title"));
+        assert_eq!(Some("setup"), anchors.heading(1));
+    }
+
+    #[test]
+    fn test_page_title() {
+        let anchors = find_anchors(
+            "# Getting Started\n## Setup\n## Setup\n[a](#setup)",
+            0,
+            Some("Ops Runbook"),
+        );
+        assert_eq!(None, anchors.heading(0));
+        assert_eq!(None, anchors.heading(1));
+        assert_eq!(
+            Some("OpsRunbook-GettingStarted"),
+            anchors.link("getting-started")
+        );
+        assert_eq!(Some("OpsRunbook-Setup"), anchors.link("setup"));
+        assert_eq!(Some("OpsRunbook-Setup.1"), anchors.link("setup-1"));
+    }
+}
@@ -1,93 +1,42 @@
 use ego_tree::NodeRef;
-use markup5ever::local_name;
 use pulldown_cmark::*;
 use scraper::{Html, Node};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::{self, Write};
 
-/// Builds the language mapper
+use crate::anchor::Anchors;
+use crate::dialect::{Dialect, Flavor};
+use crate::escape::{escape_text, Context};
+use crate::footnotes::{self, Footnotes};
+use crate::html::{self, DetailsPart, HtmlBuffer, Markup};
+use crate::image::Image;
+use crate::languages::LanguageMapper;
+use crate::links::{Resolver, Target};
+use crate::options::RenderOptions;
+use crate::properties::Directive;
+
+/// Inverts the language mapper, for converting Atlassian markup back to markdown
+///
+/// # Arguments
+///
+/// * `lang_map` - the language map
 ///
 /// # Returns
 ///
-/// * `lang_map` - HashMap<String, String> from markdown to confluence-supported code block langs
-fn build_lang_map() -> HashMap<String, String> {
-    let mut lang_map = HashMap::new();
-    let approved_langs = [
-        "actionscript3",
-        "applescript",
-        "bash",
-        "c#",
-        "c++",
-        "css",
-        "coldfusion",
-        "delphi",
-        "diff",
-        "erlang",
-        "groovy",
-        "xml",
-        "java",
-        "jfx",
-        "javascript",
-        "php",
-        "text",
-        "powershell",
-        "python",
-        "ruby",
-        "sql",
-        "sass",
-        "scala",
-        "vb",
-        "yaml",
-    ];
-    for &lang in &approved_langs {
-        // add map from self to self
-        lang_map.insert(lang.to_string(), lang.to_string());
-    }
-
-    /// build aliases/mappings from markdown -> confluence
-    ///
-    /// # Arguments
-    ///
-    /// * `sub_map` - the language map
-    /// * `approved_lang` - the confluence keyword
-    /// * `aliases` - vec![] of markdown keywords
-    fn build_aliases(
-        sub_map: &mut std::collections::HashMap<std::string::String, std::string::String>,
-        approved_lang: &str,
-        aliases: Vec<&str>,
-    ) {
-        for alias in aliases {
-            sub_map.insert(alias.to_string(), approved_lang.to_string());
-        }
-    }
-
-    // aliases and mapping between languages
-    // honestly, there should be a better way of doing this...
-    build_aliases(&mut lang_map, "actionscript3", vec!["as3", "actionscript"]);
-    build_aliases(&mut lang_map, "applescript", vec!["osascript"]);
-    build_aliases(&mut lang_map, "bash", vec!["console", "shell", "zsh", "sh"]);
-    build_aliases(&mut lang_map, "c#", vec!["csharp"]);
-    build_aliases(&mut lang_map, "c++", vec!["cpp"]);
-    build_aliases(
-        &mut lang_map,
-        "coldfusion",
-        vec!["cfm", "cfml", "coldfusion html"],
-    );
-    build_aliases(&mut lang_map, "delphi", vec!["pascal", "objectpascal"]);
-    build_aliases(&mut lang_map, "diff", vec!["udiff"]);
-    build_aliases(&mut lang_map, "xml", vec!["html"]);
-    build_aliases(&mut lang_map, "jfx", vec!["java fx"]);
-    build_aliases(&mut lang_map, "javascript", vec!["js", "node"]);
-    build_aliases(&mut lang_map, "php", vec!["inc"]);
-    build_aliases(&mut lang_map, "powershell", vec!["posh"]);
-    build_aliases(
-        &mut lang_map,
-        "ruby",
-        vec!["jruby", "macruby", "rake", "rb", "rbx"],
-    );
-    build_aliases(&mut lang_map, "sass", vec!["scss", "less", "stylus"]);
-    build_aliases(&mut lang_map, "vb", vec!["visual basic", "vb.net", "vbnet"]);
-    lang_map
+/// * `HashMap<String, String>` - from Atlassian-supported code block langs to markdown ones
+pub(crate) fn invert_lang_map(lang_map: &HashMap<String, String>) -> HashMap<String, String> {
+    let mut inverted: HashMap<String, String> = HashMap::new();
+    for (alias, lang) in lang_map {
+        // prefer the language itself, then the alphabetically first alias so this is stable
+        let keep = match inverted.get(lang) {
+            Some(existing) => existing != lang && (alias == lang || alias < existing),
+            None => true,
+        };
+        if keep {
+            inverted.insert(lang.clone(), alias.clone());
+        }
+    }
+    inverted
 }
 
 /// Makes a list of characters to escape when inside curly braces
@@ -95,7 +44,7 @@ fn build_lang_map() -> HashMap<String, String> {
 /// # Returns
 ///
 /// * `HashMap<String, String>` - from original character to escaped sequence
-fn make_escape_list() -> HashMap<String, String> {
+pub(crate) fn make_escape_list() -> HashMap<String, String> {
     let mut escape_map = HashMap::new();
 
     fn add_escape(
@@ -114,8 +63,30 @@ fn make_escape_list() -> HashMap<String, String> {
     escape_map
 }
 
+/// Every kind of GitHub alert
+pub(crate) const ALERT_KINDS: [BlockQuoteKind; 5] = [
+    BlockQuoteKind::Note,
+    BlockQuoteKind::Tip,
+    BlockQuoteKind::Important,
+    BlockQuoteKind::Warning,
+    BlockQuoteKind::Caution,
+];
+
+/// Gets the name of a macro from its opening spec, so it can be closed
+///
+/// # Arguments
+///
+/// * `spec` - the macro without braces, like `panel:title=Note|bgColor=#fff`
+///
+/// # Returns
+///
+/// * `&str` - the macro name, like `panel`
+pub(crate) fn macro_name(spec: &str) -> &str {
+    spec.split([':', '|']).next().unwrap_or(spec)
+}
+
 /// The JiraWriter takes events from pulldown-cmark and formats it into Atlassian markup
-struct AtlassianWriter<I, W> {
+struct AtlassianWriter<'a, I, W> {
     iter: I,
     writer: W,
     // if we ended on a newline so we can fix newlines for lists
@@ -124,10 +95,34 @@ struct AtlassianWriter<I, W> {
     table_header: bool,
     // what bullets we're working with
     bullet_stack: Vec<u8>,
+    // the last character written, so escaping knows what comes before text
+    last_char: Option<char>,
+    // code block content is written as-is
+    in_code_block: bool,
+    // where text is being written, for escaping
+    context: Context,
+    // if we're in a table, where text can't have pipes or newlines
+    in_table: bool,
+    // if nothing has been written in the current table cell yet
+    cell_blank: bool,
+    // lists in the current table cell, with the next number for ordered ones
+    cell_lists: Vec<Option<u64>>,
+    // the next number of a top level ordered list that doesn't start at 1, which is written as
+    // text since wiki lists always count from 1
+    numbered_items: Option<u64>,
+    // list items, and the quotes inside them, we're in, with if a block has been written in each
+    // yet. A blank line would end the list, so blocks in them are kept on adjacent lines.
+    item_blocks: Vec<bool>,
+    // if an item has started but its bullets haven't been written, since a task replaces them
+    pending_item: bool,
+    // if the top level list we're in only has tasks, with nothing nested
+    simple_task_list: bool,
+    // events that have been looked ahead at, to be handled before the rest of `iter`
+    lookahead: VecDeque<Event<'a>>,
     // must ensure space after inline code end curly brace
     inline_code: bool,
     // map between markdown/confluence code block langs
-    lang_map: HashMap<String, String>,
+    languages: LanguageMapper,
     // add modify_headers to header level
     modify_headers: i8,
     // if the current line should be output. Solves the issue of header parts being output when
@@ -135,14 +130,41 @@ struct AtlassianWriter<I, W> {
     should_output_line: bool,
     // escape some stuff in the code blocks, etc.
     escape_map: HashMap<String, String>,
-    // jira or confluence
-    flavor: char,
-    cached_html_content: String,
+    // how to spell per-target constructs
+    dialect: &'static dyn Dialect,
+    // block HTML that is parsed once its tags are balanced
+    html_buffer: HtmlBuffer,
+    // if each inline `<a>` we're in is a link, rather than only a target
+    html_links: Vec<bool>,
+    // HTML elements that have been reported as unsupported
+    unknown_html: HashSet<String>,
+    // `<details>` that have been opened in one HTML block, to be closed in a later one
+    open_details: usize,
+    // expand macros that are open. Wiki markup can't nest them, so only the outermost is written
+    expand_depth: usize,
+    // the page properties macro the next table is written in, from a directive before it
+    properties: Option<Directive>,
+    // if the current table is in a page properties macro
+    properties_table: bool,
     // cache the url for links because we need to put the text first
     dest_url: String,
+    // opening macros for each kind of github alert
+    alert_map: HashMap<BlockQuoteKind, String>,
+    // closing macros of the blockquotes we're in
+    quote_stack: Vec<String>,
+    // anchors for headings, and where `#fragment` links to them point
+    anchors: Anchors,
+    // where relative links and images point
+    resolver: Resolver,
+    // how many headings have been started, to find their anchors
+    heading_count: usize,
+    // footnote references and their rendered definitions
+    footnotes: Footnotes<String>,
+    // the footnote definition being rendered, which is written at the end instead of in place
+    footnote_buffer: Option<(String, String)>,
 }
 
-impl<'a, I, W> AtlassianWriter<I, W>
+impl<'a, I, W> AtlassianWriter<'a, I, W>
 where
     I: Iterator<Item = Event<'a>>,
     W: Write,
@@ -153,25 +175,50 @@ where
     ///
     /// * `iter` - iterator of elements provided by `pulldowm_cmark`
     /// * `writer` - something implementing Write to write output to
-    /// * `modify_headers` - int to increment/decrement headers by
-    /// * `flavor` - j or c for jira or confluence, respectively
-    fn new(iter: I, writer: W, modify_headers: i8, flavor: char) -> Self {
-        // confluence/jira only implements the following language highlighting
-        // doing this now means the cost is 1 instead of N
+    /// * `options` - language map, escape rules, header offset and flavor to render with
+    /// * `anchors` - anchors for the document's headings
+    fn new(iter: I, writer: W, options: &RenderOptions, anchors: Anchors) -> Self {
         AtlassianWriter {
             iter,
             writer,
             end_newline: false,
             table_header: false,
             bullet_stack: vec![],
+            last_char: None,
+            in_code_block: false,
+            context: Context::Text,
+            in_table: false,
+            cell_blank: false,
+            cell_lists: vec![],
+            numbered_items: None,
+            item_blocks: vec![],
+            pending_item: false,
+            simple_task_list: false,
+            lookahead: VecDeque::new(),
             inline_code: false,
-            lang_map: build_lang_map(),
-            modify_headers,
+            languages: LanguageMapper::new(options),
+            modify_headers: options.modify_headers,
             should_output_line: true,
-            escape_map: make_escape_list(),
-            flavor,
-            cached_html_content: "".to_string(),
+            escape_map: options.escape_map.clone(),
+            dialect: options.flavor.dialect(),
+            html_buffer: HtmlBuffer::default(),
+            html_links: vec![],
+            unknown_html: HashSet::new(),
+            open_details: 0,
+            expand_depth: 0,
+            properties: None,
+            properties_table: false,
             dest_url: "".to_string(),
+            alert_map: ALERT_KINDS
+                .into_iter()
+                .map(|kind| (kind, options.alert_macro(kind).to_string()))
+                .collect(),
+            quote_stack: vec![],
+            anchors,
+            resolver: Resolver::new(options),
+            heading_count: 0,
+            footnotes: Footnotes::default(),
+            footnote_buffer: None,
         }
     }
 
@@ -182,20 +229,134 @@ where
     ///
     /// * `s` - string to write
     fn write(&mut self, s: &str) -> io::Result<()> {
+        if self.pending_item {
+            self.pending_item = false;
+            self.write(&(self.bullets() + " "))?;
+        }
         if self.should_output_line {
             self.end_newline = s.ends_with('\n');
-            self.writer.write_all(s.as_bytes())
+            self.last_char = s.chars().last().or(self.last_char);
+            self.cell_blank &= s.is_empty();
+            match &mut self.footnote_buffer {
+                Some((_, buffer)) => {
+                    buffer.push_str(s);
+                    Ok(())
+                }
+                None => self.writer.write_all(s.as_bytes()),
+            }
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the next event, taking looked ahead events first
+    fn next_event(&mut self) -> Option<Event<'a>> {
+        self.lookahead.pop_front().or_else(|| self.iter.next())
+    }
+
+    /// Reads ahead to the end of the list that just started, and checks if all of its items are
+    /// tasks with nothing nested in them. The events are handled as usual afterwards.
+    fn is_simple_task_list(&mut self) -> bool {
+        let mut depth = 1;
+        let mut simple = true;
+        let mut item_start = false;
+        while depth > 0 {
+            let Some(event) = self.iter.next() else {
+                break;
+            };
+            match &event {
+                Event::Start(Tag::List(_)) => {
+                    depth += 1;
+                    simple = false;
+                }
+                Event::End(TagEnd::List(_)) => depth -= 1,
+                Event::Start(Tag::Item) => item_start = true,
+                Event::TaskListMarker(_) => (),
+                // the marker of a loose item comes after the start of its paragraph
+                Event::Start(Tag::Paragraph) if item_start => (),
+                _ if item_start => simple = false,
+                _ => (),
+            }
+            if !matches!(event, Event::Start(Tag::Item | Tag::Paragraph)) {
+                item_start = false;
+            }
+            self.lookahead.push_back(event);
+        }
+        simple
+    }
+
+    /// The escaping context outside of links and images
+    fn table_context(&self) -> Context {
+        if self.in_table {
+            Context::TableCell
         } else {
+            Context::Text
+        }
+    }
+
+    /// The bullets for an item in the current list, like `*#`
+    fn bullets(&self) -> String {
+        String::from_utf8(self.bullet_stack.to_vec()).unwrap()
+    }
+
+    /// Writes a line break, which is `\\` in a table since a newline would end the row
+    fn write_hard_break(&mut self) -> io::Result<()> {
+        if self.in_table {
+            self.write("\\\\")
+        } else {
+            self.write_newline()
+        }
+    }
+
+    /// Starts a new line in a table cell, unless nothing has been written in it yet
+    fn write_cell_line(&mut self) -> io::Result<()> {
+        if self.cell_blank {
             Ok(())
+        } else {
+            self.write("\\\\")
         }
     }
 
+    /// Starts a block inside a list item, where a blank line would end the list. Blocks after the
+    /// first go on a new line, and paragraphs after the first are set apart with `\\`.
+    ///
+    /// # Arguments
+    ///
+    /// * `paragraph` - if the block is a paragraph, which can follow the bullets on their line
+    ///
+    /// # Returns
+    ///
+    /// * `Result` - if the block is in a list item, so it shouldn't be set apart with blank lines
+    fn start_item_block(&mut self, paragraph: bool) -> io::Result<bool> {
+        let Some(written) = self.item_blocks.last_mut() else {
+            return Ok(false);
+        };
+        let written = std::mem::replace(written, true);
+        if (written || !paragraph) && !self.end_newline {
+            self.write_newline()?;
+        }
+        if written && paragraph {
+            self.write("\\\\")?;
+        }
+        Ok(true)
+    }
+
     /// Writes a newline to underlying `writer`.
     fn write_newline(&mut self) -> io::Result<()> {
         self.write("\n")
     }
 
-    /// Replace curly braces (and other special chars) so macros don't explode
+    /// Replace curly braces (and other special chars) so macros don't explode, then write it
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - string to check
+    fn write_escaped(&mut self, s: &str) -> io::Result<()> {
+        let escaped = self.escape_code(s);
+        self.write(&escaped)
+    }
+
+    /// Replace curly braces (and other special chars) using the escape map
     ///
     /// # Arguments
     ///
@@ -203,8 +364,8 @@ where
     ///
     /// # Returns
     ///
-    /// * `s` - string with {} replaced with HTML equivalent
-    fn write_escaped(&mut self, s: &str) -> io::Result<()> {
+    /// * `String` - string with {} replaced with HTML equivalent
+    fn escape_code(&self, s: &str) -> String {
         let mut r = String::from(s);
         for (key, value) in self.escape_map.iter() {
             r = r.replace(key, value);
@@ -214,75 +375,390 @@ where
         if r.starts_with('-') {
             r.replace_range(0..1, "\\-");
         }
-        self.write(&r)
+        r
+    }
+
+    /// Writes an image, with its size, alignment, alt text and title as parameters
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - the image to write
+    fn write_image(&mut self, image: &Image) -> io::Result<()> {
+        // attachments are referenced by their file name
+        let source = match self.resolver.image(&image.source) {
+            Target::Url(url) | Target::Attachment(url) => url,
+            Target::Page { title, .. } => title,
+        };
+        let mut parameters = vec![];
+        if let Some(width) = image.width {
+            parameters.push(format!("width={}", width));
+        }
+        if let Some(height) = image.height {
+            parameters.push(format!("height={}", height));
+        }
+        if let Some(align) = image.align {
+            parameters.push(format!("align={}", align.as_str()));
+        }
+        for (name, value) in [("alt", &image.alt), ("title", &image.title)] {
+            if !value.is_empty() {
+                let escaped = escape_text(value, Context::Parameter, None, false, self.dialect);
+                parameters.push(format!(r#"{}="{}""#, name, escaped));
+            }
+        }
+        if parameters.is_empty() {
+            self.write(&format!("!{}!", source))
+        } else {
+            self.write(&format!("!{}|{}!", source, parameters.join(",")))
+        }
+    }
+
+    /// Reports an HTML element that isn't in the element table, once per document
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - the element name
+    fn warn_html(&mut self, name: &str) {
+        if self.unknown_html.insert(name.to_string()) {
+            log::warn!("<{}> isn't supported, so only its content is written", name);
+        }
+    }
+
+    /// Writes an inline HTML tag as the markup its element becomes
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - a single opening or closing tag, or a comment
+    fn write_inline_html(&mut self, tag: &str) -> io::Result<()> {
+        // comments aren't written
+        let Some((name, closing)) = html::parse_tag(tag) else {
+            return Ok(());
+        };
+        let Some(markup) = html::markup(&name) else {
+            if !closing {
+                self.warn_html(&name);
+            }
+            return Ok(());
+        };
+        match (markup, closing) {
+            (Markup::Wrap(open, _), false) => self.write(open),
+            (Markup::Wrap(_, close), true) => {
+                self.write(close)?;
+                // like inline code, a closing `}}` needs a space after it
+                self.inline_code |= close == "}}";
+                Ok(())
+            }
+            (Markup::Break, false) => self.write_hard_break(),
+            (Markup::Image, false) => match Image::from_html(tag) {
+                Some(image) => self.write_image(&image),
+                None => Ok(()),
+            },
+            (Markup::Link, false) => {
+                let href = html::attribute(tag, "href");
+                // `<a name="...">` is only somewhere to link to
+                self.html_links.push(href.is_some());
+                match href {
+                    Some(href) => self.start_tag(Tag::Link {
+                        link_type: LinkType::Inline,
+                        dest_url: CowStr::from(href),
+                        title: CowStr::from(""),
+                        id: CowStr::from(""),
+                    }),
+                    None => Ok(()),
+                }
+            }
+            (Markup::Link, true) => {
+                if self.html_links.pop() == Some(true) {
+                    self.end_tag(TagEnd::Link)
+                } else {
+                    Ok(())
+                }
+            }
+            // blocks can't be written in a line, so only their content is
+            _ => Ok(()),
+        }
+    }
+
+    /// Opens an expand macro. An expand in another one can't be written, so its content goes in
+    /// the outer one, after its title in bold
+    ///
+    /// # Arguments
+    ///
+    /// * `summary` - the `<summary>` element, whose content is the title
+    fn write_expand_start(&mut self, summary: Option<NodeRef<Node>>) -> io::Result<()> {
+        if self.end_newline {
+            self.write_newline()?;
+        }
+        // formatting in the title wouldn't be rendered
+        let title: Option<String> = summary.map(|summary| {
+            summary
+                .descendants()
+                .filter_map(|node| match node.value() {
+                    Node::Text(text) => Some(&**text),
+                    _ => None,
+                })
+                .collect()
+        });
+        let title = title.as_deref().map(str::trim);
+        self.expand_depth += 1;
+        if self.expand_depth > 1 {
+            log::warn!("wiki markup can't nest expands, so a nested `<details>` is flattened");
+            return match title {
+                Some(title) if !title.is_empty() => {
+                    let title = escape_text(title, Context::Text, None, true, self.dialect);
+                    self.write(&format!("*{}*", title))?;
+                    self.write_newline()
+                }
+                _ => Ok(()),
+            };
+        }
+        self.write("{expand")?;
+        if let Some(title) = title {
+            let title = escape_text(title, Context::Parameter, None, false, self.dialect);
+            self.write(&format!("|title={}", title))?;
+        }
+        self.write("}")?;
+        self.write_newline()
     }
 
-    /// Parses HTML to Atlassian markup
+    /// Closes an expand macro, on its own line
+    fn write_expand_end(&mut self) -> io::Result<()> {
+        if !self.end_newline {
+            self.write_newline()?;
+        }
+        self.expand_depth = self.expand_depth.saturating_sub(1);
+        if self.expand_depth > 0 {
+            // the flattened expand's content goes on in the outer one
+            return Ok(());
+        }
+        self.write("{expand}")?;
+        self.write_newline()
+    }
+
+    /// Writes block HTML that opens or closes `<details>`. The markdown between the tags is the
+    /// body of the expand, so it is written as usual
+    ///
+    /// # Arguments
+    ///
+    /// * `html` - the HTML, which doesn't have to have as many closing tags as opening ones
+    fn write_details_html(&mut self, html: &str) -> io::Result<()> {
+        for part in html::split_details(html) {
+            match part {
+                DetailsPart::Open(summary) => {
+                    let summary = summary.map(Html::parse_fragment);
+                    self.write_expand_start(summary.as_ref().map(|summary| summary.tree.root()))?;
+                    self.open_details += 1;
+                }
+                // a closing tag without an opening one has nothing to close
+                DetailsPart::Close if self.open_details > 0 => {
+                    self.open_details -= 1;
+                    self.write_expand_end()?;
+                }
+                DetailsPart::Close => (),
+                DetailsPart::Html(html) => {
+                    self.parse_html(Html::parse_fragment(html).tree.root())?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes block HTML whose tags are balanced, which is either a page properties directive or
+    /// HTML to parse
+    ///
+    /// # Arguments
+    ///
+    /// * `html` - the HTML
+    fn write_block_html(&mut self, html: &str) -> io::Result<()> {
+        let Some(directive) = Directive::parse(html) else {
+            return self.parse_html(Html::parse_fragment(html).tree.root());
+        };
+        if !self.dialect.page_properties() {
+            log::warn!(
+                "page properties are only in Confluence, so `<!-- {} -->` is left out",
+                directive.name()
+            );
+            return Ok(());
+        }
+        match directive {
+            // written when the table starts
+            Directive::Details(_) => self.properties = Some(directive),
+            Directive::Report(_) => {
+                if self.end_newline {
+                    self.write_newline()?;
+                }
+                self.write(&directive.wiki_macro())?;
+                self.write_newline()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses the children of an HTML node to Atlassian markup
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - node whose children to parse
+    fn parse_html_children(&mut self, node: NodeRef<Node>) -> io::Result<()> {
+        for child in node.children() {
+            self.parse_html(child)?;
+        }
+        Ok(())
+    }
+
+    /// Parses HTML to Atlassian markup, writing each element as the markup in the element table
     ///
     /// # Arguments
     ///
     /// * `node` - node to parse
-    fn parse_html(&mut self, node: Option<NodeRef<Node>>) -> io::Result<()> {
-        match node {
-            // if there's no node to check, you've hit a leaf, so you're done here
-            None => Ok(()),
-            Some(n) => match n.value() {
-                Node::Element(elem) => {
-                    // we might need to skip parsing the child, because otherwise we get two
-                    // summary texts.
-                    let mut already_parsed = false;
-                    match elem.name.local {
-                        local_name!("details") => {
-                            self.write("{expand")?;
-                            // figure out if there is a summary amongst the children
-                            // if so, we should not write the ending curly brace.
-                            let mut should_write = true;
-                            for next_child in n.children() {
-                                // if the next scraper node elem is an element, and if it is a
-                                // summary element, we should not write the closing curly; the
-                                // summary handler will do that for us
-                                if let Node::Element(next_elem) = next_child.value() {
-                                    if matches!(next_elem.name.local, local_name!("summary")) {
-                                        should_write = false;
-                                    }
-                                }
-                            }
-                            // if there is no summary children, write the curly brace now
-                            if should_write {
-                                self.write("}\n")?;
-                            }
-                        }
-                        local_name!("summary") => {
-                            self.write("|title=")?;
-                            self.parse_html(n.first_child())?;
-                            self.write("}\n")?;
-                            // we don't need to parse the first child again
-                            already_parsed = true;
-                        }
-                        _ => (),
-                    }
-                    // if the next child is not yet parsed (wasn't a summary), parse it
-                    if !already_parsed {
-                        self.parse_html(n.first_child())?;
+    fn parse_html(&mut self, node: NodeRef<Node>) -> io::Result<()> {
+        let elem = match node.value() {
+            Node::Element(elem) => elem,
+            Node::Text(text) => {
+                // whitespace between rows and cells isn't content
+                let parent = node.parent().and_then(|parent| match parent.value() {
+                    Node::Element(parent) => html::markup(parent.name()),
+                    _ => None,
+                });
+                if matches!(
+                    parent,
+                    Some(Markup::Table | Markup::TableSection | Markup::TableRow)
+                ) {
+                    return Ok(());
+                }
+                // strip some noise
+                let str_text = text.trim_start_matches('\n').trim_start_matches(' ');
+                if self.in_table {
+                    // a newline would end the row, and `|` would start a new cell
+                    let escaped = escape_text(
+                        str_text.trim_end(),
+                        Context::TableCell,
+                        self.last_char,
+                        self.end_newline,
+                        self.dialect,
+                    );
+                    return self.write(&escaped);
+                }
+                return self.write_escaped(str_text);
+            }
+            Node::Fragment | Node::Document => return self.parse_html_children(node),
+            // comments don't make it to the output
+            _ => return Ok(()),
+        };
+        let Some(markup) = html::markup(elem.name()) else {
+            self.warn_html(elem.name());
+            return self.parse_html_children(node);
+        };
+        match markup {
+            Markup::Wrap(open, close) => {
+                self.write(open)?;
+                self.parse_html_children(node)?;
+                self.write(close)?;
+                self.inline_code |= close == "}}";
+                Ok(())
+            }
+            Markup::Break => self.write_hard_break(),
+            Markup::Rule => {
+                self.write_newline()?;
+                self.write("----")?;
+                self.write_newline()
+            }
+            Markup::Link => match elem.attr("href") {
+                Some(href) => {
+                    self.start_tag(Tag::Link {
+                        link_type: LinkType::Inline,
+                        dest_url: CowStr::from(href.to_string()),
+                        title: CowStr::from(""),
+                        id: CowStr::from(""),
+                    })?;
+                    self.parse_html_children(node)?;
+                    self.end_tag(TagEnd::Link)
+                }
+                None => self.parse_html_children(node),
+            },
+            Markup::Image => {
+                // an image on its own is a paragraph
+                let top_level = node.parent().is_some_and(|parent| {
+                    matches!(parent.value(), Node::Element(parent) if parent.name() == "html")
+                });
+                if top_level {
+                    self.start_tag(Tag::Paragraph)?;
+                }
+                self.write_image(&Image::from_element(elem))?;
+                if top_level {
+                    self.end_tag(TagEnd::Paragraph)?;
+                }
+                Ok(())
+            }
+            Markup::Preformatted => {
+                let mut code = String::new();
+                for descendant in node.descendants() {
+                    if let Node::Text(text) = descendant.value() {
+                        code.push_str(text);
                     }
-                    // close off the expand tag
-                    if matches!(elem.name.local, local_name!("details")) {
-                        self.write("\n{expand}\n")?;
+                }
+                self.start_tag(Tag::CodeBlock(CodeBlockKind::Indented))?;
+                if self.in_table {
+                    // a code block in a cell becomes monospace lines
+                    let lines: Vec<String> = code
+                        .trim_end()
+                        .lines()
+                        .map(|line| self.escape_code(line))
+                        .collect();
+                    self.write(&lines.join("}}\\\\{{"))?;
+                } else {
+                    self.write(&code)?;
+                }
+                self.end_tag(TagEnd::CodeBlock)
+            }
+            Markup::Paragraph => {
+                self.start_tag(Tag::Paragraph)?;
+                self.parse_html_children(node)?;
+                self.end_tag(TagEnd::Paragraph)
+            }
+            Markup::Table => {
+                self.start_tag(Tag::Table(vec![]))?;
+                self.parse_html_children(node)?;
+                self.end_tag(TagEnd::Table)
+            }
+            Markup::TableRow => {
+                self.parse_html_children(node)?;
+                // the row ends with the marker of its last cell
+                let last_cell = node
+                    .children()
+                    .filter_map(|child| match child.value() {
+                        Node::Element(cell) => html::markup(cell.name()),
+                        _ => None,
+                    })
+                    .filter(|markup| matches!(markup, Markup::TableCell { .. }))
+                    .last();
+                match last_cell {
+                    Some(Markup::TableCell { header: true }) => self.write("||")?,
+                    Some(_) => self.write("|")?,
+                    None => return Ok(()),
+                }
+                self.write_newline()
+            }
+            Markup::TableCell { header } => {
+                self.write(if header { "||" } else { "|" })?;
+                self.cell_blank = true;
+                self.parse_html_children(node)
+            }
+            Markup::Details => {
+                let summary = node.children().find(|child| {
+                    matches!(child.value(), Node::Element(child) if child.name() == "summary")
+                });
+                self.write_expand_start(summary)?;
+                for child in node.children() {
+                    if Some(child) != summary {
+                        self.parse_html(child)?;
                     }
-                    // parse the rest of the elements
-                    self.parse_html(n.next_sibling())
-                }
-                Node::Text(text) => {
-                    // strip some noise
-                    let str_text = text.trim_start_matches('\n').trim_start_matches(' ');
-                    self.write_escaped(str_text)?;
-                    self.parse_html(n.next_sibling())
-                }
-                Node::Fragment => return self.parse_html(n.first_child()),
-                // we don't care about comments, because those shouldn't make it to the output
-                // we won't have a document, because we're generating/parsing fragments only
-                _ => Ok(()),
-            },
+                }
+                self.write_expand_end()
+            }
+            // a summary outside of details is only text
+            Markup::Summary | Markup::TableSection | Markup::Content => {
+                self.parse_html_children(node)
+            }
         }
     }
 
@@ -293,8 +769,22 @@ where
     /// Does not render raw HTML or footnote references.
     fn run(&mut self) -> io::Result<()> {
         // using this form means you have to have the Ok(()) at the end?
-        while let Some(event) = self.iter.next() {
+        while let Some(event) = self.next_event() {
+            // HTML that is still open is written where it was, before the markdown after it
+            if html::ends_html(&event) {
+                if let Some(html) = self.html_buffer.interrupt() {
+                    self.write_block_html(&html)?;
+                }
+            }
             match event {
+                Event::Start(Tag::MetadataBlock(_)) => {
+                    // front matter isn't content
+                    while let Some(event) = self.next_event() {
+                        if matches!(event, Event::End(TagEnd::MetadataBlock(_))) {
+                            break;
+                        }
+                    }
+                }
                 Event::Start(tag) => {
                     self.start_tag(tag)?;
                 }
@@ -302,16 +792,55 @@ where
                     self.end_tag(tag)?;
                 }
                 Event::Text(text) => {
-                    if self.inline_code && !text.starts_with(' ') {
+                    // pulldown-cmark splits text at escapes and entities, but escaping needs to
+                    // see it all at once
+                    let mut text = text.into_string();
+                    loop {
+                        match self.next_event() {
+                            Some(Event::Text(more)) => text.push_str(&more),
+                            Some(event) => {
+                                self.lookahead.push_front(event);
+                                break;
+                            }
+                            None => break,
+                        }
+                    }
+                    if self.inline_code {
                         // put a space after ending double curly brace
-                        self.write(" ")?;
+                        if !text.starts_with(' ') {
+                            self.write(" ")?;
+                        }
                         self.inline_code = false;
                     }
-                    self.write(&text)?;
+                    if self.in_code_block && self.in_table {
+                        // a code block in a cell becomes monospace lines
+                        let lines: Vec<String> = text
+                            .trim_end_matches('\n')
+                            .lines()
+                            .map(|line| self.escape_code(line).replace('|', "\\|"))
+                            .collect();
+                        self.write(&lines.join("}}\\\\{{"))?;
+                    } else if self.in_code_block {
+                        self.write(&text)?;
+                    } else {
+                        // bullets that haven't been written yet come before the text
+                        let (prev, line_start) = if self.pending_item {
+                            (Some(' '), false)
+                        } else {
+                            (self.last_char, self.end_newline)
+                        };
+                        let escaped =
+                            escape_text(&text, self.context, prev, line_start, self.dialect);
+                        self.write(&escaped)?;
+                    }
                 }
                 Event::Code(text) => {
                     self.write("{{")?;
-                    self.write_escaped(&text)?;
+                    let mut escaped = self.escape_code(&text);
+                    if self.in_table {
+                        escaped = escaped.replace('|', "\\|");
+                    }
+                    self.write(&escaped)?;
                     self.write("}}")?;
                     self.inline_code = true;
                 }
@@ -321,27 +850,48 @@ where
                 }
                 Event::HardBreak => {
                     // this is the double space followed by newline
-                    self.write_newline()?;
+                    self.write_hard_break()?;
                 }
+                Event::InlineHtml(html) => self.write_inline_html(&html)?,
                 Event::Rule => {
                     self.write_newline()?;
                     self.write("----")?;
                     self.write_newline()?;
                 }
-                Event::TaskListMarker(_) => {
-                    self.write_newline()?;
-                    self.write("[] ")?;
+                Event::FootnoteReference(label) => {
+                    let (number, first) = self.footnotes.reference(&label);
+                    let anchor = footnotes::anchor(&label);
+                    if first {
+                        // only the first reference can be jumped back to
+                        self.write(&format!("{{anchor:fnref-{}}}", anchor))?;
+                    }
+                    self.write(&format!("^[{}|#fn-{}]^", number, anchor))?;
+                }
+                Event::TaskListMarker(checked) => {
+                    // the task marker takes the place of the bullets
+                    self.pending_item = false;
+                    let prefix =
+                        self.dialect
+                            .task_item(&self.bullets(), checked, self.simple_task_list);
+                    self.write(&prefix)?;
                 }
                 Event::Html(string) => {
-                    self.cached_html_content += &string;
-                    // attempt to parse it. if it fails, we don't have a complete fragment yet.
-                    // this approach is highly naive and unoptimized!
-                    let parsed_html = Html::parse_fragment(&self.cached_html_content);
-                    if parsed_html.errors.is_empty() {
-                        // parse
-                        self.parse_html(Some(parsed_html.tree.root()))?;
-                        // clear the cached HTML content
-                        self.cached_html_content = String::new()
+                    self.html_buffer.push(&string);
+                    if html::has_details(&string) {
+                        // the summary can be on the next line, so take the rest of the block too
+                        while let Some(event) = self.next_event() {
+                            match event {
+                                Event::Html(more) => self.html_buffer.push(&more),
+                                event => {
+                                    self.lookahead.push_front(event);
+                                    break;
+                                }
+                            }
+                        }
+                        let html = self.html_buffer.take();
+                        self.write_details_html(&html)?;
+                    } else if let Some(html) = self.html_buffer.complete() {
+                        self.write_block_html(&html)?;
                     }
                 }
                 // File a PR if you need a feature
@@ -349,19 +899,85 @@ where
             }
         }
 
+        if let Some(html) = self.html_buffer.finish() {
+            self.write_block_html(&html)?;
+        }
+        // details that were never closed
+        while self.open_details > 0 {
+            self.open_details -= 1;
+            self.write_expand_end()?;
+        }
+        self.write_footnotes()
+    }
+
+    /// Stores the footnote definition being written, if there is one, and goes back to writing
+    /// the document
+    fn end_footnote(&mut self) {
+        if let Some((label, content)) = self.footnote_buffer.take() {
+            self.bullet_stack.pop();
+            self.item_blocks.pop();
+            self.footnotes.define(label, content);
+            // definitions are blocks of their own, so the output is at the start of a line
+            self.end_newline = true;
+        }
+    }
+
+    /// Writes the collected footnote definitions as a numbered list under a "Notes" heading, in
+    /// the order they were referenced. Definitions that were never referenced go last.
+    fn write_footnotes(&mut self) -> io::Result<()> {
+        let notes = self.footnotes.take_notes();
+        if notes.is_empty() {
+            return Ok(());
+        }
+        self.write_newline()?;
+        let level = 2 + self.modify_headers;
+        if (1..7).contains(&level) {
+            self.write(&format!("h{}. Notes", level))?;
+        } else {
+            self.write("*Notes*")?;
+        }
+        self.write_newline()?;
+        for note in notes {
+            let anchor = note.anchor;
+            // the blocks were written as those of a `#` item, so they stay in the note. The back
+            // link goes on the item's own line, since a nested list can't be left again
+            let content = note.content.trim_end();
+            let (first, rest) = content.split_at(content.find('\n').unwrap_or(content.len()));
+            self.write(&format!("# {{anchor:fn-{}}}{}", anchor, first))?;
+            if note.referenced {
+                self.write(&format!(" [↩|#fnref-{}]", anchor))?;
+            }
+            self.write(rest)?;
+            self.write_newline()?;
+        }
         Ok(())
     }
 
     /// Handles opening tags
-    /// Since Jira/Confluence doesn't have table alignment built in, we skip that here
-    /// Also, skip starting numbered lists at a non-one value...
+    /// Since wiki markup doesn't have table alignment built in, we skip that here
+    /// Wiki lists always count from 1, so a top level ordered list starting at another number,
+    /// like one continued after a code block, is written as lines of text with the numbers in
+    /// them. A list nested in one of those lines can't be indented under it, so it is written as
+    /// a list of its own, with a warning.
     ///
     /// # Arguments
     ///
     /// * `tag` - tag to open
     fn start_tag(&mut self, tag: Tag<'a>) -> io::Result<()> {
+        // the space after inline code is only needed if text follows it directly
+        self.inline_code = false;
+        if self.properties.is_some() && !matches!(tag, Tag::Table(_) | Tag::HtmlBlock) {
+            self.properties = None;
+            log::warn!("`<!-- details -->` isn't followed by a table, so it is left out");
+        }
         match tag {
-            Tag::Paragraph => self.write_newline(),
+            Tag::Paragraph if self.in_table => self.write_cell_line(),
+            Tag::Paragraph => {
+                if !self.start_item_block(true)? {
+                    self.write_newline()?;
+                }
+                Ok(())
+            }
             Tag::Heading { level, .. } => {
                 if self.end_newline {
                     self.write_newline()?;
@@ -375,64 +991,156 @@ where
                     HeadingLevel::H6 => 6,
                 };
                 parsed_level += self.modify_headers;
+                let anchor = self.anchors.heading(self.heading_count).map(str::to_string);
+                self.heading_count += 1;
                 if parsed_level > 0 {
                     if parsed_level < 7 {
                         // valid headers are between 0..=6
-                        self.write(&format!("h{}. ", parsed_level))
-                    } else {
-                        // if the header is > 6, then just treat it as regular text.
-                        Ok(())
+                        self.write(&format!("h{}. ", parsed_level))?;
+                    }
+                    // if the header is > 6, then just treat it as regular text.
+                    match anchor {
+                        Some(anchor) => self.write(&format!("{{anchor:{}}}", anchor)),
+                        None => Ok(()),
                     }
                 } else {
                     self.should_output_line = false; // skip header contents if header level <= 0
                     Ok(())
                 }
             }
-            Tag::BlockQuote(_) => {
-                // TODO: handle block_quote_kind later
-                self.write_newline()?;
-                self.write("{quote}")
+            Tag::FootnoteDefinition(label) => {
+                // the parser can start a definition before the last one has ended
+                self.end_footnote();
+                self.footnote_buffer = Some((label.to_string(), String::new()));
+                // the definition becomes an item of the Notes list, so its blocks are kept
+                // together like those of any list item
+                self.bullet_stack.push(b'#');
+                self.item_blocks.push(false);
+                self.end_newline = false;
+                Ok(())
+            }
+            Tag::BlockQuote(kind) => {
+                let in_item = self.start_item_block(false)?;
+                if !in_item {
+                    self.write_newline()?;
+                }
+                let open = match kind {
+                    Some(kind) => self.alert_map[&kind].clone(),
+                    None => "quote".to_string(),
+                };
+                self.quote_stack.push(macro_name(&open).to_string());
+                self.write(&format!("{{{}}}", open))?;
+                if in_item {
+                    self.item_blocks.push(false);
+                    self.write_newline()?;
+                }
+                Ok(())
+            }
+            Tag::CodeBlock(_) if self.in_table => {
+                self.write_cell_line()?;
+                self.in_code_block = true;
+                self.write("{{")
             }
             Tag::CodeBlock(code_block_kind) => {
-                self.write_newline()?;
-                self.write("{code")?;
-                if let CodeBlockKind::Fenced(language) = code_block_kind {
-                    let default = "text".to_string();
-                    let lang = self
-                        .lang_map
-                        .get(&language.to_string())
-                        .unwrap_or(&default)
-                        .clone();
-                    match self.flavor {
-                        'j' => self.write(&format!(":{}", &lang))?,
-                        'c' => self.write(&format!(":language={}", &lang))?,
-                        // panic if we don't know which flavor
-                        _ => panic!("Unknown atlassian markup flavor"),
-                    }
+                if !self.start_item_block(false)? {
+                    self.write_newline()?;
                 }
                 // skipping 4-space indented type
-                self.write("}")?;
+                let lang = match code_block_kind {
+                    CodeBlockKind::Fenced(language) => Some(self.languages.map(&language)),
+                    CodeBlockKind::Indented => None,
+                };
+                self.write(&self.dialect.code_block_start(lang.as_deref()))?;
+                self.in_code_block = true;
+                self.write_newline()
+            }
+            Tag::List(first_number) if self.in_table => {
+                self.cell_lists.push(first_number);
+                Ok(())
+            }
+            Tag::List(Some(first_number))
+                if first_number != 1
+                    && self.bullet_stack.is_empty()
+                    && self.numbered_items.is_none() =>
+            {
+                self.numbered_items = Some(first_number);
                 self.write_newline()
             }
             Tag::List(first_number) => {
+                if self.item_blocks.is_empty() {
+                    self.write_newline()?;
+                } else {
+                    self.start_item_block(false)?;
+                }
+                if self.bullet_stack.is_empty() {
+                    if self.numbered_items.is_some() {
+                        log::warn!(
+                            "a list nested in an ordered list that doesn't start at 1 is written \
+                             as a list of its own, since wiki lists always count from 1"
+                        );
+                    }
+                    self.simple_task_list = self.is_simple_task_list();
+                }
                 if first_number.is_some() {
                     self.bullet_stack.push(b'#');
                 } else {
                     self.bullet_stack.push(b'*');
                 }
-                self.write_newline()
+                Ok(())
+            }
+            Tag::Item if self.in_table => {
+                // wiki lists can't be in a cell, so write the bullets as text on their own lines
+                self.write_cell_line()?;
+                let bullet = match self.cell_lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => "• ".to_string(),
+                };
+                self.write(&bullet)
+            }
+            Tag::Item if self.bullet_stack.is_empty() => {
+                if !self.end_newline {
+                    self.write_newline()?;
+                }
+                let number = self.numbered_items.unwrap_or(1);
+                self.numbered_items = Some(number + 1);
+                self.item_blocks.push(false);
+                self.write(&format!("{}. ", number))
             }
             Tag::Item => {
                 if !self.end_newline {
                     self.write_newline()?;
                 }
-                self.write(
-                    &(String::from_utf8(self.bullet_stack.to_vec()).unwrap() + &String::from(" ")),
-                )
+                self.item_blocks.push(false);
+                self.pending_item = true;
+                Ok(())
+            }
+            Tag::Table(_) => {
+                let in_item = self.start_item_block(false)?;
+                if let Some(directive) = self.properties.take() {
+                    if self.end_newline && !in_item {
+                        self.write_newline()?;
+                    }
+                    self.write(&directive.wiki_macro())?;
+                    self.properties_table = true;
+                }
+                // the header row starts a new line, which is set apart from the block before it
+                // unless a blank line would end the list item it's in
+                if !in_item || !self.end_newline {
+                    self.write_newline()?;
+                }
+                self.in_table = true;
+                self.context = Context::TableCell;
+                Ok(())
+            }
+            Tag::TableCell => {
+                self.cell_blank = true;
+                Ok(())
             }
             Tag::TableHead => {
                 self.table_header = true;
-                self.write_newline()?;
                 self.write("||")
             }
             Tag::TableRow => {
@@ -446,10 +1154,61 @@ where
             Tag::Strong => self.write("*"),
             Tag::Strikethrough => self.write("-"),
             Tag::Link { dest_url, .. } => {
-                self.dest_url = dest_url.to_string();
+                // in-page links go to the heading's anchor
+                let anchor = dest_url
+                    .strip_prefix('#')
+                    .and_then(|f| self.anchors.link(f));
+                self.dest_url = match anchor {
+                    Some(anchor) => format!("#{}", anchor),
+                    None => match self.resolver.link(&dest_url) {
+                        Target::Page {
+                            title,
+                            anchor: Some(anchor),
+                        } => format!("{}#{}", title, anchor),
+                        Target::Page {
+                            title,
+                            anchor: None,
+                        } => title,
+                        Target::Url(url) | Target::Attachment(url) => url,
+                    },
+                };
+                self.context = Context::LinkText;
                 self.write("[")
             }
-            Tag::Image { dest_url, .. } => self.write(&format!(r#"!{}|title=""#, dest_url)),
+            Tag::Image {
+                dest_url, title, ..
+            } => {
+                // the alt text comes before the end tag, but is written as a parameter
+                let mut alt = String::new();
+                let mut depth = 1;
+                while let Some(event) = self.next_event() {
+                    match event {
+                        Event::Text(text) | Event::Code(text) => alt.push_str(&text),
+                        Event::Start(Tag::Image { .. }) => depth += 1,
+                        Event::End(TagEnd::Image) => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+                let mut image = Image::new(&dest_url, &alt, &title);
+                // `{width=300}` right after the image
+                match self.next_event() {
+                    Some(Event::Text(text)) => {
+                        let read = image.read_attributes(&text);
+                        if read < text.len() {
+                            self.lookahead
+                                .push_front(Event::Text(CowStr::from(text[read..].to_string())));
+                        }
+                    }
+                    Some(event) => self.lookahead.push_front(event),
+                    None => (),
+                }
+                self.write_image(&image)
+            }
             _ => Ok(()),
         }
     }
@@ -460,8 +1219,14 @@ where
     ///
     /// * `tag` - tag to close
     fn end_tag(&mut self, tag: TagEnd) -> io::Result<()> {
+        self.inline_code = false;
         match tag {
+            TagEnd::Paragraph if self.in_table || !self.item_blocks.is_empty() => Ok(()),
             TagEnd::Paragraph => self.write_newline(),
+            TagEnd::FootnoteDefinition => {
+                self.end_footnote();
+                Ok(())
+            }
             TagEnd::Heading(..) => {
                 if !self.should_output_line {
                     self.should_output_line = true;
@@ -471,21 +1236,57 @@ where
                 }
             }
             TagEnd::BlockQuote => {
-                self.write("{quote}")?;
+                let close = self
+                    .quote_stack
+                    .pop()
+                    .unwrap_or_else(|| "quote".to_string());
+                // items in the quote have closed, so anything left is the quote's own entry
+                if self.item_blocks.pop().is_some() && !self.end_newline {
+                    self.write_newline()?;
+                }
+                self.write(&format!("{{{}}}", close))?;
                 self.write_newline()
             }
+            TagEnd::CodeBlock if self.in_table => {
+                self.in_code_block = false;
+                self.write("}}")
+            }
             TagEnd::CodeBlock => {
+                // the closing macro must be on its own line
+                if !self.end_newline {
+                    self.write_newline()?;
+                }
                 self.write("{code}")?;
+                self.in_code_block = false;
                 self.write_newline()
             }
+            TagEnd::Item if !self.in_table => {
+                self.item_blocks.pop();
+                Ok(())
+            }
+            TagEnd::List(_) if self.in_table => {
+                self.cell_lists.pop();
+                Ok(())
+            }
             TagEnd::List(_) => {
-                self.bullet_stack.pop();
-                if self.bullet_stack.is_empty() {
+                if self.bullet_stack.pop().is_none() {
+                    self.numbered_items = None;
+                }
+                if self.bullet_stack.is_empty() && self.numbered_items.is_none() {
                     self.write_newline()
                 } else {
                     Ok(())
                 }
             }
+            TagEnd::Table => {
+                self.in_table = false;
+                self.context = Context::Text;
+                if std::mem::take(&mut self.properties_table) {
+                    self.write("{details}")?;
+                    self.write_newline()?;
+                }
+                Ok(())
+            }
             TagEnd::TableHead => {
                 self.table_header = false;
                 self.write_newline()
@@ -501,8 +1302,18 @@ where
             TagEnd::Emphasis => self.write("_"),
             TagEnd::Strong => self.write("*"),
             TagEnd::Strikethrough => self.write("-"),
-            TagEnd::Link => self.write(&format!("|{}]", self.dest_url)),
-            TagEnd::Image => self.write(r#"",alt=""!"#), // TODO: handle this better
+            TagEnd::Link => {
+                self.context = self.table_context();
+                self.write(&format!("|{}]", self.dest_url))
+            }
+            TagEnd::HtmlBlock => {
+                // elements like `<p>` end with the block
+                self.html_buffer.end_block();
+                match self.html_buffer.complete() {
+                    Some(html) => self.write_block_html(&html),
+                    None => Ok(()),
+                }
+            }
             // handle Item
             _ => Ok(()),
         }
@@ -516,16 +1327,46 @@ where
 /// * `writer` - something implementing the Write trait
 /// * `iter` - an iterator of Events from pulldown-cmark
 /// * `modify_headers` - a signed int to modify header levels
+/// * `flavor` - jira or confluence
 ///
 /// # Returns
 ///
 /// * `Result` - if the JiraWriter wrote successfully to `writer`
-pub fn write<'a, I, W>(writer: W, iter: I, modify_headers: i8, flavor: char) -> io::Result<()>
+pub fn write<'a, I, W>(writer: W, iter: I, modify_headers: i8, flavor: Flavor) -> io::Result<()>
+where
+    I: Iterator<Item = Event<'a>>,
+    W: Write,
+{
+    let options = RenderOptions::new()
+        .flavor(flavor)
+        .modify_headers(modify_headers);
+    write_with_options(writer, iter, &options)
+}
+
+/// Writes Jira/Confluence output using the language map and escape rules from `options`
+///
+/// # Arguments
+///
+/// * `writer` - something implementing the Write trait
+/// * `iter` - an iterator of Events from pulldown-cmark
+/// * `options` - the render options to use
+///
+/// # Returns
+///
+/// * `Result` - if the AtlassianWriter wrote successfully to `writer`
+pub fn write_with_options<'a, I, W>(writer: W, iter: I, options: &RenderOptions) -> io::Result<()>
 where
     I: Iterator<Item = Event<'a>>,
     W: Write,
 {
-    AtlassianWriter::new(iter, writer, modify_headers, flavor).run()
+    // links can point at headings further down, so find them all first
+    let events: Vec<Event<'a>> = iter.collect();
+    let page_title = match options.flavor {
+        Flavor::Confluence => options.page_title.as_deref(),
+        Flavor::Jira => None,
+    };
+    let anchors = Anchors::new(&events, options.modify_headers, page_title);
+    AtlassianWriter::new(events.into_iter(), writer, options, anchors).run()
 }
 
 /// Writes the table of contents macro
@@ -533,329 +1374,22 @@ where
 /// # Arguments
 ///
 /// * `writer` - something implementing the Write trait
+/// * `flavor` - jira or confluence
 ///
 /// # Returns
 ///
 /// * `Result` - if wrote successfully to `writer`
-pub fn write_toc<W>(mut writer: W) -> io::Result<()>
+pub fn write_toc<W>(mut writer: W, flavor: Flavor) -> io::Result<()>
 where
     W: Write,
 {
-    // one set of curly braces is consumed to escape the other.
-    // the output should be single curly brace (macro)
-    write!(writer, "{{toc}}\n\n")
+    write!(writer, "{}\n\n", flavor.dialect().toc_macro())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
-    #[test]
-    fn test_headings() {
-        let input = "# hello world";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!("h1. hello world\n", String::from_utf8(output).unwrap());
-
-        let input = "## hello world";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!("h2. hello world\n", String::from_utf8(output).unwrap());
-    }
-
-    #[test]
-    fn test_blockquote() {
-        let input = "> hello blockquote";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\n\
-                {quote}\n\
-                hello blockquote\n\
-                {quote}\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_codeblock_jira() {
-        let input = "\
-        ```java\n\
-        System.out.println(\"hello world\")\n\
-        ```";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\n\
-                {code:java}\n\
-                System.out.println(\"hello world\")\n\
-                {code}\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_codeblock_confluence() {
-        let input = "\
-        ```java\n\
-        System.out.println(\"hello world\")\n\
-        ```";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'c').is_ok());
-        assert_eq!(
-            "\n\
-                {code:language=java}\n\
-                System.out.println(\"hello world\")\n\
-                {code}\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_console_codeblock() {
-        let input = "\
-        ```console\n\
-        $ ./console-test.sh\n\
-        should be bash\n\
-        ```";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\n\
-                {code:bash}\n\
-                $ ./console-test.sh\n\
-                should be bash\n\
-                {code}\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_unknown_codeblock() {
-        let input = "\
-        ```unknown\n\
-        should be text\n\
-        ```";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\n\
-                {code:text}\n\
-                should be text\n\
-                {code}\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_nested_markup_inline_code() {
-        let input = "`inline code with an asterisk *` like `rm -rf ./*.extension`";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\n{{inline code with an asterisk \\*}} like {{rm -rf ./\\*.extension}}\n",
-            String::from_utf8(output).unwrap()
-        );
-        let input = "a flag like `-r`";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\na flag like {{\\-r}}\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_unordered_list() {
-        let input = "\
-        * item one\n\
-        * item two\n\
-        * item three";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\n\
-                * item one\n\
-                * item two\n\
-                * item three\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_nested_unordered_list() {
-        let input = "\
-        * item one\n\
-        * item two\n\
-        \t* nested item one\n\
-        \t* nested item two\n\
-        * item three";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\n\
-                * item one\n\
-                * item two\n\
-                ** nested item one\n\
-                ** nested item two\n\
-                * item three\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_nested_ordered_in_unordered_list() {
-        let input = "\
-        * item one\n\
-        * item two\n\
-        \t1. nested item one\n\
-        \t2. nested item two\n\
-        * item three";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\n\
-                * item one\n\
-                * item two\n\
-                *# nested item one\n\
-                *# nested item two\n\
-                * item three\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_ordered_list() {
-        let input = "\
-        1. item one\n\
-        2. item two\n\
-        3. item three";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\n\
-                # item one\n\
-                # item two\n\
-                # item three\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_table() {
-        let input = "\
-        | header 1 | header 2 |\n\
-        |----------|----------|\n\
-        | item 1   | item 2   |";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\n\
-                ||header 1||header 2||\n\
-                |item 1|item 2|\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_emphasis() {
-        let input = "this is _italics_ in a string";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\nthis is _italics_ in a string\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_bold() {
-        let input = "this is **bold** in a string";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\nthis is *bold* in a string\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_bold_italics() {
-        let input = "this is _**bold italics**_ in a string";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\nthis is _*bold italics*_ in a string\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_strikethrough() {
-        let input = "this is ~~strikethrough~~ in a string";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\nthis is -strikethrough- in a string\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_link() {
-        let input = "[link](https://example.com)";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\n[link|https://example.com]\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_image() {
-        let input = "![img title](https://example.com/image.jpg)";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            r#"
-!https://example.com/image.jpg|title="img title",alt=""!
-"#,
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_inline_code() {
-        let input = "some `inline code` here";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\nsome {{inline code}} here\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_inline_code_trailing_char() {
-        let input = "`inline`s content";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!(
-            "\n{{inline}} s content\n",
-            String::from_utf8(output).unwrap()
-        );
-    }
-
-    #[test]
-    fn test_horizontal_rule() {
-        let input = "---";
-        let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!("\n----\n", String::from_utf8(output).unwrap());
-    }
-
-    #[ignore] // doesn't work yet, weird parsing issues
     #[test]
     fn test_task_list() {
         let input = "\
@@ -863,12 +1397,18 @@ mod test {
         * [ ] task two\n\
         * [x] completed task";
         let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
+        assert!(write(
+            &mut output,
+            Parser::new_ext(input, Options::all()),
+            0,
+            Flavor::Jira
+        )
+        .is_ok());
         assert_eq!(
             "\n\
-                [] task one\n\
-                [] task two\n\
-                [x] completed task\n",
+                * (off) task one\n\
+                * (off) task two\n\
+                * (/) completed task\n",
             String::from_utf8(output).unwrap()
         );
     }
@@ -878,25 +1418,49 @@ mod test {
         // header level 1 + 1 = 2
         let input = "# hello world";
         let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 1, 'j').is_ok());
+        assert!(write(
+            &mut output,
+            Parser::new_ext(input, Options::all()),
+            1,
+            Flavor::Jira
+        )
+        .is_ok());
         assert_eq!("h2. hello world\n", String::from_utf8(output).unwrap());
 
         // header level 2 - 1 = 1
         let input = "## hello world";
         let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), -1, 'j').is_ok());
+        assert!(write(
+            &mut output,
+            Parser::new_ext(input, Options::all()),
+            -1,
+            Flavor::Jira
+        )
+        .is_ok());
         assert_eq!("h1. hello world\n", String::from_utf8(output).unwrap());
 
         // header level 1 - 1 = 0
         let input = "# hello world";
         let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), -1, 'j').is_ok());
+        assert!(write(
+            &mut output,
+            Parser::new_ext(input, Options::all()),
+            -1,
+            Flavor::Jira
+        )
+        .is_ok());
         assert_eq!("", String::from_utf8(output).unwrap());
 
         // header level 6 + 1 = 7
         let input = "###### hello world";
         let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 1, 'j').is_ok());
+        assert!(write(
+            &mut output,
+            Parser::new_ext(input, Options::all()),
+            1,
+            Flavor::Jira
+        )
+        .is_ok());
         assert_eq!("hello world\n", String::from_utf8(output).unwrap());
     }
 
@@ -905,52 +1469,105 @@ mod test {
         // header level 1 - 1 = 0
         let input = "# hello world `inline code`";
         let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), -1, 'j').is_ok());
+        assert!(write(
+            &mut output,
+            Parser::new_ext(input, Options::all()),
+            -1,
+            Flavor::Jira
+        )
+        .is_ok());
         assert_eq!("", String::from_utf8(output).unwrap());
     }
 
     #[test]
-    fn test_softbreak_newline() {
-        // softbreak should be a space, not a newline
-        let input = "new\nline";
+    fn test_toc() {
         let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!("\nnew line\n", String::from_utf8(output).unwrap());
+        assert!(write_toc(&mut output, Flavor::Confluence).is_ok());
+        assert_eq!("{toc}\n\n", String::from_utf8(output).unwrap());
     }
 
     #[test]
-    fn test_hardbreak_newline() {
-        let input = "new  \nline";
+    fn test_footnotes_modified_headings() {
+        let input = "claim[^a]\n\n[^a]: source";
         let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'j').is_ok());
-        assert_eq!("\nnew\nline\n", String::from_utf8(output).unwrap());
+        assert!(write(
+            &mut output,
+            Parser::new_ext(input, Options::all()),
+            -2,
+            Flavor::Confluence
+        )
+        .is_ok());
+        assert_eq!(
+            "\nclaim{anchor:fnref-a}^[1|#fn-a]^\n\n*Notes*\n# {anchor:fn-a}source [↩|#fnref-a]\n",
+            String::from_utf8(output).unwrap()
+        );
     }
 
     #[test]
-    fn test_toc() {
-        let mut output = Vec::new();
-        assert!(write_toc(&mut output).is_ok());
-        assert_eq!("{toc}\n\n", String::from_utf8(output).unwrap());
+    fn test_heading_links_with_page_title() {
+        let input = "# Setup\n[see setup](#setup)";
+        let render = |flavor: Flavor| {
+            let options = RenderOptions::new()
+                .flavor(flavor)
+                .page_title("Ops Runbook");
+            let mut output = Vec::new();
+            assert!(write_with_options(
+                &mut output,
+                Parser::new_ext(input, Options::all()),
+                &options
+            )
+            .is_ok());
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(
+            "h1. Setup\n\n[see setup|#OpsRunbook-Setup]\n",
+            render(Flavor::Confluence)
+        );
+        // jira doesn't make anchors for headings, so the title isn't used
+        assert_eq!(
+            "h1. {anchor:setup}Setup\n\n[see setup|#setup]\n",
+            render(Flavor::Jira)
+        );
     }
 
     #[test]
-    fn test_details_no_summary() {
-        let input = "<details>Content</details>";
+    fn test_relative_links() {
+        let input = "[a](other.md) [b](other.md#setup) [c](../api.md) ![d](./img/d.png)";
+        let options = RenderOptions::new()
+            .base_url("https://example.com/repo/docs/")
+            .page("./other.md", "Other Page");
         let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'c').is_ok());
+        assert!(write_with_options(
+            &mut output,
+            Parser::new_ext(input, Options::all()),
+            &options
+        )
+        .is_ok());
         assert_eq!(
-            "{expand}\nContent\n{expand}\n",
+            "\n[a|Other Page] [b|Other Page#setup] [c|https://example.com/repo/api.md] \
+             !d.png|alt=\"d\"!\n",
             String::from_utf8(output).unwrap()
         );
     }
 
     #[test]
-    fn test_details_with_summary() {
-        let input = "<details><summary>Summary</summary>Content</details>";
+    fn test_custom_alert() {
+        let input = "> [!CAUTION]\n> careful\n\n> [!NOTE]\n> hello";
+        let options = RenderOptions::new()
+            .alert(
+                BlockQuoteKind::Caution,
+                "panel:title=Danger|bgColor=#ffebe9",
+            )
+            .alert(BlockQuoteKind::Note, "note");
         let mut output = Vec::new();
-        assert!(write(&mut output, Parser::new_ext(input, Options::all()), 0, 'c').is_ok());
+        assert!(write_with_options(
+            &mut output,
+            Parser::new_ext(input, Options::all()),
+            &options
+        )
+        .is_ok());
         assert_eq!(
-            "{expand|title=Summary}\nContent\n{expand}\n",
+            "\n{panel:title=Danger|bgColor=#ffebe9}\ncareful\n{panel}\n\n{note}\nhello\n{note}\n",
             String::from_utf8(output).unwrap()
         );
     }
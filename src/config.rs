@@ -0,0 +1,304 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The name of a project configuration file
+pub const CONFIG_FILE: &str = ".markrust.toml";
+
+/// The output formats `language` can be set to, like `--language`
+const LANGUAGES: [&str; 5] = ["jira", "confluence", "storage", "adf", "markdown"];
+
+/// The kinds of GitHub alert `alerts` can set a macro for, like `--alert`
+const ALERT_KINDS: [&str; 5] = ["note", "tip", "important", "warning", "caution"];
+
+/// Checks that a setting is one of the values it can have, in any case
+///
+/// # Arguments
+///
+/// * `setting` - what the value is, for the error
+/// * `value` - the value
+/// * `known` - the values it can have
+///
+/// # Returns
+///
+/// * `Result` - nothing, or why the value isn't known
+fn check_setting(setting: &str, value: &str, known: &[&str]) -> io::Result<()> {
+    if known.iter().any(|known| known.eq_ignore_ascii_case(value)) {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "unknown {} `{}`, expected one of {}",
+            setting,
+            value,
+            known.join(", ")
+        ),
+    ))
+}
+
+/// Defaults for the CLI, from `.markrust.toml` files. Each setting is optional, so files can be
+/// layered: settings in a file closer to the input replace those further away, and CLI flags
+/// replace them all
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Output format, like `--language`: jira, confluence, storage, adf or markdown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// Prepend TOC markup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub toc: Option<bool>,
+    /// Add N to header level (can be negative)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modify_headers: Option<i8>,
+    /// Language table added to the built-in one, relative to the file that sets it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lang_map: Option<PathBuf>,
+    /// URL of the repository root, which relative links are joined onto
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    /// Repository root, which relative links and page map paths are resolved from, relative to
+    /// the file that sets it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<PathBuf>,
+    /// JSON file mapping markdown paths to Confluence page titles, relative to the file that
+    /// sets it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_map: Option<PathBuf>,
+    /// Write the front matter title as an `h1`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title_heading: Option<bool>,
+    /// Write the front matter as a page properties table
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub front_matter_table: Option<bool>,
+    /// Macro for each kind of GitHub alert, like `caution = "panel:title=Danger"`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub alerts: BTreeMap<String, String>,
+}
+
+impl Config {
+    /// Reads a configuration from TOML
+    ///
+    /// # Arguments
+    ///
+    /// * `toml` - the configuration
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Config>` - the configuration, or why it couldn't be read, like an unknown
+    ///   `language` or alert kind
+    pub fn from_toml(toml: &str) -> io::Result<Self> {
+        let config: Config =
+            toml::from_str(toml).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some(language) = &config.language {
+            check_setting("language", language, &LANGUAGES)?;
+        }
+        for kind in config.alerts.keys() {
+            check_setting("alert kind", kind, &ALERT_KINDS)?;
+        }
+        Ok(config)
+    }
+
+    /// Reads a configuration file. Paths in it are relative to the file, so they are joined
+    /// onto its directory
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the file
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Config>` - the configuration, or why it couldn't be read, naming the file
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let named = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+        let mut config =
+            Config::from_toml(&std::fs::read_to_string(path).map_err(named)?).map_err(named)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for file in [&mut config.lang_map, &mut config.page_map, &mut config.root]
+            .into_iter()
+            .flatten()
+        {
+            *file = dir.join(&*file);
+        }
+        Ok(config)
+    }
+
+    /// Finds the configuration files that apply to a directory: the user's, then every
+    /// `.markrust.toml` from the root down to the directory
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - the directory, like the input file's
+    /// * `config_home` - the user's configuration directory, like `$XDG_CONFIG_HOME`, which can
+    ///   have a `markrust/config.toml`
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<PathBuf>` - the files that exist, furthest first
+    pub fn find(dir: &Path, config_home: Option<&Path>) -> Vec<PathBuf> {
+        let user = config_home.map(|home| home.join("markrust").join("config.toml"));
+        let mut project: Vec<PathBuf> = dir
+            .ancestors()
+            .map(|ancestor| ancestor.join(CONFIG_FILE))
+            .collect();
+        project.reverse();
+        user.into_iter()
+            .chain(project)
+            .filter(|file| file.is_file())
+            .collect()
+    }
+
+    /// Reads and layers every configuration file that applies to a directory
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - the directory, like the input file's
+    /// * `config_home` - the user's configuration directory, like `$XDG_CONFIG_HOME`
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(Config, Vec<PathBuf>)>` - the configuration, and the files it came from
+    pub fn discover(dir: &Path, config_home: Option<&Path>) -> io::Result<(Self, Vec<PathBuf>)> {
+        let files = Config::find(dir, config_home);
+        let mut config = Config::default();
+        for file in &files {
+            config = config.merge(Config::from_file(file)?);
+        }
+        Ok((config, files))
+    }
+
+    /// Layers another configuration over this one
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - the configuration whose settings win, like a closer file or the CLI flags
+    pub fn merge(self, other: Config) -> Self {
+        let mut alerts = self.alerts;
+        alerts.extend(other.alerts);
+        Config {
+            language: other.language.or(self.language),
+            toc: other.toc.or(self.toc),
+            modify_headers: other.modify_headers.or(self.modify_headers),
+            lang_map: other.lang_map.or(self.lang_map),
+            base_url: other.base_url.or(self.base_url),
+            root: other.root.or(self.root),
+            page_map: other.page_map.or(self.page_map),
+            title_heading: other.title_heading.or(self.title_heading),
+            front_matter_table: other.front_matter_table.or(self.front_matter_table),
+            alerts,
+        }
+    }
+
+    /// Writes the configuration as TOML, as it would be in a `.markrust.toml`
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("A configuration can be written as TOML")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let project = Config::from_toml(
+            "language = \"jira\"\n\
+            toc = true\n\
+            modify-headers = 1\n\
+            [alerts]\n\
+            caution = \"panel:title=Danger\"\n\
+            note = \"info\"\n",
+        )
+        .unwrap();
+        let cli = Config {
+            language: Some("confluence".to_string()),
+            alerts: BTreeMap::from([("note".to_string(), "tip".to_string())]),
+            ..Config::default()
+        };
+        let config = project.merge(cli);
+        assert_eq!(Some("confluence"), config.language.as_deref());
+        assert_eq!(Some(true), config.toc);
+        assert_eq!(Some(1), config.modify_headers);
+        assert_eq!(
+            Some("panel:title=Danger"),
+            config.alerts.get("caution").map(String::as_str)
+        );
+        assert_eq!(Some("tip"), config.alerts.get("note").map(String::as_str));
+        assert!(Config::from_toml("flavor = \"jira\"").is_err());
+        assert!(Config::from_toml("language = \"Jira\"").is_ok());
+        assert!(Config::from_toml("language = \"wiki\"").is_err());
+    }
+
+    #[test]
+    fn test_from_file() {
+        let path = std::env::temp_dir().join(format!("markrust-{}.toml", std::process::id()));
+        for toml in [
+            "language = \"wiki\"",
+            "toc = ",
+            "[alerts]\nbogus = \"info\"",
+        ] {
+            std::fs::write(&path, toml).unwrap();
+            let error = Config::from_file(&path).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, error.kind());
+            assert!(error.to_string().starts_with(&path.display().to_string()));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_discover() {
+        let root = std::env::temp_dir().join(format!("markrust-config-{}", std::process::id()));
+        let docs = root.join("project").join("docs");
+        let home = root.join("home");
+        std::fs::create_dir_all(&docs).unwrap();
+        std::fs::create_dir_all(home.join("markrust")).unwrap();
+        std::fs::write(
+            home.join("markrust").join("config.toml"),
+            "language = \"jira\"\ntoc = true\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("project").join(CONFIG_FILE),
+            "language = \"confluence\"\nlang-map = \"langs.toml\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            docs.join(CONFIG_FILE),
+            "modify-headers = -1\nroot = \"..\"\n",
+        )
+        .unwrap();
+
+        let (config, files) = Config::discover(&docs, Some(&home)).unwrap();
+        assert_eq!(3, files.len());
+        assert_eq!(
+            Config {
+                language: Some("confluence".to_string()),
+                toc: Some(true),
+                modify_headers: Some(-1),
+                lang_map: Some(root.join("project").join("langs.toml")),
+                root: Some(docs.join("..")),
+                ..Config::default()
+            },
+            config
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_to_toml() {
+        let config = Config {
+            toc: Some(true),
+            base_url: Some("https://example.com/".to_string()),
+            ..Config::default()
+        };
+        assert_eq!(
+            "toc = true\nbase-url = \"https://example.com/\"\n",
+            config.to_toml()
+        );
+        assert_eq!(config, Config::from_toml(&config.to_toml()).unwrap());
+        assert_eq!("", Config::default().to_toml());
+    }
+}
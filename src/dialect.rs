@@ -0,0 +1,165 @@
+use pulldown_cmark::BlockQuoteKind;
+
+/// Per-flavor differences in Atlassian wiki markup
+///
+/// The wiki writer only asks the dialect how to spell a construct, so adding another wiki markup
+/// flavor means implementing this trait and adding a `Flavor` variant, without touching the event
+/// loop. Storage format and ADF aren't wiki markup, so they have writers of their own.
+pub trait Dialect {
+    /// Opening code block macro, without the trailing newline
+    ///
+    /// # Arguments
+    ///
+    /// * `language` - the already-mapped code block language, if the block was fenced
+    fn code_block_start(&self, language: Option<&str>) -> String;
+
+    /// Prefix of a task list item, written instead of the usual bullets
+    ///
+    /// # Arguments
+    ///
+    /// * `bullets` - the bullets a normal item at this depth would have, like `*#`
+    /// * `checked` - if the task is done
+    /// * `simple` - if the item is in a top level list of only tasks, with nothing nested
+    fn task_item(&self, bullets: &str, checked: bool, simple: bool) -> String;
+
+    /// Table of contents macro, without trailing newlines
+    fn toc_macro(&self) -> &'static str;
+
+    /// If the page properties (`details`) and page properties report (`detailssummary`) macros
+    /// can be used
+    fn page_properties(&self) -> bool;
+
+    /// Markup that is significant at the start of a line, so must be escaped there in text
+    fn line_start_markers(&self) -> &'static [&'static str];
+
+    /// Opening macro for a GitHub alert (`> [!NOTE]`), without braces. The closing macro is the
+    /// part before any parameters
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - the kind of alert
+    fn alert_macro(&self, kind: BlockQuoteKind) -> &'static str;
+}
+
+/// Lists, headings, quotes, tables and rules, which both flavors understand
+const LINE_START_MARKERS: [&str; 12] = [
+    "*", "#", "-", "|", "h1.", "h2.", "h3.", "h4.", "h5.", "h6.", "bq.", "----",
+];
+
+/// Confluence also has task lines
+const CONFLUENCE_LINE_START_MARKERS: [&str; 14] = [
+    "*", "#", "-", "|", "h1.", "h2.", "h3.", "h4.", "h5.", "h6.", "bq.", "----", "[]", "[x]",
+];
+
+/// Jira wiki markup
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Jira;
+
+impl Dialect for Jira {
+    fn code_block_start(&self, language: Option<&str>) -> String {
+        match language {
+            Some(lang) => format!("{{code:{}}}", lang),
+            None => "{code}".to_string(),
+        }
+    }
+
+    fn task_item(&self, bullets: &str, checked: bool, _simple: bool) -> String {
+        // jira has no task lists, so use the tick and the neutral off emoticon, since the cross
+        // reads as a failure
+        format!("{} {} ", bullets, if checked { "(/)" } else { "(off)" })
+    }
+
+    fn toc_macro(&self) -> &'static str {
+        "{toc}"
+    }
+
+    fn page_properties(&self) -> bool {
+        // page properties are a confluence feature
+        false
+    }
+
+    fn line_start_markers(&self) -> &'static [&'static str] {
+        &LINE_START_MARKERS
+    }
+
+    fn alert_macro(&self, kind: BlockQuoteKind) -> &'static str {
+        // jira only has panels, so color them like github does
+        match kind {
+            BlockQuoteKind::Note => "panel:title=Note|borderColor=#0969da|titleBGColor=#ddf4ff",
+            BlockQuoteKind::Tip => "panel:title=Tip|borderColor=#1a7f37|titleBGColor=#dafbe1",
+            BlockQuoteKind::Important => {
+                "panel:title=Important|borderColor=#8250df|titleBGColor=#fbefff"
+            }
+            BlockQuoteKind::Warning => {
+                "panel:title=Warning|borderColor=#9a6700|titleBGColor=#fff8c5"
+            }
+            BlockQuoteKind::Caution => {
+                "panel:title=Caution|borderColor=#d1242f|titleBGColor=#ffebe9"
+            }
+        }
+    }
+}
+
+/// Confluence wiki markup
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Confluence;
+
+impl Dialect for Confluence {
+    fn code_block_start(&self, language: Option<&str>) -> String {
+        match language {
+            Some(lang) => format!("{{code:language={}}}", lang),
+            None => "{code}".to_string(),
+        }
+    }
+
+    fn task_item(&self, bullets: &str, checked: bool, simple: bool) -> String {
+        if !simple {
+            // task lines can't be nested or mixed with a list, so keep the list and use emoticons
+            return Jira.task_item(bullets, checked, simple);
+        }
+        if checked {
+            "[x] ".to_string()
+        } else {
+            "[] ".to_string()
+        }
+    }
+
+    fn toc_macro(&self) -> &'static str {
+        "{toc}"
+    }
+
+    fn page_properties(&self) -> bool {
+        true
+    }
+
+    fn line_start_markers(&self) -> &'static [&'static str] {
+        &CONFLUENCE_LINE_START_MARKERS
+    }
+
+    fn alert_macro(&self, kind: BlockQuoteKind) -> &'static str {
+        match kind {
+            BlockQuoteKind::Note => "info",
+            BlockQuoteKind::Tip => "tip",
+            BlockQuoteKind::Important => "note",
+            BlockQuoteKind::Warning | BlockQuoteKind::Caution => "warning",
+        }
+    }
+}
+
+/// Atlassian wiki markup flavor, since Jira and Confluence spell some macros differently
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Flavor {
+    Jira,
+    #[default]
+    Confluence,
+}
+
+impl Flavor {
+    /// The dialect that renders this flavor
+    pub fn dialect(self) -> &'static dyn Dialect {
+        match self {
+            Flavor::Jira => &Jira,
+            Flavor::Confluence => &Confluence,
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use crate::dialect::Dialect;
+
+/// Where text is being written, which decides which characters are significant
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Context {
+    /// Body text, like paragraphs, headings and list items
+    Text,
+    /// A table cell, where `|` starts the next cell
+    TableCell,
+    /// The text of a link, where `|` starts the url and `]` ends the link
+    LinkText,
+    /// A quoted macro or image parameter, like `title="..."`
+    Parameter,
+}
+
+/// Text effects, like `*bold*` and `-strikethrough-`, which only apply next to a word
+const EFFECT_MARKERS: [char; 6] = ['*', '_', '+', '^', '~', '-'];
+
+/// Emoticons that are rendered as images
+const EMOTICONS: [&str; 18] = [
+    "(/)", "(x)", "(!)", "(i)", "(?)", "(y)", "(n)", "(on)", "(off)", "(*)", "(+)", "(-)", ":)",
+    ":(", ":P", ":D", ";)", "(*r)",
+];
+
+/// Escapes text so it renders as itself in wiki markup, rather than as formatting
+///
+/// # Arguments
+///
+/// * `text` - the text to escape
+/// * `context` - where the text is being written
+/// * `prev` - the last character already written, or `None` at the start of the document
+/// * `line_start` - if the text starts at the beginning of a line
+/// * `dialect` - the flavor being written, which decides what is significant at a line start
+///
+/// # Returns
+///
+/// * `String` - text that renders as `text`
+pub(crate) fn escape_text(
+    text: &str,
+    context: Context,
+    prev: Option<char>,
+    line_start: bool,
+    dialect: &dyn Dialect,
+) -> String {
+    if context == Context::Parameter {
+        return escape_parameter(text);
+    }
+    let chars: Vec<char> = text.chars().collect();
+    let mut r = String::with_capacity(text.len());
+    let mut at_line_start = line_start;
+    for (i, &c) in chars.iter().enumerate() {
+        let before = if i == 0 { prev } else { Some(chars[i - 1]) };
+        // `None` at the end of the text, which could be followed by anything
+        let after = chars.get(i + 1).copied();
+        let rest = &text[text.char_indices().nth(i).map_or(text.len(), |(j, _)| j)..];
+
+        if at_line_start && !c.is_whitespace() {
+            at_line_start = false;
+            if dialect
+                .line_start_markers()
+                .iter()
+                .any(|marker| rest.starts_with(marker))
+            {
+                if c.is_alphanumeric() {
+                    // a backslash before a letter isn't an escape, so use an entity
+                    r.push_str(&format!("&#{};", c as u32));
+                } else {
+                    r.push('\\');
+                    r.push(c);
+                }
+                continue;
+            }
+        }
+        if c == '\n' {
+            at_line_start = true;
+        }
+
+        let escaped = match c {
+            // macros and links
+            '{' | '}' | '[' => true,
+            ']' | '|' => context != Context::Text,
+            // `\\` is a line break, and `\` escapes whatever follows
+            '\\' => {
+                r.push_str("&#92;");
+                continue;
+            }
+            // `!url!` is an image, and the url can't start with a space
+            '!' => after.is_some_and(|c| !c.is_whitespace()),
+            // `??citation??`
+            '?' => after == Some('?'),
+            // `&amp;` is an entity, but a lone `&` is fine
+            '&' => rest[1..].split_once(';').is_some_and(|(entity, _)| {
+                !entity.is_empty() && entity.chars().all(|c| c == '#' || c.is_alphanumeric())
+            }),
+            '(' | ':' | ';' => EMOTICONS.iter().any(|emoticon| rest.starts_with(emoticon)),
+            c if EFFECT_MARKERS.contains(&c) => {
+                let is_word = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+                let is_space = |c: Option<char>| c.is_none_or(char::is_whitespace);
+                // effects can't start or end inside a word, or stand alone between spaces
+                let inside_word = is_word(before) && is_word(after);
+                let standalone = is_space(before) && after.is_some_and(char::is_whitespace);
+                !inside_word && !standalone
+            }
+            _ => false,
+        };
+        if escaped {
+            if c == '&' {
+                r.push_str("&amp;");
+                continue;
+            }
+            r.push('\\');
+        }
+        r.push(c);
+    }
+    r
+}
+
+/// Escapes a quoted parameter, where backslashes aren't understood, using entities
+///
+/// # Arguments
+///
+/// * `text` - the parameter value
+///
+/// # Returns
+///
+/// * `String` - the value with quotes, braces, pipes and `!` replaced by entities
+fn escape_parameter(text: &str) -> String {
+    escape_entities(text, &['"', '{', '}', '|', '!', '=', ','])
+}
+
+/// Escapes the value of a macro parameter, like `cql=...` in `{detailssummary:cql=...}`. Only the
+/// pipe and closing brace that would end the parameter or the macro are replaced, since macros
+/// like `detailssummary` read commas as list separators and `=` as part of a CQL query
+///
+/// # Arguments
+///
+/// * `text` - the parameter value
+///
+/// # Returns
+///
+/// * `String` - the value with pipes and closing braces replaced by entities
+pub(crate) fn escape_macro_parameter(text: &str) -> String {
+    escape_entities(text, &['}', '|'])
+}
+
+/// Replaces characters with entities, and `&` as well so existing entities render as written
+///
+/// # Arguments
+///
+/// * `text` - the text to escape
+/// * `special` - the characters to replace
+///
+/// # Returns
+///
+/// * `String` - the text with `&` and the special characters replaced by entities
+fn escape_entities(text: &str, special: &[char]) -> String {
+    let mut r = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => r.push_str("&amp;"),
+            '"' if special.contains(&c) => r.push_str("&quot;"),
+            _ if special.contains(&c) => r.push_str(&format!("&#{};", c as u32)),
+            _ => r.push(c),
+        }
+    }
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::atlassian::write_with_options;
+    use crate::dialect::{Confluence, Flavor, Jira};
+    use crate::options::RenderOptions;
+    use crate::wiki::parse;
+    use pulldown_cmark::{CowStr, Event, Tag, TagEnd};
+
+    /// Escapes body text at the start of a line
+    fn escape(text: &str) -> String {
+        escape_text(text, Context::Text, None, true, &Jira)
+    }
+
+    #[test]
+    fn test_plain_text_untouched() {
+        assert_eq!(
+            "a plain sentence, with punctuation.",
+            escape("a plain sentence, with punctuation.")
+        );
+        assert_eq!(
+            "well-known snake_case 2+2 a - b",
+            escape("well-known snake_case 2+2 a - b")
+        );
+        assert_eq!("Hello! & goodbye?", escape("Hello! & goodbye?"));
+    }
+
+    #[test]
+    fn test_effects() {
+        assert_eq!(
+            "\\*not bold\\* and \\_not em\\_",
+            escape("*not bold* and _not em_")
+        );
+        assert_eq!("\\-\\-dash", escape("--dash"));
+        assert_eq!("\\??citation\\??", escape("??citation??"));
+    }
+
+    #[test]
+    fn test_macros_links_and_images() {
+        assert_eq!(
+            "\\{code\\} \\[link|x] \\!image.png!",
+            escape("{code} [link|x] !image.png!")
+        );
+        assert_eq!("C:&#92;path", escape("C:\\path"));
+        assert_eq!("&amp;copy; \\(x) \\:)", escape("&copy; (x) :)"));
+    }
+
+    #[test]
+    fn test_line_start() {
+        assert_eq!("\\* not a list", escape("* not a list"));
+        assert_eq!("\\# not a list", escape("# not a list"));
+        assert_eq!("&#104;1. not a heading", escape("h1. not a heading"));
+        assert_eq!("&#98;q. not a quote", escape("bq. not a quote"));
+        // only the start of the line matters
+        assert_eq!(
+            "a # b",
+            escape_text("a # b", Context::Text, Some(' '), false, &Jira)
+        );
+        // confluence task lines
+        assert_eq!(
+            "\\[x] done",
+            escape_text("[x] done", Context::Text, None, true, &Confluence)
+        );
+    }
+
+    #[test]
+    fn test_contexts() {
+        assert_eq!(
+            "a | b",
+            escape_text("a | b", Context::Text, None, false, &Jira)
+        );
+        assert_eq!(
+            "a \\| b",
+            escape_text("a | b", Context::TableCell, None, false, &Jira)
+        );
+        assert_eq!(
+            "a\\]",
+            escape_text("a]", Context::LinkText, None, false, &Jira)
+        );
+        assert_eq!(
+            "say &quot;hi&#33;&quot;",
+            escape_text("say \"hi!\"", Context::Parameter, None, false, &Jira)
+        );
+    }
+
+    /// Writes `text` as a wiki paragraph, then parses it back to plain text
+    fn round_trip(text: &str, flavor: Flavor) -> String {
+        let events = vec![
+            Event::Start(Tag::Paragraph),
+            Event::Text(CowStr::from(text.to_string())),
+            Event::End(TagEnd::Paragraph),
+        ];
+        let mut output = Vec::new();
+        let options = RenderOptions::new().flavor(flavor);
+        assert!(write_with_options(&mut output, events.into_iter(), &options).is_ok());
+        let wiki = String::from_utf8(output).unwrap();
+        let mut parsed = String::new();
+        for event in parse(&wiki, &RenderOptions::new().language_map()) {
+            match event {
+                Event::Text(text) => parsed.push_str(&text),
+                Event::Start(Tag::Paragraph) | Event::End(TagEnd::Paragraph) => (),
+                event => panic!("{:?} from {:?} via {:?}", event, text, wiki),
+            }
+        }
+        parsed
+    }
+
+    #[test]
+    fn test_text_survives_round_trip() {
+        // every special character, in every position, next to words, spaces and each other
+        const ALPHABET: &[char] = &[
+            'a', 'b', '1', ' ', ' ', '*', '_', '+', '^', '~', '-', '?', '!', '[', ']', '{', '}',
+            '|', '\\', '&', ';', ':', '(', ')', '#', '.', 'h', 'q', 'x', '/', '<', '"',
+        ];
+        let mut state: u64 = 0x2545f4914f6cdd1d;
+        let mut next = || {
+            // xorshift, so the cases are the same on every run
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..2000 {
+            let len = 1 + (next() % 12) as usize;
+            let text: String = (0..len)
+                .map(|_| ALPHABET[(next() % ALPHABET.len() as u64) as usize])
+                .collect();
+            // leading and trailing whitespace isn't kept by either format
+            let text = text.trim().to_string();
+            if text.is_empty() {
+                continue;
+            }
+            for flavor in [Flavor::Jira, Flavor::Confluence] {
+                assert_eq!(text, round_trip(&text, flavor), "{:?}", flavor);
+            }
+        }
+    }
+}
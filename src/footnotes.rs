@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+
+/// Makes a footnote label safe to use as an anchor name
+///
+/// # Arguments
+///
+/// * `label` - the footnote label, like `1` in `[^1]`
+///
+/// # Returns
+///
+/// * `String` - the label with anything but letters, digits, `-` and `_` replaced by `-`
+pub(crate) fn anchor(label: &str) -> String {
+    label
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// A footnote definition, as it is written in the Notes section at the end of a document
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Note<T> {
+    // the anchor name, without the `fn-` or `fnref-` prefix
+    pub(crate) anchor: String,
+    // the rendered definition, which is empty if the footnote was never defined
+    pub(crate) content: T,
+    // if the footnote is referenced, so the note can link back to it
+    pub(crate) referenced: bool,
+}
+
+/// Footnote references and definitions, which every writer moves to a Notes section at the end
+/// of the document since none of the Atlassian formats has footnotes of its own
+#[derive(Clone, Debug)]
+pub(crate) struct Footnotes<T> {
+    // footnote labels, numbered from 1 in the order they're first referenced
+    numbers: HashMap<String, usize>,
+    // rendered footnote definitions, by label, in the order they're defined
+    definitions: Vec<(String, T)>,
+}
+
+impl<T> Default for Footnotes<T> {
+    fn default() -> Self {
+        Footnotes {
+            numbers: HashMap::new(),
+            definitions: vec![],
+        }
+    }
+}
+
+impl<T: Default> Footnotes<T> {
+    /// Numbers a footnote reference
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - the footnote label
+    ///
+    /// # Returns
+    ///
+    /// * `(usize, bool)` - the footnote's number, and if this is its first reference, which is
+    ///   the one the note links back to
+    pub(crate) fn reference(&mut self, label: &str) -> (usize, bool) {
+        if let Some(number) = self.numbers.get(label) {
+            return (*number, false);
+        }
+        let number = self.numbers.len() + 1;
+        self.numbers.insert(label.to_string(), number);
+        (number, true)
+    }
+
+    /// Stores a rendered footnote definition
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - the footnote label
+    /// * `content` - the rendered definition
+    pub(crate) fn define(&mut self, label: String, content: T) {
+        self.definitions.push((label, content));
+    }
+
+    /// Takes the notes to write at the end of the document, in the order they were referenced.
+    /// Definitions that were never referenced go last.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Note<T>>` - the notes, which are empty if there were no footnotes
+    pub(crate) fn take_notes(&mut self) -> Vec<Note<T>> {
+        let Footnotes {
+            numbers,
+            mut definitions,
+        } = std::mem::take(self);
+        let mut referenced: Vec<(usize, String)> = numbers
+            .into_iter()
+            .map(|(label, number)| (number, label))
+            .collect();
+        referenced.sort();
+        let mut notes = vec![];
+        for (_, label) in referenced {
+            let content = match definitions
+                .iter()
+                .position(|(defined, _)| *defined == label)
+            {
+                Some(index) => definitions.remove(index).1,
+                None => T::default(),
+            };
+            notes.push(Note {
+                anchor: anchor(&label),
+                content,
+                referenced: true,
+            });
+        }
+        for (label, content) in definitions {
+            notes.push(Note {
+                anchor: anchor(&label),
+                content,
+                referenced: false,
+            });
+        }
+        notes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_notes() {
+        let mut footnotes: Footnotes<String> = Footnotes::default();
+        assert_eq!((1, true), footnotes.reference("b"));
+        assert_eq!((2, true), footnotes.reference("a 1"));
+        assert_eq!((1, false), footnotes.reference("b"));
+        footnotes.define("a 1".to_string(), "first".to_string());
+        footnotes.define("unused".to_string(), "last".to_string());
+        let note = |anchor: &str, content: &str, referenced| Note {
+            anchor: anchor.to_string(),
+            content: content.to_string(),
+            referenced,
+        };
+        assert_eq!(
+            vec![
+                note("b", "", true),
+                note("a-1", "first", true),
+                note("unused", "last", false)
+            ],
+            footnotes.take_notes()
+        );
+        assert!(footnotes.take_notes().is_empty());
+    }
+}
@@ -0,0 +1,409 @@
+use pulldown_cmark::*;
+use std::io;
+
+use crate::options::RenderOptions;
+use crate::properties::Directive;
+
+/// Turns a YAML value into text
+///
+/// # Arguments
+///
+/// * `value` - the value
+///
+/// # Returns
+///
+/// * `Vec<String>` - one item for a scalar, one for each scalar in a list, and none for anything
+///   else, like a nested mapping
+fn yaml_items(value: &serde_yaml::Value) -> Vec<String> {
+    match value {
+        serde_yaml::Value::String(s) => vec![s.clone()],
+        serde_yaml::Value::Number(n) => vec![n.to_string()],
+        serde_yaml::Value::Bool(b) => vec![b.to_string()],
+        serde_yaml::Value::Sequence(items) => items
+            .iter()
+            .filter(|item| !item.is_sequence())
+            .flat_map(yaml_items)
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Turns a TOML value into text
+///
+/// # Arguments
+///
+/// * `value` - the value
+///
+/// # Returns
+///
+/// * `Vec<String>` - one item for a scalar, one for each scalar in an array, and none for
+///   anything else, like a table
+fn toml_items(value: &toml::Value) -> Vec<String> {
+    match value {
+        toml::Value::String(s) => vec![s.clone()],
+        toml::Value::Array(items) => items
+            .iter()
+            .filter(|item| !item.is_array())
+            .flat_map(toml_items)
+            .collect(),
+        toml::Value::Table(_) => vec![],
+        // integers, floats, booleans and dates are written as they are in TOML
+        scalar => vec![scalar.to_string()],
+    }
+}
+
+/// Metadata from the YAML (`---`) or TOML (`+++`) front matter at the start of a document, as
+/// Jekyll and Hugo use it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrontMatter {
+    /// The page title, from `title`
+    pub title: Option<String>,
+    /// Confluence labels, from `labels` or `tags`
+    pub labels: Vec<String>,
+    /// If a table of contents should be prepended, from `toc`
+    pub toc: Option<bool>,
+    /// If the values should be written as a page properties table, from `details`, which is
+    /// `true` or the id of the page properties macro
+    pub details: bool,
+    /// The id of the page properties macro, so a report can pick this table out
+    pub details_id: Option<String>,
+    /// Every key whose value can be written as text, in order, with lists joined by commas
+    pub values: Vec<(String, String)>,
+}
+
+impl FrontMatter {
+    /// Reads front matter
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - YAML for `---` front matter, or TOML for `+++`
+    /// * `text` - the front matter, without its fences
+    ///
+    /// # Returns
+    ///
+    /// * `Result<FrontMatter>` - the metadata, or why it couldn't be read
+    pub fn parse(kind: MetadataBlockKind, text: &str) -> io::Result<Self> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        let entries: Vec<(String, Vec<String>)> = match kind {
+            MetadataBlockKind::YamlStyle => {
+                let mapping: serde_yaml::Mapping = match serde_yaml::from_str(text) {
+                    Ok(mapping) => mapping,
+                    // front matter with only comments in it
+                    Err(_) if text.lines().all(|line| line.trim().starts_with('#')) => {
+                        serde_yaml::Mapping::new()
+                    }
+                    Err(e) => return Err(invalid(e.to_string())),
+                };
+                mapping
+                    .iter()
+                    .filter_map(|(key, value)| Some((key.as_str()?.to_string(), yaml_items(value))))
+                    .collect()
+            }
+            MetadataBlockKind::PlusesStyle => {
+                let table: toml::Table =
+                    toml::from_str(text).map_err(|e| invalid(e.to_string()))?;
+                table
+                    .iter()
+                    .map(|(key, value)| (key.clone(), toml_items(value)))
+                    .collect()
+            }
+        };
+
+        let mut front_matter = FrontMatter::default();
+        for (key, items) in entries {
+            if items.is_empty() {
+                continue;
+            }
+            match key.as_str() {
+                "title" => front_matter.title = Some(items.join(", ")),
+                "toc" => front_matter.toc = items[0].parse().ok(),
+                "details" => match items[0].as_str() {
+                    "true" => front_matter.details = true,
+                    "false" => (),
+                    id => {
+                        front_matter.details = true;
+                        front_matter.details_id = Some(id.to_string());
+                    }
+                },
+                // a single string of labels is separated like Hugo's tags
+                "labels" | "tags" => front_matter.labels.extend(
+                    items
+                        .iter()
+                        .flat_map(|item| item.split([',', ' ']))
+                        .filter(|label| !label.is_empty())
+                        .map(str::to_string),
+                ),
+                _ => (),
+            }
+            front_matter.values.push((key, items.join(", ")));
+        }
+        Ok(front_matter)
+    }
+
+    /// Reads the front matter of a markdown document. Front matter that can't be read is
+    /// reported, and the document is converted as if it had none
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - the markdown
+    ///
+    /// # Returns
+    ///
+    /// * `Option<FrontMatter>` - the metadata, if the document starts with any
+    pub fn from_markdown(input: &str) -> Option<Self> {
+        let mut parser = Parser::new_ext(input, Options::all());
+        let Some(Event::Start(Tag::MetadataBlock(kind))) = parser.next() else {
+            return None;
+        };
+        let mut text = String::new();
+        for event in parser {
+            match event {
+                Event::Text(t) => text.push_str(&t),
+                _ => break,
+            }
+        }
+        match FrontMatter::parse(kind, &text) {
+            Ok(front_matter) => Some(front_matter),
+            Err(e) => {
+                log::warn!("front matter can't be read, so it is left out: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Sets the options the front matter controls: the title is the page title, unless one has
+    /// been set, and `toc` turns the table of contents on or off
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - the options to render with
+    pub fn apply(&self, mut options: RenderOptions) -> RenderOptions {
+        if let (None, Some(title)) = (&options.page_title, &self.title) {
+            options.page_title = Some(title.clone());
+        }
+        if let Some(toc) = self.toc {
+            options.toc = toc;
+        }
+        options
+    }
+
+    /// Replaces `{{ page.KEY }}` in text with the value of KEY, like Jekyll does
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - the text
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - the text with values in it, or `None` if it doesn't use any
+    pub(crate) fn substitute(&self, text: &str) -> Option<String> {
+        if !text.contains("{{") {
+            return None;
+        }
+        let mut result = String::with_capacity(text.len());
+        let mut rest = text;
+        let mut changed = false;
+        while let Some(start) = rest.find("{{") {
+            let value = rest[start + 2..].find("}}").and_then(|end| {
+                let key = rest[start + 2..start + 2 + end]
+                    .trim()
+                    .strip_prefix("page.")?;
+                let (_, value) = self.values.iter().find(|(k, _)| k == key)?;
+                Some((value, start + 2 + end + 2))
+            });
+            match value {
+                Some((value, end)) => {
+                    result.push_str(&rest[..start]);
+                    result.push_str(value);
+                    rest = &rest[end..];
+                    changed = true;
+                }
+                None => {
+                    result.push_str(&rest[..start + 2]);
+                    rest = &rest[start + 2..];
+                }
+            }
+        }
+        result.push_str(rest);
+        changed.then_some(result)
+    }
+
+    /// Makes the content the front matter adds to the start of the document: the title as an
+    /// `h1`, and the values as a page properties table, if the options or the front matter ask
+    /// for them
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - the options to render with
+    fn events(&self, options: &RenderOptions) -> Vec<Event<'static>> {
+        let mut events = vec![];
+        if let (true, Some(title)) = (options.title_heading, &self.title) {
+            let heading = Tag::Heading {
+                level: HeadingLevel::H1,
+                id: None,
+                classes: vec![],
+                attrs: vec![],
+            };
+            events.push(Event::Start(heading));
+            events.push(Event::Text(CowStr::from(title.clone())));
+            events.push(Event::End(TagEnd::Heading(HeadingLevel::H1)));
+        }
+        // `toc` and `details` are for markrust, not the reader
+        let properties: Vec<&(String, String)> = self
+            .values
+            .iter()
+            .filter(|(key, _)| key != "toc" && key != "details")
+            .collect();
+        if (options.front_matter_table || self.details) && !properties.is_empty() {
+            let params = self
+                .details_id
+                .iter()
+                .map(|id| ("id".to_string(), id.clone()))
+                .collect();
+            events.push(Event::Start(Tag::HtmlBlock));
+            events.push(Event::Html(CowStr::from(
+                Directive::Details(params).to_html(),
+            )));
+            events.push(Event::End(TagEnd::HtmlBlock));
+            // the keys are the headings, so each value is found by its key, like in Confluence's
+            // page properties
+            let cell = |text: &str| {
+                [
+                    Event::Start(Tag::TableCell),
+                    Event::Text(CowStr::from(text.to_string())),
+                    Event::End(TagEnd::TableCell),
+                ]
+            };
+            events.push(Event::Start(Tag::Table(vec![
+                Alignment::None;
+                properties.len()
+            ])));
+            events.push(Event::Start(Tag::TableHead));
+            events.extend(properties.iter().flat_map(|(key, _)| cell(key)));
+            events.push(Event::End(TagEnd::TableHead));
+            events.push(Event::Start(Tag::TableRow));
+            events.extend(properties.iter().flat_map(|(_, value)| cell(value)));
+            events.push(Event::End(TagEnd::TableRow));
+            events.push(Event::End(TagEnd::Table));
+        }
+        events
+    }
+
+    /// Adds the front matter to a markdown document's events: its content goes after the
+    /// front matter, and its values replace `{{ page.KEY }}` in text outside of code
+    ///
+    /// # Arguments
+    ///
+    /// * `iter` - the document's events
+    /// * `options` - the options to render with
+    pub(crate) fn render<'a>(
+        &self,
+        iter: impl Iterator<Item = Event<'a>>,
+        options: &RenderOptions,
+    ) -> Vec<Event<'a>> {
+        let mut events = vec![];
+        let mut in_code_block = false;
+        for event in iter {
+            match event {
+                Event::End(TagEnd::MetadataBlock(_)) => {
+                    events.push(event);
+                    events.extend(self.events(options));
+                }
+                Event::Start(Tag::CodeBlock(_)) => {
+                    in_code_block = true;
+                    events.push(event);
+                }
+                Event::End(TagEnd::CodeBlock) => {
+                    in_code_block = false;
+                    events.push(event);
+                }
+                Event::Text(text) if !in_code_block => match self.substitute(&text) {
+                    Some(substituted) => events.push(Event::Text(CowStr::from(substituted))),
+                    None => events.push(Event::Text(text)),
+                },
+                event => events.push(event),
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_yaml() {
+        let front_matter = FrontMatter::parse(
+            MetadataBlockKind::YamlStyle,
+            "title: Decision 12\nstatus: accepted\ntags: [adr, backend]\ntoc: true\n\
+            owner:\n  name: someone\n",
+        )
+        .unwrap();
+        assert_eq!(Some("Decision 12"), front_matter.title.as_deref());
+        assert_eq!(vec!["adr", "backend"], front_matter.labels);
+        assert_eq!(Some(true), front_matter.toc);
+        // nested mappings can't be written as text
+        assert_eq!(
+            vec![
+                ("title".to_string(), "Decision 12".to_string()),
+                ("status".to_string(), "accepted".to_string()),
+                ("tags".to_string(), "adr, backend".to_string()),
+                ("toc".to_string(), "true".to_string()),
+            ],
+            front_matter.values
+        );
+        assert!(FrontMatter::parse(MetadataBlockKind::YamlStyle, "title: [").is_err());
+    }
+
+    #[test]
+    fn test_parse_toml() {
+        let front_matter = FrontMatter::parse(
+            MetadataBlockKind::PlusesStyle,
+            "title = \"Runbook\"\nlabels = \"ops, on-call\"\nweight = 3\n",
+        )
+        .unwrap();
+        assert_eq!(Some("Runbook"), front_matter.title.as_deref());
+        assert_eq!(vec!["ops", "on-call"], front_matter.labels);
+        assert_eq!(
+            ("weight".to_string(), "3".to_string()),
+            front_matter.values[2]
+        );
+        assert!(!front_matter.details);
+
+        let front_matter = FrontMatter::parse(
+            MetadataBlockKind::YamlStyle,
+            "status: accepted\ndetails: adr\n",
+        )
+        .unwrap();
+        assert!(front_matter.details);
+        assert_eq!(Some("adr"), front_matter.details_id.as_deref());
+    }
+
+    #[test]
+    fn test_from_markdown() {
+        assert_eq!(
+            Some("Hello"),
+            FrontMatter::from_markdown("---\ntitle: Hello\n---\n\n# Body")
+                .unwrap()
+                .title
+                .as_deref()
+        );
+        assert_eq!(None, FrontMatter::from_markdown("# Body\n\n---\n"));
+        assert_eq!(None, FrontMatter::from_markdown("---\n: [\n---\n"));
+    }
+
+    #[test]
+    fn test_substitute() {
+        let front_matter = FrontMatter {
+            values: vec![("version".to_string(), "1.2".to_string())],
+            ..FrontMatter::default()
+        };
+        assert_eq!(
+            Some("install 1.2, not {{ page.other }} or {{version}}".to_string()),
+            front_matter
+                .substitute("install {{ page.version }}, not {{ page.other }} or {{version}}")
+        );
+        assert_eq!(None, front_matter.substitute("{{ nothing }}"));
+    }
+}
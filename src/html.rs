@@ -0,0 +1,652 @@
+use pulldown_cmark::{Event, Tag, TagEnd};
+use scraper::{Html, Node};
+
+/// How an HTML element is written as wiki markup
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Markup {
+    /// Markers around the content, like `^` for `<sup>`
+    Wrap(&'static str, &'static str),
+    /// A line break, `\\` in a table cell
+    Break,
+    /// A horizontal rule
+    Rule,
+    /// A link, if it has an `href`
+    Link,
+    /// An image, with its size and alignment
+    Image,
+    /// A code block
+    Preformatted,
+    /// A paragraph
+    Paragraph,
+    /// A table
+    Table,
+    /// `<thead>`, `<tbody>` and `<tfoot>`, which only hold rows
+    TableSection,
+    /// A table row
+    TableRow,
+    /// A table cell, which is a heading for `<th>`
+    TableCell { header: bool },
+    /// An expand macro
+    Details,
+    /// The title of an expand macro
+    Summary,
+    /// Only the content is written, for elements that just group it
+    Content,
+}
+
+impl Markup {
+    /// If the element is a block, which can't be written inside a paragraph
+    pub(crate) fn is_block(self) -> bool {
+        matches!(
+            self,
+            Markup::Rule
+                | Markup::Preformatted
+                | Markup::Paragraph
+                | Markup::Table
+                | Markup::TableSection
+                | Markup::TableRow
+                | Markup::TableCell { .. }
+                | Markup::Details
+        )
+    }
+}
+
+/// HTML elements that are understood, and how each one is written. Other elements are reported,
+/// and their content is written as text
+const ELEMENTS: [(&str, Markup); 36] = [
+    ("b", Markup::Wrap("*", "*")),
+    ("strong", Markup::Wrap("*", "*")),
+    ("i", Markup::Wrap("_", "_")),
+    ("em", Markup::Wrap("_", "_")),
+    ("cite", Markup::Wrap("??", "??")),
+    ("ins", Markup::Wrap("+", "+")),
+    ("u", Markup::Wrap("+", "+")),
+    ("del", Markup::Wrap("-", "-")),
+    ("s", Markup::Wrap("-", "-")),
+    ("strike", Markup::Wrap("-", "-")),
+    ("sup", Markup::Wrap("^", "^")),
+    ("sub", Markup::Wrap("~", "~")),
+    ("code", Markup::Wrap("{{", "}}")),
+    ("kbd", Markup::Wrap("{{", "}}")),
+    ("samp", Markup::Wrap("{{", "}}")),
+    ("tt", Markup::Wrap("{{", "}}")),
+    // wiki markup has no highlighting, so marked text is made bold
+    ("mark", Markup::Wrap("*", "*")),
+    ("br", Markup::Break),
+    ("hr", Markup::Rule),
+    ("a", Markup::Link),
+    ("img", Markup::Image),
+    ("pre", Markup::Preformatted),
+    ("p", Markup::Paragraph),
+    ("div", Markup::Paragraph),
+    ("center", Markup::Paragraph),
+    ("table", Markup::Table),
+    ("thead", Markup::TableSection),
+    ("tbody", Markup::TableSection),
+    ("tfoot", Markup::TableSection),
+    ("tr", Markup::TableRow),
+    ("th", Markup::TableCell { header: true }),
+    ("td", Markup::TableCell { header: false }),
+    ("details", Markup::Details),
+    ("summary", Markup::Summary),
+    ("span", Markup::Content),
+    // the parser puts every fragment in an `<html>` element
+    ("html", Markup::Content),
+];
+
+/// Looks up how an HTML element is written
+///
+/// # Arguments
+///
+/// * `name` - the element name, in any case
+///
+/// # Returns
+///
+/// * `Option<Markup>` - how to write it, or `None` if the element isn't understood
+pub(crate) fn markup(name: &str) -> Option<Markup> {
+    let name = name.to_ascii_lowercase();
+    ELEMENTS
+        .iter()
+        .find(|(element, _)| *element == name)
+        .map(|(_, markup)| *markup)
+}
+
+/// Reads a single inline HTML tag, like `<kbd>`, `</kbd>` or `<br/>`
+///
+/// # Arguments
+///
+/// * `html` - the tag
+///
+/// # Returns
+///
+/// * `Option<(String, bool)>` - the lowercase element name, and if it's a closing tag. Comments
+///   and declarations aren't tags
+pub(crate) fn parse_tag(html: &str) -> Option<(String, bool)> {
+    let rest = html.trim().strip_prefix('<')?;
+    let (rest, closing) = match rest.strip_prefix('/') {
+        Some(rest) => (rest, true),
+        None => (rest, false),
+    };
+    let name: String = rest
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '-')
+        .collect();
+    if name.is_empty() || !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    Some((name.to_ascii_lowercase(), closing))
+}
+
+/// Reads an attribute from a single opening tag, like the `href` of `<a href="...">`
+///
+/// # Arguments
+///
+/// * `html` - the tag
+/// * `name` - the attribute name
+pub(crate) fn attribute(html: &str, name: &str) -> Option<String> {
+    let fragment = Html::parse_fragment(html);
+    let value = fragment
+        .tree
+        .root()
+        .descendants()
+        .find_map(|node| match node.value() {
+            Node::Element(elem) if elem.name() != "html" => Some(elem.attr(name)),
+            _ => None,
+        })??;
+    Some(value.to_string())
+}
+
+/// Elements that never have content or a closing tag
+const VOID_ELEMENTS: [&str; 14] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+/// Elements that are closed without a closing tag, by a sibling or the end of their parent
+const OPTIONAL_END_ELEMENTS: [&str; 13] = [
+    "p", "li", "dt", "dd", "tr", "td", "th", "thead", "tbody", "tfoot", "option", "rp", "rt",
+];
+
+/// Elements whose content is text, so tags in it aren't tags
+const RAW_TEXT_ELEMENTS: [&str; 4] = ["script", "style", "textarea", "title"];
+
+/// Finds the `>` that ends a tag, skipping any in quoted attribute values
+///
+/// # Arguments
+///
+/// * `tag` - the tag, or the part of it after what has already been scanned
+/// * `quote` - the quote of the attribute value being scanned, which is kept for the next part
+///   of the tag when it isn't finished
+///
+/// # Returns
+///
+/// * `Option<usize>` - the position of the `>`, or `None` if the tag isn't finished yet
+fn tag_end(tag: &str, quote: &mut Option<char>) -> Option<usize> {
+    for (i, c) in tag.char_indices() {
+        match (*quote, c) {
+            (None, '"' | '\'') => *quote = Some(c),
+            (Some(q), _) if c == q => *quote = None,
+            (None, '>') => return Some(i),
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Block HTML, which markdown gives a line at a time, collected until its tags are balanced so
+/// it can be parsed as a whole. Each line is only scanned once, so a large table or a tag that
+/// is never closed doesn't cost more than the HTML itself
+#[derive(Clone, Debug, Default)]
+pub(crate) struct HtmlBuffer {
+    // the HTML collected so far
+    html: String,
+    // how much of `html` has been scanned for tags
+    scanned: usize,
+    // where a tag or comment that isn't finished yet starts. Scanning it goes on from `scanned`
+    // once more HTML arrives
+    tag_start: Option<usize>,
+    // the quote of the attribute value an unfinished tag ends in
+    quote: Option<char>,
+    // elements that have been opened and not closed, outermost first
+    open: Vec<String>,
+}
+
+impl HtmlBuffer {
+    /// Adds HTML to the buffer, and keeps track of the elements it opens and closes
+    ///
+    /// # Arguments
+    ///
+    /// * `html` - the next piece of HTML
+    pub(crate) fn push(&mut self, html: &str) {
+        self.html.push_str(html);
+        while self.scanned < self.html.len() {
+            if let Some(start) = self.tag_start {
+                if !self.scan_tag(start) {
+                    return;
+                }
+                continue;
+            }
+            let rest = &self.html[self.scanned..];
+            // only the closing tag ends a script or style
+            if let Some(raw) = self
+                .open
+                .last()
+                .filter(|n| RAW_TEXT_ELEMENTS.contains(&&***n))
+            {
+                let close = format!("</{}", raw);
+                match rest.to_ascii_lowercase().find(&close) {
+                    Some(i) => self.scanned += i,
+                    None => {
+                        // the closing tag may be split across pieces
+                        let mut keep = self.html.len().saturating_sub(close.len());
+                        while !self.html.is_char_boundary(keep) {
+                            keep -= 1;
+                        }
+                        self.scanned = keep.max(self.scanned);
+                        return;
+                    }
+                }
+            }
+            let rest = &self.html[self.scanned..];
+            let Some(start) = rest.find('<') else {
+                self.scanned = self.html.len();
+                return;
+            };
+            let tag = &rest[start..];
+            if tag.len() < "<!--".len() && "<!--".starts_with(tag) {
+                // a `<` at the end could still become a tag or a comment
+                self.scanned += start;
+                return;
+            }
+            if tag[1..].starts_with(|c: char| c.is_ascii_alphabetic() || "!?/".contains(c)) {
+                self.scanned += start;
+                self.tag_start = Some(self.scanned);
+                self.quote = None;
+            } else {
+                // `a < b` is text
+                self.scanned += start + 1;
+            }
+        }
+    }
+
+    /// Scans the rest of a tag or comment, and keeps track of the element it opens or closes
+    /// once it is finished. Only what hasn't been scanned yet is, so a long comment or tag isn't
+    /// scanned again for every line of it
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - where the tag starts
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - if the tag is finished
+    fn scan_tag(&mut self, start: usize) -> bool {
+        let end = if self.html[start..].starts_with("<!--") {
+            // the `-->` may be split across pieces
+            let from = self.scanned.max(start + 2);
+            match self.html[from..].find("-->") {
+                Some(i) => from + i + 2,
+                None => {
+                    let mut keep = self.html.len() - 2;
+                    while !self.html.is_char_boundary(keep) {
+                        keep -= 1;
+                    }
+                    self.scanned = keep.max(self.scanned);
+                    return false;
+                }
+            }
+        } else {
+            match tag_end(&self.html[self.scanned..], &mut self.quote) {
+                Some(i) => self.scanned + i,
+                None => {
+                    self.scanned = self.html.len();
+                    return false;
+                }
+            }
+        };
+        let tag = &self.html[start..=end];
+        if let Some((name, closing)) = parse_tag(tag) {
+            let self_closing = tag[..tag.len() - 1].ends_with('/');
+            if closing {
+                self.close(&name);
+            } else if !self_closing && !VOID_ELEMENTS.contains(&name.as_str()) {
+                self.open(name);
+            }
+        }
+        self.tag_start = None;
+        self.scanned = end + 1;
+        true
+    }
+
+    /// Opens an element, closing the siblings it ends, like an `<li>` after an `<li>`
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - the lowercase element name
+    fn open(&mut self, name: String) {
+        if OPTIONAL_END_ELEMENTS.contains(&name.as_str()) {
+            let implied = self
+                .open
+                .iter()
+                .rev()
+                .take_while(|open| OPTIONAL_END_ELEMENTS.contains(&open.as_str()))
+                .position(|open| *open == name);
+            if let Some(i) = implied {
+                self.open.truncate(self.open.len() - i - 1);
+            }
+        }
+        self.open.push(name);
+    }
+
+    /// Closes an element, and any elements still open inside it. A closing tag for an element
+    /// that isn't open is ignored
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - the lowercase element name
+    fn close(&mut self, name: &str) {
+        if let Some(i) = self.open.iter().rposition(|open| open == name) {
+            self.open.truncate(i);
+        }
+    }
+
+    /// Ends a markdown HTML block, which closes the elements that don't need a closing tag
+    pub(crate) fn end_block(&mut self) {
+        while self
+            .open
+            .last()
+            .is_some_and(|open| OPTIONAL_END_ELEMENTS.contains(&open.as_str()))
+        {
+            self.open.pop();
+        }
+    }
+
+    /// Takes the HTML once every element in it has been closed
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - the HTML, or `None` if it isn't complete or there isn't any
+    pub(crate) fn complete(&mut self) -> Option<String> {
+        if self.html.is_empty()
+            || !self.open.is_empty()
+            || self.tag_start.is_some()
+            || self.scanned < self.html.len()
+        {
+            return None;
+        }
+        Some(self.take())
+    }
+
+    /// Takes the HTML, whether or not it is complete
+    pub(crate) fn take(&mut self) -> String {
+        self.scanned = 0;
+        self.tag_start = None;
+        self.quote = None;
+        self.open.clear();
+        std::mem::take(&mut self.html)
+    }
+
+    /// Takes what is left at the end of the document, with a warning, since an element that was
+    /// never closed holds everything after it
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - the HTML, or `None` if there isn't any
+    pub(crate) fn finish(&mut self) -> Option<String> {
+        if let Some(html) = self.complete() {
+            return Some(html);
+        }
+        if self.html.trim().is_empty() {
+            self.take();
+            return None;
+        }
+        match self.open.first() {
+            Some(name) => log::warn!(
+                "<{}> is never closed, so it holds the rest of the HTML",
+                name
+            ),
+            None => log::warn!("the document ends in the middle of an HTML tag or comment"),
+        }
+        Some(self.take())
+    }
+
+    /// Takes HTML that is still open when a markdown block comes after it, with a warning, so it
+    /// is written where it was instead of after the markdown
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - the HTML, or `None` if there isn't any
+    pub(crate) fn interrupt(&mut self) -> Option<String> {
+        if self.html.trim().is_empty() {
+            self.take();
+            return None;
+        }
+        match self.open.first() {
+            Some(name) => log::warn!(
+                "<{}> isn't closed before the markdown after it, so it ends there",
+                name
+            ),
+            None => {
+                log::warn!("an HTML tag or comment isn't finished before the markdown after it")
+            }
+        }
+        Some(self.take())
+    }
+}
+
+/// A piece of block HTML, split around `<details>` tags, since the markdown between them is
+/// the body of an expand
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum DetailsPart<'h> {
+    /// An opening `<details>`, with the content of its `<summary>`, if it has one
+    Open(Option<&'h str>),
+    /// A closing `</details>`
+    Close,
+    /// Any other HTML
+    Html(&'h str),
+}
+
+/// Checks if a markdown event starts or ends a block other than HTML, which ends any HTML element
+/// still open before it
+///
+/// # Arguments
+///
+/// * `event` - the event
+pub(crate) fn ends_html(event: &Event) -> bool {
+    match event {
+        Event::Start(tag) => !matches!(tag, Tag::HtmlBlock),
+        Event::End(tag) => !matches!(tag, TagEnd::HtmlBlock),
+        Event::Rule => true,
+        _ => false,
+    }
+}
+
+/// Checks if block HTML opens or closes a `<details>` element
+///
+/// # Arguments
+///
+/// * `html` - the HTML
+pub(crate) fn has_details(html: &str) -> bool {
+    html.to_ascii_lowercase().contains("details")
+        && !split_details(html)
+            .iter()
+            .all(|part| matches!(part, DetailsPart::Html(_)))
+}
+
+/// Splits block HTML at its `<details>` and `</details>` tags, which don't have to be in the
+/// same block
+///
+/// # Arguments
+///
+/// * `html` - the HTML
+///
+/// # Returns
+///
+/// * `Vec<DetailsPart>` - the tags, with a summary that follows an opening tag, and the HTML
+///   between them
+pub(crate) fn split_details(html: &str) -> Vec<DetailsPart<'_>> {
+    // tags are ASCII, so positions in the lowercase copy are the same
+    let lower = html.to_ascii_lowercase();
+    let mut parts = vec![];
+    // where the HTML that hasn't been added to `parts` starts, and where to search from
+    let mut html_start = 0;
+    let mut search = 0;
+    while let Some(start) = lower[search..].find("details").map(|i| search + i) {
+        search = start + "details".len();
+        let closing = lower[..start].ends_with("</");
+        let tag_start = start - if closing { 2 } else { 1 };
+        let is_tag = (closing || lower[..start].ends_with('<'))
+            && lower[search..].starts_with(|c: char| c == '>' || c.is_whitespace());
+        // `details` in text, or in another tag's attributes, is left in the HTML
+        let Some(tag_end) = lower[start..]
+            .find('>')
+            .map(|i| start + i + 1)
+            .filter(|_| is_tag)
+        else {
+            continue;
+        };
+        parts.push(DetailsPart::Html(&html[html_start..tag_start]));
+        html_start = tag_end;
+        search = tag_end;
+        if closing {
+            parts.push(DetailsPart::Close);
+            continue;
+        }
+        // the summary comes first in the details
+        let after = lower[tag_end..].trim_start();
+        let summary = after
+            .starts_with("<summary")
+            .then(|| lower.len() - after.len())
+            .and_then(|summary_start| {
+                let content_start = summary_start + lower[summary_start..].find('>')? + 1;
+                let content_end = content_start + lower[content_start..].find("</summary")?;
+                let summary_end = content_end + lower[content_end..].find('>')? + 1;
+                Some((content_start, content_end, summary_end))
+            });
+        match summary {
+            Some((content_start, content_end, summary_end)) => {
+                parts.push(DetailsPart::Open(Some(&html[content_start..content_end])));
+                html_start = summary_end;
+                search = summary_end;
+            }
+            None => parts.push(DetailsPart::Open(None)),
+        }
+    }
+    parts.push(DetailsPart::Html(&html[html_start..]));
+    // whitespace between the tags isn't content
+    parts.retain(|part| !matches!(part, DetailsPart::Html(html) if html.trim().is_empty()));
+    parts
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_markup() {
+        assert_eq!(Some(Markup::Wrap("^", "^")), markup("sup"));
+        assert_eq!(Some(Markup::Wrap("{{", "}}")), markup("KBD"));
+        assert_eq!(Some(Markup::Break), markup("br"));
+        assert_eq!(None, markup("blink"));
+    }
+
+    #[test]
+    fn test_parse_tag() {
+        assert_eq!(Some(("kbd".to_string(), false)), parse_tag("<kbd>"));
+        assert_eq!(Some(("kbd".to_string(), true)), parse_tag("</KBD>"));
+        assert_eq!(Some(("br".to_string(), false)), parse_tag("<br/>"));
+        assert_eq!(
+            Some(("a".to_string(), false)),
+            parse_tag(r#"<a href="https://example.com">"#)
+        );
+        assert_eq!(None, parse_tag("<!-- a comment -->"));
+        assert_eq!(None, parse_tag("<?php ?>"));
+    }
+
+    #[test]
+    fn test_attribute() {
+        assert_eq!(
+            Some("https://example.com/?a=1&b=2".to_string()),
+            attribute(r#"<a href="https://example.com/?a=1&amp;b=2">"#, "href")
+        );
+        assert_eq!(None, attribute(r#"<a name="top">"#, "href"));
+    }
+
+    #[test]
+    fn test_split_details() {
+        assert_eq!(
+            vec![DetailsPart::Open(Some("More <b>info</b>"))],
+            split_details("<details open>\n<summary>More <b>info</b></summary>\n")
+        );
+        assert_eq!(
+            vec![
+                DetailsPart::Html("<p>a details page</p>\n"),
+                DetailsPart::Close,
+                DetailsPart::Open(None),
+                DetailsPart::Html("body"),
+                DetailsPart::Close,
+            ],
+            split_details("<p>a details page</p>\n</details><DETAILS>body</details>\n")
+        );
+        assert!(has_details("</details>\n"));
+        assert!(!has_details("<p class=\"details\">details</p>"));
+    }
+
+    #[test]
+    fn test_html_buffer() {
+        let mut buffer = HtmlBuffer::default();
+        buffer.push("<table>\n");
+        buffer.push("<tr><td>a <br> b</td>\n");
+        assert_eq!(None, buffer.complete());
+        buffer.push("</table>\n");
+        assert_eq!(
+            Some("<table>\n<tr><td>a <br> b</td>\n</table>\n".to_string()),
+            buffer.complete()
+        );
+        // a stray closing tag doesn't hold up the HTML after it
+        buffer.push("</p>\n");
+        assert_eq!(Some("</p>\n".to_string()), buffer.complete());
+        // tags split across lines, and `>` in attributes
+        buffer.push("<img src=\"a.png\"\n");
+        assert_eq!(None, buffer.complete());
+        buffer.push("alt=\"a > b\">\n");
+        assert!(buffer.complete().is_some());
+        // comments and scripts hold text that looks like tags
+        buffer.push("<!-- <div> -->\n<script>if (a <b) {}</script>\n");
+        assert!(buffer.complete().is_some());
+        buffer.push("<ul><li>a<li>b</ul> 1 < 2\n");
+        assert!(buffer.complete().is_some());
+        // comments and quoted values that go on over several pieces, even split in their ends
+        for pieces in [
+            ["<!-- a\n", "<p> b -", "-> c\n"],
+            ["<!", "-- <p> -- > --", ">\n"],
+            ["<a title='x\n", "> <p> y'\n", "href=\"b\">c</a>\n"],
+        ] {
+            for (i, piece) in pieces.iter().enumerate() {
+                buffer.push(piece);
+                assert_eq!(
+                    i == pieces.len() - 1,
+                    buffer.complete().is_some(),
+                    "{}",
+                    piece
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_html_buffer_end_block() {
+        let mut buffer = HtmlBuffer::default();
+        buffer.push("<p align=\"center\">\n");
+        buffer.push("<img src=\"a.png\">\n");
+        assert_eq!(None, buffer.complete());
+        buffer.end_block();
+        assert!(buffer.complete().is_some());
+        // a `<div>` can wrap markdown, so it stays open across blocks
+        buffer.push("<div>\n");
+        buffer.end_block();
+        assert_eq!(None, buffer.complete());
+        assert_eq!(Some("<div>\n".to_string()), buffer.finish());
+        assert_eq!(None, buffer.finish());
+    }
+}
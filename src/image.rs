@@ -0,0 +1,235 @@
+use scraper::node::Element;
+use scraper::{Html, Node};
+
+use crate::storage::escape_xml;
+
+/// Where an image sits on the page
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+impl Align {
+    /// Reads an `align` attribute value
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - `left`, `center` or `right`, in any case
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "left" => Some(Align::Left),
+            "center" | "middle" => Some(Align::Center),
+            "right" => Some(Align::Right),
+            _ => None,
+        }
+    }
+
+    /// The attribute value, as wiki markup, storage format and HTML spell it
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Align::Left => "left",
+            Align::Center => "center",
+            Align::Right => "right",
+        }
+    }
+}
+
+/// An image, with the attributes each format can write
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Image {
+    // the url or path of the image
+    pub(crate) source: String,
+    // alternative text, from the markdown image text or the `alt` attribute
+    pub(crate) alt: String,
+    // tooltip, from the markdown image title or the `title` attribute
+    pub(crate) title: String,
+    // size in pixels
+    pub(crate) width: Option<u32>,
+    pub(crate) height: Option<u32>,
+    pub(crate) align: Option<Align>,
+}
+
+impl Image {
+    /// return a new Image
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the url or path of the image
+    /// * `alt` - the alternative text
+    /// * `title` - the tooltip
+    pub(crate) fn new(source: &str, alt: &str, title: &str) -> Self {
+        Image {
+            source: source.to_string(),
+            alt: alt.to_string(),
+            title: title.to_string(),
+            ..Image::default()
+        }
+    }
+
+    /// Sets a size or alignment attribute. Other attributes, and values that can't be
+    /// represented, like percentages, are ignored
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - `width`, `height` or `align`
+    /// * `value` - the attribute value, like `300`, `300px` or `center`
+    pub(crate) fn set(&mut self, key: &str, value: &str) {
+        let pixels = || value.trim_end_matches("px").parse::<u32>().ok();
+        match key.to_ascii_lowercase().as_str() {
+            "width" => self.width = pixels().or(self.width),
+            "height" => self.height = pixels().or(self.height),
+            "align" => self.align = Align::parse(value).or(self.align),
+            _ => (),
+        }
+    }
+
+    /// Reads pandoc style `{width=300 align=center}` attributes from the text after an image
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - the text that follows the image
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - how many bytes of `text` were attributes, or 0 if it doesn't start with any
+    pub(crate) fn read_attributes(&mut self, text: &str) -> usize {
+        let Some(end) = text.strip_prefix('{').and_then(|rest| rest.find('}')) else {
+            return 0;
+        };
+        let mut attributes = vec![];
+        for attribute in text[1..end + 1].split_whitespace() {
+            match attribute.split_once('=') {
+                Some((key, value)) if !key.is_empty() => attributes.push((key, value)),
+                // pandoc classes and ids have nowhere to go, but are still attributes
+                None if attribute.starts_with(['.', '#']) => (),
+                // anything else is text that happens to be in braces
+                _ => return 0,
+            }
+        }
+        if attributes.is_empty() {
+            return 0;
+        }
+        for (key, value) in attributes {
+            self.set(key, value.trim_matches('"'));
+        }
+        end + 2
+    }
+
+    /// Reads an HTML `<img>` tag
+    ///
+    /// # Arguments
+    ///
+    /// * `html` - the HTML, which should be a single `<img>` element
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Image>` - the image, if the HTML is one
+    pub(crate) fn from_html(html: &str) -> Option<Self> {
+        if !html.trim_start().to_ascii_lowercase().starts_with("<img") {
+            return None;
+        }
+        let fragment = Html::parse_fragment(html);
+        let img = fragment
+            .tree
+            .root()
+            .descendants()
+            .find_map(|node| match node.value() {
+                Node::Element(elem) if elem.name() == "img" => Some(elem),
+                _ => None,
+            })?;
+        Some(Image::from_element(img))
+    }
+
+    /// Reads the attributes of an HTML `<img>` element
+    ///
+    /// # Arguments
+    ///
+    /// * `img` - the element
+    pub(crate) fn from_element(img: &Element) -> Self {
+        let attr = |name| img.attr(name).unwrap_or_default();
+        let mut image = Image::new(attr("src"), attr("alt"), attr("title"));
+        for key in ["width", "height", "align"] {
+            image.set(key, attr(key));
+        }
+        image
+    }
+
+    /// Checks if the image has a size or alignment, which markdown image syntax can't hold
+    pub(crate) fn has_attributes(&self) -> bool {
+        self.width.is_some() || self.height.is_some() || self.align.is_some()
+    }
+
+    /// Writes the image as an HTML `<img>` tag, for markdown
+    pub(crate) fn to_html(&self) -> String {
+        let mut html = format!(r#"<img src="{}""#, escape_xml(&self.source));
+        if !self.alt.is_empty() {
+            html.push_str(&format!(r#" alt="{}""#, escape_xml(&self.alt)));
+        }
+        if !self.title.is_empty() {
+            html.push_str(&format!(r#" title="{}""#, escape_xml(&self.title)));
+        }
+        if let Some(width) = self.width {
+            html.push_str(&format!(r#" width="{}""#, width));
+        }
+        if let Some(height) = self.height {
+            html.push_str(&format!(r#" height="{}""#, height));
+        }
+        if let Some(align) = self.align {
+            html.push_str(&format!(r#" align="{}""#, align.as_str()));
+        }
+        html.push_str(" />");
+        html
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_attributes() {
+        let mut image = Image::default();
+        assert_eq!(
+            26,
+            image.read_attributes("{width=300px align=center} and more")
+        );
+        assert_eq!(Some(300), image.width);
+        assert_eq!(Some(Align::Center), image.align);
+        // percentages can't be written, but are still attributes
+        let mut image = Image::default();
+        assert_eq!(11, image.read_attributes("{width=50%}"));
+        assert_eq!(None, image.width);
+        // braces that aren't attributes are left as text
+        assert_eq!(0, Image::default().read_attributes("{not attributes}"));
+        assert_eq!(0, Image::default().read_attributes("{}"));
+        assert_eq!(0, Image::default().read_attributes(" {width=300}"));
+    }
+
+    #[test]
+    fn test_from_html() {
+        let image = Image::from_html(
+            r#"<img src="a.png" alt="a &amp; b" width="300" height="200px" align="right">"#,
+        )
+        .unwrap();
+        assert_eq!("a.png", image.source);
+        assert_eq!("a & b", image.alt);
+        assert_eq!(Some(300), image.width);
+        assert_eq!(Some(200), image.height);
+        assert_eq!(Some(Align::Right), image.align);
+        assert_eq!(None, Image::from_html("<b>"));
+    }
+
+    #[test]
+    fn test_to_html() {
+        let mut image = Image::new("a.png", "say \"hi\"", "");
+        image.set("width", "300");
+        image.set("align", "center");
+        assert_eq!(
+            r#"<img src="a.png" alt="say &quot;hi&quot;" width="300" align="center" />"#,
+            image.to_html()
+        );
+        assert_eq!(image, Image::from_html(&image.to_html()).unwrap());
+    }
+}
@@ -0,0 +1,307 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::dialect::Flavor;
+use crate::options::RenderOptions;
+
+/// The built-in language table
+const DEFAULT_LANGUAGES: &str = include_str!("languages.toml");
+
+/// The code block languages each flavor can highlight, and the markdown languages that map to
+/// them. The default is the built-in table, and a file laid out like it can be added with
+/// `Languages::from_file` and `Languages::merge`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Languages {
+    // the language code blocks are highlighted as when theirs isn't supported
+    #[serde(default)]
+    fallback: Option<String>,
+    // languages every flavor can highlight
+    #[serde(default)]
+    languages: Vec<String>,
+    // languages only one flavor can highlight
+    #[serde(default)]
+    jira: Vec<String>,
+    #[serde(default)]
+    confluence: Vec<String>,
+    // markdown languages, by the supported language they're highlighted as
+    #[serde(default)]
+    aliases: BTreeMap<String, Vec<String>>,
+}
+
+impl Default for Languages {
+    fn default() -> Self {
+        // options are made often, so the table is only read once
+        static DEFAULT: OnceLock<Languages> = OnceLock::new();
+        DEFAULT
+            .get_or_init(|| {
+                Languages::from_toml(DEFAULT_LANGUAGES)
+                    .expect("The built-in language table is valid")
+            })
+            .clone()
+    }
+}
+
+impl Languages {
+    /// Reads a language table from TOML
+    ///
+    /// # Arguments
+    ///
+    /// * `toml` - the table, laid out like the built-in one
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Languages>` - the table, or why it couldn't be read
+    pub fn from_toml(toml: &str) -> io::Result<Self> {
+        toml::from_str(toml).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Reads a language table from YAML
+    ///
+    /// # Arguments
+    ///
+    /// * `yaml` - the table, laid out like the built-in one
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Languages>` - the table, or why it couldn't be read
+    pub fn from_yaml(yaml: &str) -> io::Result<Self> {
+        serde_yaml::from_str(yaml).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Reads a language table from a file, which is YAML if it ends in `.yaml` or `.yml`, and
+    /// TOML otherwise
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the file
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Languages>` - the table, or why it couldn't be read, naming the file
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let named = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+        let text = std::fs::read_to_string(path).map_err(named)?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml" | "yml") => Languages::from_yaml(&text),
+            _ => Languages::from_toml(&text),
+        }
+        .map_err(named)
+    }
+
+    /// Adds another table to this one. Its languages are supported as well, its aliases replace
+    /// any for the same markdown language, and its fallback replaces this one's
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - the table to add, like one from a user's file
+    pub fn merge(mut self, other: Languages) -> Self {
+        if other.fallback.is_some() {
+            self.fallback = other.fallback;
+        }
+        for (list, more) in [
+            (&mut self.languages, other.languages),
+            (&mut self.jira, other.jira),
+            (&mut self.confluence, other.confluence),
+        ] {
+            for language in more {
+                if !list.contains(&language) {
+                    list.push(language);
+                }
+            }
+        }
+        for (language, aliases) in other.aliases {
+            for existing in self.aliases.values_mut() {
+                existing.retain(|alias| !aliases.contains(alias));
+            }
+            self.aliases.entry(language).or_default().extend(aliases);
+        }
+        self
+    }
+
+    /// A table without any languages, for a language map that replaces the built-in one
+    pub(crate) fn empty() -> Self {
+        Languages {
+            fallback: None,
+            languages: vec![],
+            jira: vec![],
+            confluence: vec![],
+            aliases: BTreeMap::new(),
+        }
+    }
+
+    /// The language code blocks are highlighted as when theirs isn't supported
+    pub fn fallback(&self) -> &str {
+        self.fallback.as_deref().unwrap_or("text")
+    }
+
+    /// Builds the map a flavor's code block languages are looked up in
+    ///
+    /// # Arguments
+    ///
+    /// * `flavor` - the flavor, whose own languages are supported as well as the shared ones
+    ///
+    /// # Returns
+    ///
+    /// * `HashMap<String, String>` - from markdown to supported code block langs. Aliases of a
+    ///   language the flavor can't highlight are left out, so they fall back
+    pub fn lang_map(&self, flavor: Flavor) -> HashMap<String, String> {
+        let flavor_languages = match flavor {
+            Flavor::Jira => &self.jira,
+            Flavor::Confluence => &self.confluence,
+        };
+        let mut lang_map: HashMap<String, String> = self
+            .languages
+            .iter()
+            .chain(flavor_languages)
+            .map(|language| (language.clone(), language.clone()))
+            .collect();
+        for (language, aliases) in &self.aliases {
+            if lang_map.contains_key(language) {
+                for alias in aliases {
+                    lang_map.insert(alias.clone(), language.clone());
+                }
+            }
+        }
+        lang_map
+    }
+}
+
+/// Maps markdown code block languages to supported ones, warning once for each language that
+/// isn't supported
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LanguageMapper {
+    // from markdown to supported code block langs
+    lang_map: HashMap<String, String>,
+    // the language for code blocks whose language isn't supported
+    fallback: String,
+    // languages that have been reported as unsupported
+    unknown: HashSet<String>,
+}
+
+impl LanguageMapper {
+    /// return a new LanguageMapper
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - the language map and fallback
+    pub(crate) fn new(options: &RenderOptions) -> Self {
+        LanguageMapper {
+            lang_map: options.language_map(),
+            fallback: options.fallback_language.clone(),
+            unknown: HashSet::new(),
+        }
+    }
+
+    /// Maps a markdown code block language to a supported one, ignoring case
+    ///
+    /// # Arguments
+    ///
+    /// * `language` - the markdown code block language
+    ///
+    /// # Returns
+    ///
+    /// * `String` - the mapped language, or the fallback if it isn't supported
+    pub(crate) fn map(&mut self, language: &str) -> String {
+        if let Some(lang) = self.lang_map.get(&language.to_lowercase()) {
+            return lang.clone();
+        }
+        // a code block without a language has nothing to report
+        if !language.is_empty() && self.unknown.insert(language.to_string()) {
+            log::warn!(
+                "code block language `{}` isn't supported, so it is highlighted as `{}`",
+                language,
+                self.fallback
+            );
+        }
+        self.fallback.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default() {
+        let lang_map = Languages::default().lang_map(Flavor::Confluence);
+        assert_eq!(Some("bash"), lang_map.get("console").map(String::as_str));
+        assert_eq!(Some("yaml"), lang_map.get("yaml").map(String::as_str));
+        assert_eq!(None, lang_map.get("go"));
+        assert_eq!("text", Languages::default().fallback());
+        // Jira's {code} macro can't highlight some of the languages Confluence's can
+        assert_eq!(Some("delphi"), lang_map.get("pascal").map(String::as_str));
+        let jira = Languages::default().lang_map(Flavor::Jira);
+        for language in ["coldfusion", "delphi", "jfx", "pascal"] {
+            assert_eq!(None, jira.get(language));
+        }
+        assert_eq!(Some("go"), jira.get("go").map(String::as_str));
+    }
+
+    #[test]
+    fn test_from_file() {
+        let path = std::env::temp_dir().join("markrust-test-languages.toml");
+        std::fs::write(&path, "fallback = [").unwrap();
+        let error = Languages::from_file(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(error.to_string().starts_with(&path.display().to_string()));
+    }
+
+    #[test]
+    fn test_merge() {
+        let user = Languages::from_toml(
+            "fallback = \"none\"\n\
+            confluence = [\"go\", \"rust\"]\n\
+            [aliases]\n\
+            go = [\"golang\"]\n\
+            rust = [\"rs\"]\n\
+            text = [\"sh\"]\n",
+        )
+        .unwrap();
+        let languages = Languages::default().merge(user);
+        assert_eq!("none", languages.fallback());
+        let confluence = languages.lang_map(Flavor::Confluence);
+        assert_eq!(Some("go"), confluence.get("golang").map(String::as_str));
+        assert_eq!(Some("rust"), confluence.get("rs").map(String::as_str));
+        // aliases from the user's table replace the built-in ones
+        assert_eq!(Some("text"), confluence.get("sh").map(String::as_str));
+        assert_eq!(Some("bash"), confluence.get("zsh").map(String::as_str));
+        // rust is only supported in confluence, while Jira has go built in
+        let jira = languages.lang_map(Flavor::Jira);
+        assert_eq!(None, jira.get("rust"));
+        assert_eq!(None, jira.get("rs"));
+        assert_eq!(Some("go"), jira.get("golang").map(String::as_str));
+    }
+
+    #[test]
+    fn test_from_yaml() {
+        let languages =
+            Languages::from_yaml("jira:\n  - kotlin\naliases:\n  kotlin: [kt, kts]\n").unwrap();
+        assert_eq!(
+            Some("kotlin"),
+            languages
+                .lang_map(Flavor::Jira)
+                .get("kts")
+                .map(String::as_str)
+        );
+        assert!(Languages::from_yaml("language: [go]").is_err());
+        assert!(Languages::from_toml("fallback = 1").is_err());
+    }
+
+    #[test]
+    fn test_mapper() {
+        let options = RenderOptions::new().language("golang", "go");
+        let mut mapper = LanguageMapper::new(&options);
+        assert_eq!("go", mapper.map("golang"));
+        assert_eq!("javascript", mapper.map("JS"));
+        assert_eq!("text", mapper.map("brainfuck"));
+        assert_eq!("text", mapper.map(""));
+        assert!(mapper.unknown.contains("brainfuck"));
+        assert!(!mapper.unknown.contains(""));
+    }
+}
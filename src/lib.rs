@@ -0,0 +1,225 @@
+//! Converts Markdown to Atlassian markup
+//!
+//! ```
+//! use markrust::{Converter, Flavor, RenderOptions};
+//!
+//! let converter = Converter::new(RenderOptions::new().flavor(Flavor::Jira));
+//! assert_eq!(converter.convert("# hello world").unwrap(), "h1. hello world\n");
+//! ```
+
+use pulldown_cmark::{Event, Options, Parser as MarkdownParser};
+use std::io::{self, Write};
+
+/// The ADF renderer converts events from pulldown-cmark into Atlassian Document Format JSON, and
+/// the ADF parser converts it back into events
+pub mod adf;
+/// Heading slugs, and the anchors `#fragment` links are rewritten to
+mod anchor;
+/// The renderer is responsible for converting events from pulldown-cmark into markup
+pub mod atlassian;
+/// Per-directory defaults for the CLI, from `.markrust.toml` files
+pub mod config;
+/// Per-target differences between Atlassian markup flavors
+pub mod dialect;
+/// Context-aware escaping of text written as wiki markup
+mod escape;
+/// Footnotes, which are numbered and moved to a Notes section at the end of the document
+mod footnotes;
+/// Metadata from the front matter at the start of a markdown document
+pub mod front_matter;
+/// HTML elements, and the wiki markup each one becomes
+mod html;
+/// Images, and the size and alignment attributes markdown keeps outside of image syntax
+mod image;
+/// Code block languages each flavor can highlight, from a built-in table and the user's own
+pub mod languages;
+/// Resolves relative links and images against a base URL, page map and attachments
+mod links;
+/// The markdown renderer converts events back into CommonMark/GFM
+pub mod markdown;
+/// Builder for configuring the renderer
+pub mod options;
+/// Page properties directives, which mark a table for Confluence's page properties macro
+mod properties;
+/// The storage module converts events from pulldown-cmark into Confluence storage format, and back
+pub mod storage;
+/// The wiki parser converts Jira/Confluence wiki markup into events
+pub mod wiki;
+
+pub use config::Config;
+pub use dialect::{Dialect, Flavor};
+pub use front_matter::FrontMatter;
+pub use languages::Languages;
+pub use options::{InputFormat, OutputFormat, RenderOptions};
+pub use pulldown_cmark::BlockQuoteKind;
+
+/// Converts between Markdown and Atlassian markup with the same behavior as the CLI
+#[derive(Clone, Debug, Default)]
+pub struct Converter {
+    options: RenderOptions,
+}
+
+impl Converter {
+    /// return a new Converter
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - how to render the output
+    pub fn new(options: RenderOptions) -> Self {
+        Converter { options }
+    }
+
+    /// The options this converter renders with
+    pub fn options(&self) -> &RenderOptions {
+        &self.options
+    }
+
+    /// Converts the input format to the output format, writing to `writer`
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - something implementing the Write trait
+    /// * `input` - Markdown (or wiki markup, or ADF) source
+    ///
+    /// # Returns
+    ///
+    /// * `Result` - if wrote successfully to `writer`, or an error if the input couldn't be parsed
+    pub fn convert_to<W>(&self, mut writer: W, input: &str) -> io::Result<()>
+    where
+        W: Write,
+    {
+        // front matter can set options for the document
+        let front_matter = match self.options.input {
+            InputFormat::Markdown => FrontMatter::from_markdown(input),
+            _ => None,
+        };
+        let options = match &front_matter {
+            Some(front_matter) => front_matter.apply(self.options.clone()),
+            None => self.options.clone(),
+        };
+
+        let parser: Box<dyn Iterator<Item = Event>> = match self.options.input {
+            InputFormat::Markdown => {
+                let parser = MarkdownParser::new_ext(input, Options::all());
+                match &front_matter {
+                    Some(front_matter) => {
+                        Box::new(front_matter.render(parser, &options).into_iter())
+                    }
+                    None => Box::new(parser),
+                }
+            }
+            InputFormat::Wiki => {
+                Box::new(wiki::parse(input, &self.options.language_map()).into_iter())
+            }
+            InputFormat::Adf => {
+                Box::new(adf::parse(input, &self.options.language_map())?.into_iter())
+            }
+            InputFormat::Storage => {
+                Box::new(storage::parse(input, &self.options.language_map()).into_iter())
+            }
+        };
+
+        match options.format {
+            OutputFormat::Wiki => {
+                // prepend TOC markup first if required
+                if options.toc {
+                    atlassian::write_toc(&mut writer, options.flavor)?;
+                }
+                atlassian::write_with_options(&mut writer, parser, &options)
+            }
+            OutputFormat::Storage => {
+                if options.toc {
+                    storage::write_toc(&mut writer)?;
+                }
+                storage::write(&mut writer, parser, &options)
+            }
+            // the TOC is part of the document, so the writer handles it
+            OutputFormat::Adf => adf::write(&mut writer, parser, &options),
+            // markdown has no TOC markup
+            OutputFormat::Markdown => markdown::write(&mut writer, parser),
+        }
+    }
+
+    /// Converts the input format to the output format
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - Markdown (or wiki markup, or ADF) source
+    ///
+    /// # Returns
+    ///
+    /// * `Result<String>` - the rendered markup, or an error if the input couldn't be parsed
+    pub fn convert(&self, input: &str) -> io::Result<String> {
+        let mut output = Vec::new();
+        self.convert_to(&mut output, input)?;
+        // every writer writes str
+        Ok(String::from_utf8(output).expect("Output was not UTF-8"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_converter_toc() {
+        let converter = Converter::new(RenderOptions::new().toc(true));
+        assert_eq!(
+            "{toc}\n\nh1. hello world\n",
+            converter.convert("# hello world").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_converter_front_matter() {
+        let input = "---\ntitle: Runbook\nowner: ops\ntoc: true\n---\n\nAsk {{ page.owner }}";
+        let converter = Converter::new(
+            RenderOptions::new()
+                .title_heading(true)
+                .front_matter_table(true),
+        );
+        assert_eq!(
+            "{toc}\n\nh1. Runbook\n\n{details}\n||title||owner||\n|Runbook|ops|\n{details}\n\nAsk ops\n",
+            converter.convert(input).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_converter_custom_language() {
+        let input = "\
+        ```rust\n\
+        fn main() {}\n\
+        ```";
+        let converter = Converter::new(
+            RenderOptions::new()
+                .flavor(Flavor::Jira)
+                .language("rust", "rust"),
+        );
+        assert_eq!(
+            "\n{code:rust}\nfn main() {}\n{code}\n",
+            converter.convert(input).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_converter_flavor_languages() {
+        // each flavor highlights its own languages without a language table being passed
+        let input = "```go\nfunc main() {}\n```\n\n```pascal\nbegin end.\n```";
+        let jira = Converter::new(RenderOptions::new().flavor(Flavor::Jira));
+        assert_eq!(
+            "\n{code:go}\nfunc main() {}\n{code}\n\n{code:text}\nbegin end.\n{code}\n",
+            jira.convert(input).unwrap()
+        );
+        let confluence = Converter::new(RenderOptions::new().flavor(Flavor::Confluence));
+        assert_eq!(
+            "\n{code:language=text}\nfunc main() {}\n{code}\n\n{code:language=delphi}\nbegin end.\n{code}\n",
+            confluence.convert(input).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_converter_custom_escape() {
+        let converter = Converter::new(RenderOptions::new().escape("|", "&#124;"));
+        assert_eq!("\n{{a&#124;b}}\n", converter.convert("`a|b`").unwrap());
+    }
+}
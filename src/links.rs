@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+
+use crate::options::RenderOptions;
+
+/// Where a link or image points, once relative paths have been resolved
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Target {
+    /// A URL, written as-is
+    Url(String),
+    /// A Confluence page, by title, and an anchor on it
+    Page {
+        title: String,
+        anchor: Option<String>,
+    },
+    /// A file attached to the page, by file name
+    Attachment(String),
+}
+
+/// Checks if a link destination is a path in the repository, rather than a URL or a fragment
+///
+/// # Arguments
+///
+/// * `dest` - the link destination
+fn is_relative(dest: &str) -> bool {
+    if dest.is_empty() || dest.starts_with('#') || dest.starts_with("//") {
+        return false;
+    }
+    // a scheme, like `https:` or `mailto:`
+    let scheme = dest.split_once(':').is_some_and(|(scheme, _)| {
+        scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    });
+    !scheme
+}
+
+/// Normalizes a relative path, so `./a/../b.md` and `b.md` are the same
+///
+/// # Arguments
+///
+/// * `path` - the path. A leading `/` is relative to the repository root
+///
+/// # Returns
+///
+/// * `String` - the path without `.` segments, and with `..` applied where it can be
+pub(crate) fn normalize_path(path: &str) -> String {
+    let mut segments: Vec<&str> = vec![];
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." if segments.last().is_some_and(|last| *last != "..") => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+/// Joins a path onto a base URL, applying any `..` to the URL's path
+///
+/// # Arguments
+///
+/// * `base_url` - the URL paths are relative to, like a repository's tree URL
+/// * `path` - the path, relative to the base URL
+fn join(base_url: &str, path: &str) -> String {
+    // keep `scheme://host` out of the path
+    let path_start = base_url
+        .find("://")
+        .and_then(|i| base_url[i + 3..].find('/').map(|j| i + 3 + j))
+        .unwrap_or(base_url.len());
+    let (origin, base_path) = base_url.split_at(path_start);
+    let joined = normalize_path(&format!("{}/{}", base_path, path));
+    // `..` past the root of the base url has nowhere to go
+    let joined = joined.trim_start_matches("../");
+    format!("{}/{}", origin, joined)
+}
+
+/// Resolves the links and images in a document
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Resolver {
+    // the URL of the repository root, which resolved links are joined onto
+    base_url: Option<String>,
+    // from normalized markdown path, relative to the repository root, to Confluence page title
+    page_map: HashMap<String, String>,
+    // the directory of the document, relative to the repository root, which links start from
+    dir: String,
+}
+
+impl Resolver {
+    /// return a new Resolver
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - the base URL, page map and document path
+    pub(crate) fn new(options: &RenderOptions) -> Self {
+        let dir = match options
+            .path
+            .as_deref()
+            .and_then(|path| path.rsplit_once('/'))
+        {
+            Some((dir, _)) => dir.to_string(),
+            None => String::new(),
+        };
+        Resolver {
+            base_url: options.base_url.clone(),
+            page_map: options.page_map.clone(),
+            dir,
+        }
+    }
+
+    /// Resolves a relative path from the document's directory
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path, as it's linked. A leading `/` is relative to the repository root
+    ///
+    /// # Returns
+    ///
+    /// * `String` - the normalized path relative to the repository root
+    fn repository_path(&self, path: &str) -> String {
+        if path.starts_with('/') {
+            normalize_path(path)
+        } else {
+            normalize_path(&format!("{}/{}", self.dir, path))
+        }
+    }
+
+    /// Resolves a link: paths in the page map become Confluence pages, and other relative paths
+    /// are joined onto the base URL. Both are resolved from the document's path first
+    ///
+    /// # Arguments
+    ///
+    /// * `dest` - the link destination
+    ///
+    /// # Returns
+    ///
+    /// * `Target` - where the link goes
+    pub(crate) fn link(&self, dest: &str) -> Target {
+        if !is_relative(dest) {
+            return Target::Url(dest.to_string());
+        }
+        let (path, anchor) = match dest.split_once('#') {
+            Some((path, anchor)) => (path, Some(anchor.to_string())),
+            None => (dest, None),
+        };
+        if let Some(title) = self.page_map.get(&self.repository_path(path)) {
+            return Target::Page {
+                title: title.clone(),
+                anchor,
+            };
+        }
+        Target::Url(self.url(dest))
+    }
+
+    /// Resolves a link to a URL, for targets that can't link to pages
+    ///
+    /// # Arguments
+    ///
+    /// * `dest` - the link destination
+    ///
+    /// # Returns
+    ///
+    /// * `String` - the URL, joined onto the base URL if it's relative
+    pub(crate) fn url(&self, dest: &str) -> String {
+        match &self.base_url {
+            Some(base_url) if is_relative(dest) => {
+                let (path, fragment) = match dest.find('#') {
+                    Some(i) => dest.split_at(i),
+                    None => (dest, ""),
+                };
+                join(base_url, &self.repository_path(path)) + fragment
+            }
+            _ => dest.to_string(),
+        }
+    }
+
+    /// Resolves an image: local images are uploaded with the page, so they become attachments
+    ///
+    /// # Arguments
+    ///
+    /// * `dest` - the image source
+    ///
+    /// # Returns
+    ///
+    /// * `Target` - an attachment for a relative path, otherwise the URL
+    pub(crate) fn image(&self, dest: &str) -> Target {
+        if is_relative(dest) {
+            let name = dest.rsplit('/').next().unwrap_or(dest);
+            Target::Attachment(name.to_string())
+        } else {
+            Target::Url(dest.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_relative() {
+        assert!(is_relative("other.md"));
+        assert!(is_relative("../api/auth.md#tokens"));
+        assert!(is_relative("/docs/index.md"));
+        assert!(!is_relative("https://example.com"));
+        assert!(!is_relative("mailto:someone@example.com"));
+        assert!(!is_relative("//cdn.example.com/x.png"));
+        assert!(!is_relative("#setup"));
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!("b.md", normalize_path("./a/../b.md"));
+        assert_eq!("../api/auth.md", normalize_path("../api/./auth.md"));
+        assert_eq!("docs/index.md", normalize_path("/docs//index.md"));
+    }
+
+    #[test]
+    fn test_links() {
+        let resolver = Resolver::new(
+            &RenderOptions::new()
+                .base_url("https://github.com/org/repo/blob/main/docs/")
+                .page("guides/other.md", "Other Page"),
+        );
+        assert_eq!(
+            Target::Url("https://github.com/org/repo/blob/main/api/auth.md#tokens".to_string()),
+            resolver.link("../api/auth.md#tokens")
+        );
+        assert_eq!(
+            Target::Page {
+                title: "Other Page".to_string(),
+                anchor: Some("setup".to_string()),
+            },
+            resolver.link("./guides/other.md#setup")
+        );
+        assert_eq!(
+            Target::Url("https://example.com".to_string()),
+            resolver.link("https://example.com")
+        );
+        // without a base url, relative links are left alone
+        assert_eq!(
+            Target::Url("../api/auth.md".to_string()),
+            Resolver::default().link("../api/auth.md")
+        );
+    }
+
+    #[test]
+    fn test_links_from_document_path() {
+        let resolver = Resolver::new(
+            &RenderOptions::new()
+                .base_url("https://github.com/org/repo/blob/main/")
+                .page("docs/api/auth.md", "API Authentication")
+                .path("./docs/guide/g.md"),
+        );
+        assert_eq!(
+            Target::Page {
+                title: "API Authentication".to_string(),
+                anchor: Some("tokens".to_string()),
+            },
+            resolver.link("../api/auth.md#tokens")
+        );
+        assert_eq!(
+            Target::Page {
+                title: "API Authentication".to_string(),
+                anchor: None,
+            },
+            resolver.link("/docs/api/auth.md")
+        );
+        assert_eq!(
+            Target::Url("https://github.com/org/repo/blob/main/docs/guide/setup.md".to_string()),
+            resolver.link("setup.md")
+        );
+        assert_eq!(
+            "https://github.com/org/repo/blob/main/README.md#usage",
+            resolver.url("../../README.md#usage")
+        );
+        // the map is keyed by repository path, not by the link as written
+        assert_eq!(
+            Target::Url("https://github.com/org/repo/blob/main/api/auth.md".to_string()),
+            Resolver::new(
+                &RenderOptions::new()
+                    .base_url("https://github.com/org/repo/blob/main/")
+                    .page("../api/auth.md", "API Authentication")
+                    .path("docs/g.md")
+            )
+            .link("../api/auth.md")
+        );
+    }
+
+    #[test]
+    fn test_images() {
+        assert_eq!(
+            Target::Attachment("diagram.png".to_string()),
+            Resolver::default().image("./img/diagram.png")
+        );
+        assert_eq!(
+            Target::Url("https://example.com/x.png".to_string()),
+            Resolver::default().image("https://example.com/x.png")
+        );
+    }
+}
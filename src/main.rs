@@ -1,11 +1,13 @@
-use pulldown_cmark::{Options, Parser as MarkdownParser};
+use markrust::{
+    BlockQuoteKind, Config, Converter, Flavor, FrontMatter, InputFormat, Languages, OutputFormat,
+    RenderOptions,
+};
 
-/// The renderer is responsible for converting events from pulldown-cmark into markup
-mod atlassian;
-
-use clap::{ArgGroup, Parser, ValueEnum};
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
 
+use std::collections::HashMap;
 use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::{env, fs};
 
@@ -16,9 +18,14 @@ use std::{env, fs};
 // exclude output because we'll manually shift input to output later (index 0 is input)
 #[clap(group(ArgGroup::new("editor_exclusion").required(false).args(&["output", "editor"])))]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Action>,
     /// Prepend TOC markup
-    #[clap(short, long)]
+    #[clap(short, long, overrides_with = "no_toc")]
     toc: bool,
+    /// Don't prepend TOC markup, even if the configuration does
+    #[clap(long, overrides_with = "toc")]
+    no_toc: bool,
     /// FILE input, or empty for stdin
     input: Option<String>,
     /// FILE output, or empty for stdout
@@ -26,11 +33,119 @@ struct Cli {
     /// Launch $EDITOR as input
     #[clap(short, long)]
     editor: bool,
-    /// Add N to header level (can be negative)
-    #[clap(default_value_t = 0, short, long)]
-    modify_headers: i8,
-    #[clap(default_value_t = Language::Confluence, short, long, value_enum)]
-    language: Language,
+    /// Add N to header level (can be negative) [default: 0]
+    #[clap(short, long, allow_negative_numbers = true)]
+    modify_headers: Option<i8>,
+    /// Output format [default: confluence, or markdown when converting from Atlassian markup]
+    #[clap(short, long, visible_alias = "to", value_enum)]
+    language: Option<Language>,
+    /// Input format
+    #[clap(default_value_t = Source::Markdown, short, long, value_enum)]
+    from: Source,
+    /// Macro for a kind of GitHub alert, like `caution=panel:title=Danger` (repeatable)
+    #[clap(long = "alert", value_name = "KIND=MACRO", value_parser = parse_alert)]
+    alerts: Vec<(String, String)>,
+    /// Confluence page title, so in-page links use the anchors Confluence makes for headings
+    #[clap(long, value_name = "TITLE")]
+    page_title: Option<String>,
+    /// URL of the repository root that relative links are joined onto, like its tree URL
+    #[clap(long, value_name = "URL")]
+    base_url: Option<String>,
+    /// Repository root that relative links and page map paths are resolved from [default: the
+    /// git repository the input is in, or the current directory]
+    #[clap(long, value_name = "DIR")]
+    root: Option<String>,
+    /// JSON file mapping markdown paths in the repository to the titles of the Confluence pages
+    /// they're published as
+    #[clap(long, value_name = "FILE")]
+    page_map: Option<String>,
+    /// TOML or YAML file of code block languages to support, their aliases and the fallback,
+    /// added to the built-in table
+    #[clap(long, value_name = "FILE")]
+    lang_map: Option<String>,
+    /// Write the front matter title as an h1, as well as using it as the page title
+    #[clap(long, overrides_with = "no_title_heading")]
+    title_heading: bool,
+    /// Don't write the front matter title as an h1, even if the configuration does
+    #[clap(long, overrides_with = "title_heading")]
+    no_title_heading: bool,
+    /// Write the front matter as a Confluence page properties table at the start
+    #[clap(long, overrides_with = "no_front_matter_table")]
+    front_matter_table: bool,
+    /// Don't write the front matter as a page properties table, even if the configuration does
+    #[clap(long, overrides_with = "front_matter_table")]
+    no_front_matter_table: bool,
+    /// Write the front matter title, labels and values to FILE as JSON, for publishing scripts
+    #[clap(long, value_name = "FILE")]
+    front_matter: Option<String>,
+}
+
+impl Cli {
+    /// The configuration set by flags, which is layered over the `.markrust.toml` files
+    fn config(&self) -> Config {
+        Config {
+            language: self
+                .language
+                .and_then(|language| language.to_possible_value())
+                .map(|language| language.get_name().to_string()),
+            toc: flag(self.toc, self.no_toc),
+            modify_headers: self.modify_headers,
+            lang_map: self.lang_map.as_ref().map(PathBuf::from),
+            base_url: self.base_url.clone(),
+            root: self.root.as_ref().map(PathBuf::from),
+            page_map: self.page_map.as_ref().map(PathBuf::from),
+            title_heading: flag(self.title_heading, self.no_title_heading),
+            front_matter_table: flag(self.front_matter_table, self.no_front_matter_table),
+            alerts: self.alerts.iter().cloned().collect(),
+        }
+    }
+}
+
+/// Reads a pair of `--FLAG` and `--no-FLAG` flags, of which only the last one given is set
+///
+/// # Arguments
+///
+/// * `on` - if `--FLAG` was given
+/// * `off` - if `--no-FLAG` was given
+///
+/// # Returns
+///
+/// * `Option<bool>` - the setting, or `None` to leave it to the configuration
+fn flag(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// Commands other than converting
+#[derive(Subcommand)]
+enum Action {
+    /// Work with `.markrust.toml` configuration files
+    Config {
+        #[clap(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+/// What to do with the configuration
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Print the configuration the `.markrust.toml` files and flags add up to
+    Show,
+}
+
+/// Input format
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum Source {
+    Markdown,
+    Jira,
+    Confluence,
+    /// Atlassian Document Format (JSON)
+    Adf,
+    /// Confluence storage format (XHTML)
+    Storage,
 }
 
 /// Atlassian flavor. Due to differences in rendering codeblocks. View #8
@@ -38,7 +153,139 @@ struct Cli {
 enum Language {
     Jira,
     Confluence,
+    /// Confluence storage format (XHTML)
+    Storage,
+    /// Atlassian Document Format (JSON)
+    Adf,
+    Markdown,
+}
+/// Parses a kind of GitHub alert
+///
+/// # Arguments
+///
+/// * `kind` - note, tip, important, warning or caution, in any case
+///
+/// # Returns
+///
+/// * `Result` - the alert kind, or why it couldn't be parsed
+fn parse_alert_kind(kind: &str) -> Result<BlockQuoteKind, String> {
+    match kind.to_lowercase().as_str() {
+        "note" => Ok(BlockQuoteKind::Note),
+        "tip" => Ok(BlockQuoteKind::Tip),
+        "important" => Ok(BlockQuoteKind::Important),
+        "warning" => Ok(BlockQuoteKind::Warning),
+        "caution" => Ok(BlockQuoteKind::Caution),
+        _ => Err(format!("unknown alert kind `{}`", kind)),
+    }
+}
+
+/// Parses an `--alert` mapping
+///
+/// # Arguments
+///
+/// * `s` - `KIND=MACRO`, where KIND is note, tip, important, warning or caution
+///
+/// # Returns
+///
+/// * `Result` - the lowercase alert kind and its opening macro, or why it couldn't be parsed
+fn parse_alert(s: &str) -> Result<(String, String), String> {
+    let (kind, alert_macro) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected KIND=MACRO, got `{}`", s))?;
+    parse_alert_kind(kind)?;
+    Ok((kind.to_lowercase(), alert_macro.to_string()))
+}
+
+/// Finds where the user's configuration lives, following the XDG base directory spec
+///
+/// # Returns
+///
+/// * `Option<PathBuf>` - `$XDG_CONFIG_HOME`, or `$HOME/.config` if it isn't set
+fn config_home() -> Option<PathBuf> {
+    let not_empty = |value: &std::ffi::OsString| !value.is_empty();
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(not_empty)
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME")
+                .filter(not_empty)
+                .map(|home| PathBuf::from(home).join(".config"))
+        })
 }
+
+/// Finds the document's path relative to the repository root, so relative links in it can be
+/// resolved to repository paths
+///
+/// # Arguments
+///
+/// * `input` - the input file
+/// * `root` - the configured repository root, if any. Otherwise it's the git repository the
+///   input is in, or the current directory
+///
+/// # Returns
+///
+/// * `Option<String>` - the path with `/` separators, if the input is under the root
+fn repository_path(input: &Path, root: Option<&Path>) -> Option<String> {
+    let input = fs::canonicalize(input).ok()?;
+    let root = match root {
+        Some(root) => fs::canonicalize(root).ok()?,
+        None => match input.ancestors().find(|dir| dir.join(".git").exists()) {
+            Some(repository) => repository.to_path_buf(),
+            None => fs::canonicalize(env::current_dir().ok()?).ok()?,
+        },
+    };
+    let path = input.strip_prefix(root).ok()?;
+    let segments: Option<Vec<&str>> = path.iter().map(|segment| segment.to_str()).collect();
+    Some(segments?.join("/"))
+}
+
+/// Reads a page map, from markdown paths in the repository to Confluence page titles
+///
+/// # Arguments
+///
+/// * `path` - the JSON file
+///
+/// # Returns
+///
+/// * `Result<HashMap<String, String>>` - the page map, or why it couldn't be read, naming the file
+fn read_page_map(path: &Path) -> io::Result<HashMap<String, String>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    serde_json::from_str(&text).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{}: page map must be a JSON object of strings: {}",
+                path.display(),
+                e
+            ),
+        )
+    })
+}
+
+/// Prints warnings, like unsupported HTML, to stderr
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            let level = match record.level() {
+                log::Level::Error => "error",
+                _ => "warning",
+            };
+            eprintln!("{}: {}", level, record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
 /// Binary entrypoint
 ///
 /// # Returns
@@ -46,6 +293,39 @@ enum Language {
 /// * `Result` - from writing to stdout or file
 fn main() -> io::Result<()> {
     let args = Cli::parse();
+    // the logger can only be set once, which it hasn't been yet
+    log::set_logger(&LOGGER).expect("Could not set up warnings");
+    log::set_max_level(log::LevelFilter::Warn);
+
+    // `.markrust.toml` files apply to the input file's directory, or the current one
+    let current_dir = env::current_dir()?;
+    let config_dir = match &args.input {
+        Some(input) if !args.editor => {
+            let dir = current_dir.join(Path::new(input).parent().unwrap_or(Path::new("")));
+            fs::canonicalize(&dir).unwrap_or(dir)
+        }
+        _ => current_dir,
+    };
+    let (config, config_files) = Config::discover(&config_dir, config_home().as_deref())?;
+    let config = config.merge(args.config());
+
+    if let Some(Action::Config {
+        action: ConfigAction::Show,
+    }) = args.command
+    {
+        let mut stdout = io::stdout();
+        for file in &config_files {
+            writeln!(stdout, "# from {}", file.display())?;
+        }
+        write!(stdout, "{}", config.to_toml())?;
+        return Ok(());
+    }
+
+    // relative links are resolved from the input file's place in the repository
+    let document_path = match &args.input {
+        Some(input) if !args.editor => repository_path(Path::new(input), config.root.as_deref()),
+        _ => None,
+    };
 
     let mut input_file: Option<String> = args.input;
     let mut output_file: Option<String> = args.output;
@@ -93,19 +373,82 @@ fn main() -> io::Result<()> {
         None => Box::new(BufWriter::new(io::stdout())),
     };
 
-    let options = Options::all();
-    let parser = MarkdownParser::new_ext(&input_string, options);
-
-    let modify_headers = args.modify_headers;
-
-    // prepend TOC markup first if required
-    if args.toc {
-        atlassian::write_toc(&mut output_writer)?;
+    let input = match args.from {
+        Source::Markdown => InputFormat::Markdown,
+        Source::Jira | Source::Confluence => InputFormat::Wiki,
+        Source::Adf => InputFormat::Adf,
+        Source::Storage => InputFormat::Storage,
+    };
+    // the configured language is for markdown, since other input goes back to markdown unless
+    // the flag says otherwise
+    let configured = match (&config.language, input) {
+        (Some(language), InputFormat::Markdown) => Some(
+            Language::from_str(language, true)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+        ),
+        _ => None,
+    };
+    let language = args.language.or(configured).unwrap_or(match input {
+        InputFormat::Markdown => Language::Confluence,
+        _ => Language::Markdown,
+    });
+    let (flavor, format) = match language {
+        Language::Confluence => (Flavor::Confluence, OutputFormat::Wiki),
+        Language::Jira => (Flavor::Jira, OutputFormat::Wiki),
+        Language::Storage => (Flavor::Confluence, OutputFormat::Storage),
+        Language::Adf => (Flavor::Jira, OutputFormat::Adf),
+        Language::Markdown => (Flavor::Confluence, OutputFormat::Markdown),
+    };
+    let mut options = RenderOptions::new()
+        .flavor(flavor)
+        .format(format)
+        .input(input)
+        .modify_headers(config.modify_headers.unwrap_or(0))
+        .toc(config.toc.unwrap_or(false))
+        .title_heading(config.title_heading.unwrap_or(false))
+        .front_matter_table(config.front_matter_table.unwrap_or(false));
+    for (kind, alert_macro) in &config.alerts {
+        let kind =
+            parse_alert_kind(kind).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        options = options.alert(kind, alert_macro);
+    }
+    if let Some(page_title) = &args.page_title {
+        options = options.page_title(page_title);
+    }
+    if let Some(base_url) = &config.base_url {
+        options = options.base_url(base_url);
+    }
+    if let Some(path) = &document_path {
+        options = options.path(path);
+    }
+    if let Some(page_map) = &config.page_map {
+        options = options.page_map(read_page_map(page_map)?);
+    }
+    if let Some(lang_map) = &config.lang_map {
+        let languages = Languages::from_file(lang_map)?;
+        options = options.languages(&Languages::default().merge(languages));
     }
-    match args.language {
-        Language::Confluence => atlassian::write(&mut output_writer, parser, modify_headers, 'c')?,
-        Language::Jira => atlassian::write(&mut output_writer, parser, modify_headers, 'j')?,
+    if let Some(front_matter_file) = &args.front_matter {
+        let front_matter = match input {
+            InputFormat::Markdown => FrontMatter::from_markdown(&input_string),
+            _ => None,
+        }
+        .unwrap_or_default();
+        let values: serde_json::Map<String, serde_json::Value> = front_matter
+            .values
+            .into_iter()
+            .map(|(key, value)| (key, value.into()))
+            .collect();
+        let json = serde_json::json!({
+            "title": front_matter.title,
+            "labels": front_matter.labels,
+            "toc": front_matter.toc,
+            "values": values,
+        });
+        fs::write(front_matter_file, format!("{:#}\n", json))
+            .expect("Could not write front matter file");
     }
+    Converter::new(options).convert_to(&mut output_writer, &input_string)?;
 
     // flush before drop
     output_writer.flush()
@@ -0,0 +1,706 @@
+use pulldown_cmark::*;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+/// Escapes characters that would otherwise start Markdown inline markup
+///
+/// # Arguments
+///
+/// * `s` - text to escape
+/// * `line_start` - if the text starts at the beginning of a line
+///
+/// # Returns
+///
+/// * `String` - text that renders as itself
+fn escape_text(s: &str, line_start: bool) -> String {
+    let mut r = String::with_capacity(s.len());
+    let chars: Vec<char> = s.chars().collect();
+    for (i, &c) in chars.iter().enumerate() {
+        match c {
+            '\\' | '*' | '`' | '[' | ']' | '<' | '|' => {
+                r.push('\\');
+                r.push(c);
+            }
+            // underscores inside words don't start emphasis
+            '_' if i == 0
+                || i + 1 == chars.len()
+                || !chars[i - 1].is_alphanumeric()
+                || !chars[i + 1].is_alphanumeric() =>
+            {
+                r.push_str("\\_");
+            }
+            // these only start blocks at the beginning of a line
+            '#' | '>' | '-' | '+' if i == 0 && line_start => {
+                r.push('\\');
+                r.push(c);
+            }
+            // the `.` or `)` after a number starts an ordered list, like `1.`
+            '.' | ')'
+                if line_start
+                    && (1..=9).contains(&i)
+                    && chars[..i].iter().all(|c| c.is_ascii_digit()) =>
+            {
+                r.push('\\');
+                r.push(c);
+            }
+            _ => r.push(c),
+        }
+    }
+    r
+}
+
+/// Finds the longest run of backticks, so code can be fenced with a longer one
+///
+/// # Arguments
+///
+/// * `code` - the code
+fn longest_backtick_run(code: &str) -> usize {
+    let mut longest = 0;
+    let mut current = 0;
+    for c in code.chars() {
+        if c == '`' {
+            current += 1;
+            longest = longest.max(current);
+        } else {
+            current = 0;
+        }
+    }
+    longest
+}
+
+/// The fence around front matter
+///
+/// # Arguments
+///
+/// * `kind` - YAML or TOML front matter
+fn front_matter_fence(kind: MetadataBlockKind) -> &'static str {
+    match kind {
+        MetadataBlockKind::YamlStyle => "---",
+        MetadataBlockKind::PlusesStyle => "+++",
+    }
+}
+
+/// The MarkdownWriter takes events from pulldown-cmark (or any importer producing them) and
+/// formats it into CommonMark/GFM
+struct MarkdownWriter<'a, I, W> {
+    iter: I,
+    writer: W,
+    // events that have been looked ahead at, to be handled before the rest of `iter`
+    lookahead: VecDeque<Event<'a>>,
+    // line prefixes of open containers, like `> ` for quotes and indentation for list items
+    prefixes: Vec<String>,
+    // if nothing has been written on the current line yet, including prefixes
+    line_start: bool,
+    // if anything has been written at all
+    started: bool,
+    // if the previous block needs a blank line before the next one
+    blank_pending: bool,
+    // if we just opened a container and its first block shouldn't start a new line
+    container_fresh: bool,
+    // numbers of open lists, or None for bullets
+    list_stack: Vec<Option<u64>>,
+    // if the previous event was part of an HTML block
+    in_html_block: bool,
+    // if we're inside a code block, where text isn't escaped
+    in_code_block: bool,
+    // cells of the current table row; inline content is written here while in a table
+    table_cells: Option<Vec<String>>,
+    // column alignments of the current table
+    table_alignments: Vec<Alignment>,
+    // cache the url and title for links and images because we need to put the text first
+    link_stack: Vec<(String, String)>,
+}
+
+impl<'a, I, W> MarkdownWriter<'a, I, W>
+where
+    I: Iterator<Item = Event<'a>>,
+    W: Write,
+{
+    /// return a new MarkdownWriter
+    ///
+    /// # Arguments
+    ///
+    /// * `iter` - iterator of pulldown-cmark events
+    /// * `writer` - something implementing Write to write output to
+    fn new(iter: I, writer: W) -> Self {
+        MarkdownWriter {
+            iter,
+            writer,
+            lookahead: VecDeque::new(),
+            prefixes: vec![],
+            line_start: true,
+            started: false,
+            blank_pending: false,
+            container_fresh: false,
+            list_stack: vec![],
+            in_html_block: false,
+            in_code_block: false,
+            table_cells: None,
+            table_alignments: vec![],
+            link_stack: vec![],
+        }
+    }
+
+    /// Returns the next event, taking looked ahead events first
+    fn next_event(&mut self) -> Option<Event<'a>> {
+        self.lookahead.pop_front().or_else(|| self.iter.next())
+    }
+
+    /// Returns the next event without taking it
+    fn peek_event(&mut self) -> Option<&Event<'a>> {
+        if self.lookahead.is_empty() {
+            let event = self.iter.next()?;
+            self.lookahead.push_back(event);
+        }
+        self.lookahead.front()
+    }
+
+    /// Writes `s` to underlying `writer`, prefixing every new line with the container prefixes.
+    /// Inside a table cell, writes to the cell instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - string to write
+    fn write(&mut self, s: &str) -> io::Result<()> {
+        if let Some(cell) = self.table_cells.as_mut().and_then(|cells| cells.last_mut()) {
+            cell.push_str(s);
+            return Ok(());
+        }
+        for line in s.split_inclusive('\n') {
+            if self.line_start {
+                let prefix = self.prefixes.concat();
+                if line == "\n" {
+                    // don't leave trailing whitespace on blank lines
+                    self.writer.write_all(prefix.trim_end().as_bytes())?;
+                } else {
+                    self.writer.write_all(prefix.as_bytes())?;
+                }
+            }
+            self.writer.write_all(line.as_bytes())?;
+            self.line_start = line.ends_with('\n');
+        }
+        self.started = true;
+        self.container_fresh = false;
+        Ok(())
+    }
+
+    /// Writes text, escaped unless it is code
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - text to write
+    fn write_text(&mut self, s: &str) -> io::Result<()> {
+        if self.in_code_block {
+            self.write(s)
+        } else {
+            let in_cell = self.table_cells.is_some();
+            let escaped = escape_text(s, self.line_start && !in_cell);
+            self.write(&escaped)
+        }
+    }
+
+    /// Gets ready to write a new block, ending the current line and separating it from the
+    /// previous block if necessary
+    fn block_start(&mut self) -> io::Result<()> {
+        if self.container_fresh {
+            self.container_fresh = false;
+            self.blank_pending = false;
+            return Ok(());
+        }
+        if self.started && !self.line_start {
+            self.write("\n")?;
+        }
+        if self.started && self.blank_pending {
+            self.write("\n")?;
+        }
+        self.blank_pending = false;
+        Ok(())
+    }
+
+    /// Marks the end of a block, so the next block is separated by a blank line
+    fn block_end(&mut self) {
+        self.blank_pending = true;
+    }
+
+    /// Writes an inline code span, using enough backticks to fence the content
+    ///
+    /// # Arguments
+    ///
+    /// * `code` - the code
+    fn write_code(&mut self, code: &str) -> io::Result<()> {
+        let fence = "`".repeat(longest_backtick_run(code) + 1);
+        let padding = if code.starts_with('`') || code.ends_with('`') {
+            " "
+        } else {
+            ""
+        };
+        let code = if self.table_cells.is_some() {
+            code.replace('|', "\\|")
+        } else {
+            code.to_string()
+        };
+        self.write(&format!("{fence}{padding}{code}{padding}{fence}"))
+    }
+
+    /// Writes the opening delimiter of emphasis, strong or strikethrough text. Markdown doesn't
+    /// allow whitespace just inside the delimiters, so it is moved outside of them, and a run
+    /// that is only whitespace is written without them. The closing delimiter is written when
+    /// the run ends.
+    ///
+    /// # Arguments
+    ///
+    /// * `delimiter` - the delimiter for the kind of mark
+    fn start_mark(&mut self, delimiter: &str) -> io::Result<()> {
+        // read ahead to the end of the run, joining text that pulldown-cmark split up
+        let mut events: Vec<Event<'a>> = vec![];
+        let mut depth = 1;
+        while depth > 0 {
+            let Some(event) = self.next_event() else {
+                break;
+            };
+            match &event {
+                Event::Start(_) => depth += 1,
+                Event::End(_) => depth -= 1,
+                _ => (),
+            }
+            match (events.last_mut(), event) {
+                (Some(Event::Text(text)), Event::Text(more)) => {
+                    *text = format!("{}{}", text, more).into();
+                }
+                (_, event) => events.push(event),
+            }
+        }
+        let end = if depth == 0 { events.pop() } else { None };
+        let is_mark = |event: &Event| {
+            matches!(
+                event,
+                Event::Start(Tag::Emphasis | Tag::Strong | Tag::Strikethrough)
+                    | Event::End(TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough)
+            )
+        };
+        let blank = |event: &Event| matches!(event, Event::Text(text) if text.trim().is_empty());
+        if events.iter().all(|event| is_mark(event) || blank(event)) {
+            let text: String = events
+                .iter()
+                .filter_map(|event| match event {
+                    Event::Text(text) => Some(text.as_ref()),
+                    _ => None,
+                })
+                .collect();
+            return self.write(&text);
+        }
+
+        let mut leading = String::new();
+        if let Some(Event::Text(text)) = events.iter_mut().find(|event| !is_mark(event)) {
+            let trimmed = text.trim_start();
+            leading = text[..text.len() - trimmed.len()].to_string();
+            *text = trimmed.to_string().into();
+        }
+        let mut trailing = String::new();
+        if let Some(Event::Text(text)) = events.iter_mut().rev().find(|event| !is_mark(event)) {
+            let trimmed = text.trim_end();
+            trailing = text[trimmed.len()..].to_string();
+            *text = trimmed.to_string().into();
+        }
+        self.write(&leading)?;
+        self.write(delimiter)?;
+        // the rest of the run is handled as usual, followed by the whitespace moved out of it
+        let trailing = (!trailing.is_empty()).then(|| Event::Text(trailing.into()));
+        for event in events.into_iter().chain(end).chain(trailing).rev() {
+            self.lookahead.push_front(event);
+        }
+        Ok(())
+    }
+
+    /// Writes the current table row
+    ///
+    /// # Arguments
+    ///
+    /// * `cells` - the row's cells
+    fn write_row(&mut self, cells: &[String]) -> io::Result<()> {
+        self.write(&format!("| {} |\n", cells.join(" | ")))
+    }
+
+    /// Main part of the writer, outputting to underlying `writer`.
+    ///
+    /// Passes start/end tags out to `start_tag` and `end_tag`, respectively.
+    /// Writes out the rest of the inline content as necessary.
+    fn run(&mut self) -> io::Result<()> {
+        while let Some(event) = self.next_event() {
+            let in_html_block = std::mem::replace(&mut self.in_html_block, false);
+            match event {
+                Event::Start(tag) => self.start_tag(tag)?,
+                Event::End(tag) => self.end_tag(tag)?,
+                Event::Text(text) => {
+                    // pulldown-cmark splits text at escapes, but escaping needs to see it all at
+                    // once
+                    let mut text = text.into_string();
+                    while let Some(Event::Text(more)) = self.peek_event() {
+                        text.push_str(more);
+                        self.lookahead.pop_front();
+                    }
+                    self.write_text(&text)?
+                }
+                Event::Code(text) => self.write_code(&text)?,
+                Event::Html(html) => {
+                    // HTML blocks come a line at a time
+                    if !in_html_block {
+                        self.block_start()?;
+                    }
+                    self.write(&html)?;
+                    self.block_end();
+                    self.in_html_block = true;
+                }
+                Event::InlineHtml(html) => self.write(&html)?,
+                Event::SoftBreak => self.write("\n")?,
+                Event::HardBreak => {
+                    if self.table_cells.is_some() {
+                        self.write("<br>")?;
+                    } else {
+                        self.write("\\\n")?;
+                    }
+                }
+                Event::Rule => {
+                    self.block_start()?;
+                    self.write("---")?;
+                    self.block_end();
+                }
+                Event::TaskListMarker(checked) => {
+                    self.write(if checked { "[x] " } else { "[ ] " })?;
+                }
+                Event::FootnoteReference(name) => self.write(&format!("[^{}]", name))?,
+                _ => (),
+            }
+        }
+        if self.started && !self.line_start {
+            self.write("\n")?;
+        }
+
+        Ok(())
+    }
+
+    /// Handles opening tags
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - tag to open
+    fn start_tag(&mut self, tag: Tag<'a>) -> io::Result<()> {
+        match tag {
+            Tag::Paragraph => self.block_start(),
+            Tag::Heading { level, .. } => {
+                self.block_start()?;
+                self.write(&format!("{} ", "#".repeat(level as usize)))
+            }
+            Tag::BlockQuote(kind) => {
+                self.block_start()?;
+                self.prefixes.push("> ".to_string());
+                if let Some(kind) = kind {
+                    let name = match kind {
+                        BlockQuoteKind::Note => "NOTE",
+                        BlockQuoteKind::Tip => "TIP",
+                        BlockQuoteKind::Important => "IMPORTANT",
+                        BlockQuoteKind::Warning => "WARNING",
+                        BlockQuoteKind::Caution => "CAUTION",
+                    };
+                    self.write(&format!("[!{}]\n", name))?;
+                }
+                self.container_fresh = true;
+                Ok(())
+            }
+            Tag::MetadataBlock(kind) => {
+                self.block_start()?;
+                // the front matter is written as it is, like code
+                self.in_code_block = true;
+                self.write(front_matter_fence(kind))?;
+                self.write("\n")
+            }
+            Tag::CodeBlock(kind) => {
+                self.block_start()?;
+                let lang = match kind {
+                    CodeBlockKind::Fenced(lang) => lang.to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                // the fence has to be longer than any run of backticks in the code, so the code
+                // is read before anything is written
+                let mut code = String::new();
+                while let Some(event) = self.next_event() {
+                    match event {
+                        Event::Text(text) => code.push_str(&text),
+                        _ => break,
+                    }
+                }
+                let fence = "`".repeat(longest_backtick_run(&code).max(2) + 1);
+                self.write(&format!("{}{}\n", fence, lang))?;
+                self.write(&code)?;
+                if !code.is_empty() && !code.ends_with('\n') {
+                    self.write("\n")?;
+                }
+                self.write(&fence)?;
+                self.block_end();
+                Ok(())
+            }
+            Tag::List(first_number) => {
+                // nested lists stay attached to their item
+                if self.list_stack.is_empty() || !self.line_start {
+                    self.block_start()?;
+                }
+                self.list_stack.push(first_number);
+                Ok(())
+            }
+            Tag::Item => {
+                self.block_start()?;
+                let marker = match self.list_stack.last_mut() {
+                    Some(Some(number)) => {
+                        let marker = format!("{}. ", number);
+                        *number += 1;
+                        marker
+                    }
+                    _ => "* ".to_string(),
+                };
+                self.write(&marker)?;
+                self.prefixes.push(" ".repeat(marker.len()));
+                self.container_fresh = true;
+                Ok(())
+            }
+            Tag::Table(alignments) => {
+                self.block_start()?;
+                self.table_alignments = alignments;
+                self.table_cells = Some(vec![]);
+                Ok(())
+            }
+            Tag::TableHead | Tag::TableRow => {
+                self.table_cells = Some(vec![]);
+                Ok(())
+            }
+            Tag::TableCell => {
+                if let Some(cells) = self.table_cells.as_mut() {
+                    cells.push(String::new());
+                }
+                Ok(())
+            }
+            Tag::Emphasis => self.start_mark("_"),
+            Tag::Strong => self.start_mark("**"),
+            Tag::Strikethrough => self.start_mark("~~"),
+            Tag::Link {
+                dest_url, title, ..
+            } => {
+                self.link_stack
+                    .push((dest_url.to_string(), title.to_string()));
+                self.write("[")
+            }
+            Tag::Image {
+                dest_url, title, ..
+            } => {
+                self.link_stack
+                    .push((dest_url.to_string(), title.to_string()));
+                self.write("![")
+            }
+            Tag::FootnoteDefinition(name) => {
+                self.block_start()?;
+                self.write(&format!("[^{}]: ", name))?;
+                self.container_fresh = true;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Handles closing tags
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - tag to close
+    fn end_tag(&mut self, tag: TagEnd) -> io::Result<()> {
+        match tag {
+            TagEnd::Paragraph | TagEnd::Heading(..) | TagEnd::FootnoteDefinition => {
+                self.block_end();
+                Ok(())
+            }
+            TagEnd::BlockQuote => {
+                self.prefixes.pop();
+                self.block_end();
+                Ok(())
+            }
+            TagEnd::MetadataBlock(kind) => {
+                if !self.line_start {
+                    self.write("\n")?;
+                }
+                self.in_code_block = false;
+                self.write(front_matter_fence(kind))?;
+                self.block_end();
+                Ok(())
+            }
+            TagEnd::List(_) => {
+                self.list_stack.pop();
+                // only separate the list from what follows at the top level
+                self.blank_pending = self.list_stack.is_empty();
+                Ok(())
+            }
+            TagEnd::Item => {
+                self.prefixes.pop();
+                Ok(())
+            }
+            TagEnd::Table => {
+                self.table_cells = None;
+                self.block_end();
+                Ok(())
+            }
+            TagEnd::TableHead => {
+                let cells = self.table_cells.replace(vec![]).unwrap_or_default();
+                let separators: Vec<String> = (0..cells.len())
+                    .map(|i| {
+                        match self.table_alignments.get(i) {
+                            Some(Alignment::Left) => ":---",
+                            Some(Alignment::Center) => ":---:",
+                            Some(Alignment::Right) => "---:",
+                            _ => "---",
+                        }
+                        .to_string()
+                    })
+                    .collect();
+                self.table_cells = None;
+                self.write_row(&cells)?;
+                self.write_row(&separators)?;
+                self.table_cells = Some(vec![]);
+                Ok(())
+            }
+            TagEnd::TableRow => {
+                let cells = self.table_cells.take().unwrap_or_default();
+                self.write_row(&cells)?;
+                self.table_cells = Some(vec![]);
+                Ok(())
+            }
+            TagEnd::Emphasis => self.write("_"),
+            TagEnd::Strong => self.write("**"),
+            TagEnd::Strikethrough => self.write("~~"),
+            TagEnd::Link | TagEnd::Image => {
+                let (url, title) = self.link_stack.pop().unwrap_or_default();
+                let url = if url.contains(' ') {
+                    format!("<{}>", url)
+                } else {
+                    url
+                };
+                if title.is_empty() {
+                    self.write(&format!("]({})", url))
+                } else {
+                    self.write(&format!("]({} \"{}\")", url, title.replace('"', "\\\"")))
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes CommonMark/GFM output
+///
+/// # Arguments
+///
+/// * `writer` - something implementing the Write trait
+/// * `iter` - an iterator of Events, from pulldown-cmark or one of the importers
+///
+/// # Returns
+///
+/// * `Result` - if the MarkdownWriter wrote successfully to `writer`
+pub fn write<'a, I, W>(writer: W, iter: I) -> io::Result<()>
+where
+    I: Iterator<Item = Event<'a>>,
+    W: Write,
+{
+    MarkdownWriter::new(iter, writer).run()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Normalizes markdown by parsing and writing it again
+    fn render(input: &str) -> String {
+        let mut output = Vec::new();
+        assert!(write(&mut output, Parser::new_ext(input, Options::all())).is_ok());
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_blocks() {
+        let input = "# title\n\ntext\n\n---\n\n```rust\nfn main() {}\n```\n";
+        assert_eq!(input, render(input));
+    }
+
+    #[test]
+    fn test_code_block_fence() {
+        // the fence is longer than the backticks in the code
+        let input = "````markdown\n```rust\nfn main() {}\n```\n````\n";
+        assert_eq!(input, render(input));
+        assert_eq!("```\n```\n", render("```\n```"));
+    }
+
+    #[test]
+    fn test_lists() {
+        let input = "* a\n* b\n  1. c\n  2. d\n\n3. x\n4. y\n";
+        assert_eq!(input, render(input));
+    }
+
+    #[test]
+    fn test_quotes() {
+        let input = "> [!TIP]\n> quoted\n>\n> > nested\n";
+        assert_eq!(input, render(input));
+    }
+
+    #[test]
+    fn test_table() {
+        let input = "| a | b |\n| :---: | --- |\n| `c\\|d` | **e** |\n";
+        assert_eq!(input, render(input));
+    }
+
+    #[test]
+    fn test_escaping() {
+        assert_eq!(
+            "\\*not bold\\* a_b \\[x\\]\n",
+            render("\\*not bold\\* a_b \\[x\\]")
+        );
+        assert_eq!("\\# not a heading\n", render("\\# not a heading"));
+        assert_eq!("1\\. not a list\n", render("1\\. not a list"));
+        assert_eq!("12\\) not a list\n", render("12\\) not a list"));
+    }
+
+    #[test]
+    fn test_inline() {
+        assert_eq!(
+            "_a_ **b** ~~c~~ ``d`e`` [f](g \"h\") ![i](j)\n",
+            render("*a* __b__ ~~c~~ ``d`e`` [f](g \"h\") ![i](j)")
+        );
+    }
+
+    #[test]
+    fn test_mark_whitespace() {
+        let text = |s: &'static str| Event::Text(s.into());
+        let events = vec![
+            Event::Start(Tag::Paragraph),
+            text("a"),
+            Event::Start(Tag::Strong),
+            text(" b "),
+            Event::End(TagEnd::Strong),
+            text("c"),
+            Event::Start(Tag::Emphasis),
+            Event::Start(Tag::Strikethrough),
+            text(" d"),
+            Event::End(TagEnd::Strikethrough),
+            text("e "),
+            Event::End(TagEnd::Emphasis),
+            Event::Start(Tag::Strong),
+            text(" "),
+            Event::End(TagEnd::Strong),
+            text("*lit*"),
+            Event::Start(Tag::Strong),
+            text(" "),
+            Event::End(TagEnd::Strong),
+            Event::End(TagEnd::Paragraph),
+        ];
+        let mut output = Vec::new();
+        assert!(write(&mut output, events.into_iter()).is_ok());
+        assert_eq!(
+            "a **b** c _~~d~~e_  \\*lit\\* \n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+}
@@ -0,0 +1,342 @@
+use pulldown_cmark::BlockQuoteKind;
+use std::collections::HashMap;
+
+use crate::atlassian::make_escape_list;
+use crate::dialect::Flavor;
+use crate::languages::Languages;
+use crate::links::normalize_path;
+
+/// What kind of document to read
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum InputFormat {
+    /// CommonMark/GFM
+    #[default]
+    Markdown,
+    /// Jira/Confluence wiki markup. Both flavors are understood
+    Wiki,
+    /// Atlassian Document Format (ADF) JSON, as returned by the Jira Cloud v3 REST API
+    Adf,
+    /// Confluence storage format (XHTML), as returned by the Confluence REST API
+    Storage,
+}
+
+/// What kind of document to produce
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OutputFormat {
+    /// Jira/Confluence wiki markup, spelled according to the flavor
+    #[default]
+    Wiki,
+    /// Confluence storage format (XHTML), as accepted by the Confluence REST API
+    Storage,
+    /// Atlassian Document Format (ADF) JSON, as accepted by the Jira Cloud v3 REST API
+    Adf,
+    /// CommonMark/GFM, for converting Atlassian content back to markdown
+    Markdown,
+}
+
+/// Options controlling how Markdown is rendered to Atlassian markup
+///
+/// Built with chained setters, starting from `RenderOptions::new()`, which has the same defaults
+/// as the CLI.
+#[derive(Clone, Debug)]
+pub struct RenderOptions {
+    pub(crate) flavor: Flavor,
+    pub(crate) format: OutputFormat,
+    pub(crate) input: InputFormat,
+    pub(crate) modify_headers: i8,
+    pub(crate) toc: bool,
+    // the language table, whose map for the flavor is built when rendering
+    pub(crate) languages: Languages,
+    // mappings added on top of the language table's
+    pub(crate) lang_map: HashMap<String, String>,
+    pub(crate) fallback_language: String,
+    pub(crate) escape_map: HashMap<String, String>,
+    pub(crate) alert_map: HashMap<BlockQuoteKind, String>,
+    pub(crate) page_title: Option<String>,
+    pub(crate) base_url: Option<String>,
+    pub(crate) path: Option<String>,
+    pub(crate) page_map: HashMap<String, String>,
+    pub(crate) title_heading: bool,
+    pub(crate) front_matter_table: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        let languages = Languages::default();
+        RenderOptions {
+            flavor: Flavor::default(),
+            format: OutputFormat::default(),
+            input: InputFormat::default(),
+            modify_headers: 0,
+            toc: false,
+            fallback_language: languages.fallback().to_string(),
+            languages,
+            lang_map: HashMap::new(),
+            escape_map: make_escape_list(),
+            alert_map: HashMap::new(),
+            page_title: None,
+            base_url: None,
+            path: None,
+            page_map: HashMap::new(),
+            title_heading: false,
+            front_matter_table: false,
+        }
+    }
+}
+
+impl RenderOptions {
+    /// return RenderOptions with the default flavor, language map and escape rules
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the Atlassian flavor to render
+    ///
+    /// # Arguments
+    ///
+    /// * `flavor` - jira or confluence
+    pub fn flavor(mut self, flavor: Flavor) -> Self {
+        self.flavor = flavor;
+        self
+    }
+
+    /// Sets the kind of document to produce
+    ///
+    /// # Arguments
+    ///
+    /// * `format` - wiki markup, storage format, ADF or markdown
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets the kind of document to read
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - markdown, wiki markup or ADF
+    pub fn input(mut self, input: InputFormat) -> Self {
+        self.input = input;
+        self
+    }
+
+    /// Sets the header offset
+    ///
+    /// # Arguments
+    ///
+    /// * `modify_headers` - add N to header level (can be negative)
+    pub fn modify_headers(mut self, modify_headers: i8) -> Self {
+        self.modify_headers = modify_headers;
+        self
+    }
+
+    /// Sets if TOC markup should be prepended
+    ///
+    /// # Arguments
+    ///
+    /// * `toc` - prepend TOC markup
+    pub fn toc(mut self, toc: bool) -> Self {
+        self.toc = toc;
+        self
+    }
+
+    /// Replaces the whole language map, including the built-in language table
+    ///
+    /// # Arguments
+    ///
+    /// * `lang_map` - from markdown to Atlassian-supported code block langs
+    pub fn lang_map(mut self, lang_map: HashMap<String, String>) -> Self {
+        self.languages = Languages::empty();
+        self.lang_map = lang_map;
+        self
+    }
+
+    /// Adds (or overrides) a single language mapping
+    ///
+    /// # Arguments
+    ///
+    /// * `alias` - the markdown code block language
+    /// * `language` - the Atlassian code block language it maps to
+    pub fn language(mut self, alias: &str, language: &str) -> Self {
+        self.lang_map
+            .insert(alias.to_string(), language.to_string());
+        self
+    }
+
+    /// Replaces the language map and fallback with a language table's. The table's map is built
+    /// for whichever flavor is rendered
+    ///
+    /// # Arguments
+    ///
+    /// * `languages` - the language table, like the built-in one merged with a user's file
+    pub fn languages(mut self, languages: &Languages) -> Self {
+        self.languages = languages.clone();
+        self.lang_map = HashMap::new();
+        self.fallback_language = languages.fallback().to_string();
+        self
+    }
+
+    /// Sets the language code blocks are highlighted as when theirs isn't in the language map
+    ///
+    /// # Arguments
+    ///
+    /// * `fallback` - an Atlassian code block language, like `text` or `none`
+    pub fn fallback_language(mut self, fallback: &str) -> Self {
+        self.fallback_language = fallback.to_string();
+        self
+    }
+
+    /// Replaces the whole escape list
+    ///
+    /// # Arguments
+    ///
+    /// * `escape_map` - from original character to escaped sequence
+    pub fn escape_map(mut self, escape_map: HashMap<String, String>) -> Self {
+        self.escape_map = escape_map;
+        self
+    }
+
+    /// Adds (or overrides) a single escape rule
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - the original sequence
+    /// * `to` - the escaped sequence
+    pub fn escape(mut self, from: &str, to: &str) -> Self {
+        self.escape_map.insert(from.to_string(), to.to_string());
+        self
+    }
+
+    /// Replaces the whole alert map. Kinds that aren't in the map use the flavor's default macro
+    ///
+    /// # Arguments
+    ///
+    /// * `alert_map` - from GitHub alert kind to the opening macro, without braces
+    pub fn alert_map(mut self, alert_map: HashMap<BlockQuoteKind, String>) -> Self {
+        self.alert_map = alert_map;
+        self
+    }
+
+    /// Overrides the macro a single kind of GitHub alert is rendered as
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - the kind of alert, like `> [!NOTE]`
+    /// * `alert_macro` - the opening macro without braces, like `info` or `panel:title=Heads up`.
+    ///   The closing macro is the part before any parameters
+    pub fn alert(mut self, kind: BlockQuoteKind, alert_macro: &str) -> Self {
+        self.alert_map.insert(kind, alert_macro.to_string());
+        self
+    }
+
+    /// Sets the title of the Confluence page being written. In-page links then point at the
+    /// anchors Confluence generates for headings, instead of `{anchor}` macros written at each
+    /// heading. Jira doesn't generate heading anchors, so it's ignored for Jira
+    ///
+    /// # Arguments
+    ///
+    /// * `page_title` - the page title, exactly as it is in Confluence
+    pub fn page_title(mut self, page_title: &str) -> Self {
+        self.page_title = Some(page_title.to_string());
+        self
+    }
+
+    /// Sets the URL of the repository root, like its tree URL, which relative links are joined
+    /// onto once they're resolved from the document's path. Links in the page map go to their
+    /// page instead
+    ///
+    /// # Arguments
+    ///
+    /// * `base_url` - the URL of the repository root
+    pub fn base_url(mut self, base_url: &str) -> Self {
+        self.base_url = Some(base_url.to_string());
+        self
+    }
+
+    /// Sets the document's path relative to the repository root, like `docs/guide/setup.md`, so
+    /// relative links in it can be resolved to repository paths. Without it, links are taken as
+    /// relative to the root
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the document's path, with `/` separators
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(normalize_path(path));
+        self
+    }
+
+    /// Replaces the whole page map
+    ///
+    /// # Arguments
+    ///
+    /// * `page_map` - from markdown path, relative to the repository root, to Confluence page
+    ///   title
+    pub fn page_map(mut self, page_map: HashMap<String, String>) -> Self {
+        self.page_map = page_map
+            .into_iter()
+            .map(|(path, title)| (normalize_path(&path), title))
+            .collect();
+        self
+    }
+
+    /// Adds (or overrides) the Confluence page a single markdown file was published as
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the markdown path, relative to the repository root
+    /// * `title` - the Confluence page title
+    pub fn page(mut self, path: &str, title: &str) -> Self {
+        self.page_map
+            .insert(normalize_path(path), title.to_string());
+        self
+    }
+
+    /// Sets if the front matter `title` should be written as an `h1` at the start of the
+    /// document. Otherwise it is only the page title
+    ///
+    /// # Arguments
+    ///
+    /// * `title_heading` - write the title as a heading
+    pub fn title_heading(mut self, title_heading: bool) -> Self {
+        self.title_heading = title_heading;
+        self
+    }
+
+    /// Sets if the front matter should be written as a page properties table at the start of the
+    /// document, with a column for each key. Front matter with `details: true` is written as one
+    /// either way
+    ///
+    /// # Arguments
+    ///
+    /// * `front_matter_table` - write the front matter as a table
+    pub fn front_matter_table(mut self, front_matter_table: bool) -> Self {
+        self.front_matter_table = front_matter_table;
+        self
+    }
+
+    /// The language map for the flavor: the language table's, with any single mappings on top
+    ///
+    /// # Returns
+    ///
+    /// * `HashMap<String, String>` - from markdown to Atlassian-supported code block langs
+    pub(crate) fn language_map(&self) -> HashMap<String, String> {
+        let mut lang_map = self.languages.lang_map(self.flavor);
+        lang_map.extend(
+            self.lang_map
+                .iter()
+                .map(|(alias, language)| (alias.clone(), language.clone())),
+        );
+        lang_map
+    }
+
+    /// The opening macro for a kind of GitHub alert, without braces
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - the kind of alert
+    pub(crate) fn alert_macro(&self, kind: BlockQuoteKind) -> &str {
+        self.alert_map
+            .get(&kind)
+            .map_or_else(|| self.flavor.dialect().alert_macro(kind), String::as_str)
+    }
+}
@@ -0,0 +1,199 @@
+use crate::escape::escape_macro_parameter;
+
+/// A page properties directive: an HTML comment on its own, like `<!-- details id=decision -->`
+/// before a table, or `<!-- detailssummary label=decision -->`. Markdown renderers hide it, and
+/// Confluence gets the macro it names
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Directive {
+    /// The next table is a page properties (`details`) macro, with these parameters
+    Details(Vec<(String, String)>),
+    /// A page properties report (`detailssummary`) macro, with these parameters
+    Report(Vec<(String, String)>),
+}
+
+/// Reads the parameters of a directive, like `id=decision label="a b" hidden`
+///
+/// # Arguments
+///
+/// * `text` - the parameters
+///
+/// # Returns
+///
+/// * `Vec<(String, String)>` - the parameters, in order. One without a value is `true`
+fn parse_params(text: &str) -> Vec<(String, String)> {
+    let mut params = vec![];
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let key_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let key = rest[..key_end].to_string();
+        rest = &rest[key_end..];
+        let value = match rest.strip_prefix('=') {
+            Some(value) => {
+                let (value, after) = match value.chars().next() {
+                    Some(quote @ ('"' | '\'')) => {
+                        let value = &value[1..];
+                        // an unterminated quote runs to the end
+                        let end = value.find(quote).unwrap_or(value.len());
+                        (&value[..end], value.get(end + 1..).unwrap_or(""))
+                    }
+                    _ => {
+                        let end = value.find(char::is_whitespace).unwrap_or(value.len());
+                        (&value[..end], &value[end..])
+                    }
+                };
+                rest = after;
+                value.to_string()
+            }
+            None => "true".to_string(),
+        };
+        if !key.is_empty() {
+            params.push((key, value));
+        }
+        rest = rest.trim_start();
+    }
+    params
+}
+
+impl Directive {
+    /// Reads a directive from block HTML
+    ///
+    /// # Arguments
+    ///
+    /// * `html` - the HTML
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Directive>` - the directive, if the HTML is only a comment holding one
+    pub(crate) fn parse(html: &str) -> Option<Self> {
+        let comment = html.trim().strip_prefix("<!--")?.strip_suffix("-->")?;
+        if comment.contains("-->") {
+            return None;
+        }
+        let comment = comment.trim();
+        let (name, params) = comment
+            .split_once(char::is_whitespace)
+            .unwrap_or((comment, ""));
+        match name {
+            "details" => Some(Directive::Details(parse_params(params))),
+            "detailssummary" => Some(Directive::Report(parse_params(params))),
+            _ => None,
+        }
+    }
+
+    /// The name of the Confluence macro
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Directive::Details(_) => "details",
+            Directive::Report(_) => "detailssummary",
+        }
+    }
+
+    /// The parameters of the macro, in order
+    pub(crate) fn params(&self) -> &[(String, String)] {
+        match self {
+            Directive::Details(params) | Directive::Report(params) => params,
+        }
+    }
+
+    /// Writes the opening wiki macro, like `{details:id=decision}`
+    pub(crate) fn wiki_macro(&self) -> String {
+        let params: Vec<String> = self
+            .params()
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, escape_macro_parameter(value)))
+            .collect();
+        if params.is_empty() {
+            format!("{{{}}}", self.name())
+        } else {
+            format!("{{{}:{}}}", self.name(), params.join("|"))
+        }
+    }
+
+    /// Writes the directive as an HTML comment, on its own line
+    pub(crate) fn to_html(&self) -> String {
+        let mut html = format!("<!-- {}", self.name());
+        for (key, value) in self.params() {
+            let quote = if value.contains('"') { "'" } else { "\"" };
+            if value.is_empty() || value.contains(char::is_whitespace) || value.contains(quote) {
+                html.push_str(&format!(" {}={}{}{}", key, quote, value, quote));
+            } else {
+                html.push_str(&format!(" {}={}", key, value));
+            }
+        }
+        html.push_str(" -->\n");
+        html
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Some(Directive::Details(params(&[("id", "decision")]))),
+            Directive::parse("<!-- details id=decision -->\n")
+        );
+        assert_eq!(
+            Some(Directive::Details(vec![])),
+            Directive::parse("<!--details-->")
+        );
+        assert_eq!(
+            Some(Directive::Report(params(&[
+                ("cql", "label = 'adr'"),
+                ("headings", "Status,Owner"),
+                ("showLastModified", "true"),
+            ]))),
+            Directive::parse(
+                "<!-- detailssummary cql=\"label = 'adr'\" headings=Status,Owner showLastModified -->"
+            )
+        );
+        assert_eq!(None, Directive::parse("<!-- a comment about details -->"));
+        assert_eq!(
+            None,
+            Directive::parse("<!-- details --> <p>more</p> <!-- -->")
+        );
+        assert_eq!(None, Directive::parse("<p>details</p>"));
+    }
+
+    #[test]
+    fn test_write() {
+        let directive = Directive::Report(params(&[
+            ("cql", "label = 'adr'"),
+            ("headings", "Status,Owner"),
+        ]));
+        assert_eq!(
+            "{detailssummary:cql=label = 'adr'|headings=Status,Owner}",
+            directive.wiki_macro()
+        );
+        assert_eq!(
+            "<!-- detailssummary cql=\"label = 'adr'\" headings=Status,Owner -->\n",
+            directive.to_html()
+        );
+        assert_eq!(
+            Some(directive.clone()),
+            Directive::parse(&directive.to_html())
+        );
+        assert_eq!("{details}", Directive::Details(vec![]).wiki_macro());
+    }
+
+    #[test]
+    fn test_write_escaped() {
+        // a pipe or closing brace in a value would end the parameter or the macro
+        let directive = Directive::Report(params(&[("cql", "label = adr|x"), ("id", "{a}")]));
+        assert_eq!(
+            "{detailssummary:cql=label = adr&#124;x|id={a&#125;}",
+            directive.wiki_macro()
+        );
+    }
+}
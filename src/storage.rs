@@ -0,0 +1,2352 @@
+use ego_tree::NodeRef;
+use pulldown_cmark::*;
+use scraper::{Html, Node};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Write};
+
+use crate::anchor::Anchors;
+use crate::atlassian::{invert_lang_map, macro_name, ALERT_KINDS};
+use crate::footnotes::{self, Footnotes};
+use crate::html::{self, DetailsPart, HtmlBuffer, Markup};
+use crate::image::Image;
+use crate::languages::LanguageMapper;
+use crate::links::{Resolver, Target};
+use crate::options::RenderOptions;
+use crate::properties::Directive;
+
+/// Escapes text so it is safe inside XHTML elements and attribute values
+///
+/// # Arguments
+///
+/// * `s` - string to escape
+///
+/// # Returns
+///
+/// * `String` - string with `&`, `<`, `>` and `"` replaced with entities
+pub(crate) fn escape_xml(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => r.push_str("&amp;"),
+            '<' => r.push_str("&lt;"),
+            '>' => r.push_str("&gt;"),
+            '"' => r.push_str("&quot;"),
+            _ => r.push(c),
+        }
+    }
+    r
+}
+
+/// Writes an anchor macro, which `ac:anchor` links jump to
+///
+/// # Arguments
+///
+/// * `name` - the anchor name
+///
+/// # Returns
+///
+/// * `String` - the `anchor` structured macro
+fn anchor_macro(name: &str) -> String {
+    format!(
+        "<ac:structured-macro ac:name=\"anchor\">\
+         <ac:parameter ac:name=\"\">{}</ac:parameter></ac:structured-macro>",
+        escape_xml(name)
+    )
+}
+
+/// Writes a link to an anchor on the same page
+///
+/// # Arguments
+///
+/// * `anchor` - the anchor name
+/// * `body` - the link text, which is escaped
+///
+/// # Returns
+///
+/// * `String` - the `ac:link` element
+fn anchor_link(anchor: &str, body: &str) -> String {
+    format!(
+        r#"<ac:link ac:anchor="{}"><ac:link-body>{}</ac:link-body></ac:link>"#,
+        escape_xml(anchor),
+        escape_xml(body)
+    )
+}
+
+/// The XHTML tags for an HTML element that wraps its content, by the wiki markup it becomes
+///
+/// # Arguments
+///
+/// * `open` - the opening wiki markup, like `^` for `<sup>`
+///
+/// # Returns
+///
+/// * `(&str, &str)` - the opening and closing tags
+fn wrap_tags(open: &str) -> (&'static str, &'static str) {
+    match open {
+        "*" => ("<strong>", "</strong>"),
+        "_" => ("<em>", "</em>"),
+        "??" => ("<cite>", "</cite>"),
+        "+" => ("<u>", "</u>"),
+        "-" => (
+            r#"<span style="text-decoration: line-through;">"#,
+            "</span>",
+        ),
+        "^" => ("<sup>", "</sup>"),
+        "~" => ("<sub>", "</sub>"),
+        _ => ("<code>", "</code>"),
+    }
+}
+
+/// Escapes text so it is safe inside a CDATA section
+///
+/// # Arguments
+///
+/// * `s` - string to escape
+///
+/// # Returns
+///
+/// * `String` - string with `]]>` split across two CDATA sections
+fn escape_cdata(s: &str) -> String {
+    s.replace("]]>", "]]]]><![CDATA[>")
+}
+
+/// The StorageWriter takes events from pulldown-cmark and formats it into Confluence storage
+/// format (XHTML)
+struct StorageWriter<'a, I, W> {
+    iter: I,
+    writer: W,
+    // if we're on a table header cell
+    table_header: bool,
+    // column alignments of the current table
+    table_alignments: Vec<Alignment>,
+    // index of the current table cell in its row
+    table_cell_index: usize,
+    // map between markdown/confluence code block langs
+    languages: LanguageMapper,
+    // add modify_headers to header level
+    modify_headers: i8,
+    // if the current content should be output. Skips headers whose level is <= 0
+    should_output_line: bool,
+    // closing tag of the current heading, which may have become a paragraph
+    heading_close: &'static str,
+    // anchors for headings, and where `#fragment` links to them point
+    anchors: Anchors,
+    // where relative links and images point
+    resolver: Resolver,
+    // how many headings have been started, to find their anchors
+    heading_count: usize,
+    // closing tag of the current link, which is a macro for links to headings
+    link_close: &'static str,
+    // code block content goes into CDATA, so it must not be XML escaped
+    in_code_block: bool,
+    // the current image, whose alt text comes as text events before the end tag
+    image: Option<Image>,
+    // block HTML that is parsed once its tags are balanced
+    html_buffer: HtmlBuffer,
+    // `<details>` that have been opened in one HTML block, to be closed in a later one
+    open_details: usize,
+    // HTML elements that have been warned about, so each is only reported once
+    unknown_html: HashSet<String>,
+    // inline HTML elements that are open, innermost last: the element name, how many markdown
+    // tags were open around it, and its closing tag, which is `None` for a link
+    inline_html: Vec<(String, usize, Option<&'static str>)>,
+    // how many markdown tags are open
+    tag_depth: usize,
+    // the page properties macro the next table is written in, from a directive before it
+    properties: Option<Directive>,
+    // if the current table is in a page properties macro
+    properties_table: bool,
+    // opening macros for each kind of github alert
+    alert_map: HashMap<BlockQuoteKind, String>,
+    // if each blockquote we're in was written as a macro
+    quote_stack: Vec<bool>,
+    // if each list we're in was written as a task list
+    list_stack: Vec<bool>,
+    // if a task just started, so a paragraph around its marker is left out
+    task_start: bool,
+    // if the paragraph around a task's marker was left out, so its end is too
+    task_paragraph: bool,
+    // last task id, since every task needs a unique one
+    task_id: usize,
+    // events that have been looked ahead at, to be handled before the rest of `iter`
+    lookahead: VecDeque<Event<'a>>,
+    // footnote references and their rendered definitions
+    footnotes: Footnotes<String>,
+    // the footnote definition being rendered, which is written at the end instead of in place
+    footnote_buffer: Option<(String, String)>,
+}
+
+impl<'a, I, W> StorageWriter<'a, I, W>
+where
+    I: Iterator<Item = Event<'a>>,
+    W: Write,
+{
+    /// return a new StorageWriter
+    ///
+    /// # Arguments
+    ///
+    /// * `iter` - iterator of elements provided by `pulldowm_cmark`
+    /// * `writer` - something implementing Write to write output to
+    /// * `options` - language map and header offset to render with
+    /// * `anchors` - anchors for the document's headings
+    fn new(iter: I, writer: W, options: &RenderOptions, anchors: Anchors) -> Self {
+        StorageWriter {
+            iter,
+            writer,
+            table_header: false,
+            table_alignments: vec![],
+            table_cell_index: 0,
+            languages: LanguageMapper::new(options),
+            modify_headers: options.modify_headers,
+            should_output_line: true,
+            heading_close: "",
+            anchors,
+            resolver: Resolver::new(options),
+            heading_count: 0,
+            link_close: "</a>",
+            in_code_block: false,
+            image: None,
+            html_buffer: HtmlBuffer::default(),
+            open_details: 0,
+            unknown_html: HashSet::new(),
+            inline_html: vec![],
+            tag_depth: 0,
+            properties: None,
+            properties_table: false,
+            alert_map: ALERT_KINDS
+                .into_iter()
+                .map(|kind| (kind, options.alert_macro(kind).to_string()))
+                .collect(),
+            quote_stack: vec![],
+            list_stack: vec![],
+            task_start: false,
+            task_paragraph: false,
+            task_id: 0,
+            lookahead: VecDeque::new(),
+            footnotes: Footnotes::default(),
+            footnote_buffer: None,
+        }
+    }
+
+    /// Writes `s` to underlying `writer`, if it should write.
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - string to write
+    fn write(&mut self, s: &str) -> io::Result<()> {
+        if self.should_output_line {
+            match &mut self.footnote_buffer {
+                Some((_, buffer)) => {
+                    buffer.push_str(s);
+                    Ok(())
+                }
+                None => self.writer.write_all(s.as_bytes()),
+            }
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Writes text content, escaped for where it is going
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - text to write
+    fn write_text(&mut self, s: &str) -> io::Result<()> {
+        if let Some(image) = self.image.as_mut() {
+            image.alt.push_str(s);
+            Ok(())
+        } else if self.in_code_block {
+            self.write(&escape_cdata(s))
+        } else {
+            self.write(&escape_xml(s))
+        }
+    }
+
+    /// Collects the text content of an HTML node and its children
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - node to collect text from
+    /// * `text` - where to collect the text
+    fn collect_text(node: NodeRef<Node>, text: &mut String) {
+        for descendant in node.descendants() {
+            if let Node::Text(t) = descendant.value() {
+                text.push_str(t.trim_start_matches('\n').trim_start_matches(' '));
+            }
+        }
+    }
+
+    /// Writes an image, with its size, alignment, alt text and title as attributes
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - the image to write
+    fn write_image(&mut self, image: &Image) -> io::Result<()> {
+        let source = match self.resolver.image(&image.source) {
+            Target::Attachment(name) => {
+                format!(r#"<ri:attachment ri:filename="{}" />"#, escape_xml(&name))
+            }
+            Target::Url(url) | Target::Page { title: url, .. } => {
+                format!(r#"<ri:url ri:value="{}" />"#, escape_xml(&url))
+            }
+        };
+        let mut attributes = String::new();
+        if let Some(align) = image.align {
+            attributes.push_str(&format!(r#" ac:align="{}""#, align.as_str()));
+        }
+        if let Some(width) = image.width {
+            attributes.push_str(&format!(r#" ac:width="{}""#, width));
+        }
+        if let Some(height) = image.height {
+            attributes.push_str(&format!(r#" ac:height="{}""#, height));
+        }
+        attributes.push_str(&format!(r#" ac:alt="{}""#, escape_xml(&image.alt)));
+        if !image.title.is_empty() {
+            attributes.push_str(&format!(r#" ac:title="{}""#, escape_xml(&image.title)));
+        }
+        self.write(&format!("<ac:image{}>{}</ac:image>", attributes, source))
+    }
+
+    /// Writes block HTML that opens or closes `<details>`. The markdown between the tags is the
+    /// body of the expand, so it is written as usual
+    ///
+    /// # Arguments
+    ///
+    /// * `html` - the HTML, which doesn't have to have as many closing tags as opening ones
+    fn write_details_html(&mut self, html: &str) -> io::Result<()> {
+        for part in html::split_details(html) {
+            match part {
+                DetailsPart::Open(summary) => {
+                    self.write(r#"<ac:structured-macro ac:name="expand">"#)?;
+                    if let Some(summary) = summary {
+                        let mut title = String::new();
+                        Self::collect_text(Html::parse_fragment(summary).tree.root(), &mut title);
+                        self.write(&format!(
+                            r#"<ac:parameter ac:name="title">{}</ac:parameter>"#,
+                            escape_xml(title.trim())
+                        ))?;
+                    }
+                    self.write("<ac:rich-text-body>")?;
+                    self.open_details += 1;
+                }
+                // a closing tag without an opening one has nothing to close
+                DetailsPart::Close if self.open_details > 0 => {
+                    self.open_details -= 1;
+                    self.write("</ac:rich-text-body></ac:structured-macro>")?;
+                }
+                DetailsPart::Close => (),
+                DetailsPart::Html(html) => {
+                    let fragment = Html::parse_fragment(html);
+                    self.parse_html_blocks(&[fragment.tree.root()])?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes block HTML whose tags are balanced, which is either a page properties directive or
+    /// HTML to parse
+    ///
+    /// # Arguments
+    ///
+    /// * `html` - the HTML
+    fn write_block_html(&mut self, html: &str) -> io::Result<()> {
+        match Directive::parse(html) {
+            // written when the table starts
+            Some(directive @ Directive::Details(_)) => {
+                self.properties = Some(directive);
+                Ok(())
+            }
+            Some(directive) => {
+                self.write_macro_start(&directive)?;
+                self.write("</ac:structured-macro>")
+            }
+            None => {
+                let fragment = Html::parse_fragment(html);
+                self.parse_html_blocks(&[fragment.tree.root()])
+            }
+        }
+    }
+
+    /// Opens the macro a page properties directive names, with its parameters
+    ///
+    /// # Arguments
+    ///
+    /// * `directive` - the directive
+    fn write_macro_start(&mut self, directive: &Directive) -> io::Result<()> {
+        self.write(&format!(
+            "<ac:structured-macro ac:name=\"{}\">",
+            directive.name()
+        ))?;
+        for (name, value) in directive.params() {
+            self.write(&format!(
+                "<ac:parameter ac:name=\"{}\">{}</ac:parameter>",
+                escape_xml(name),
+                escape_xml(value)
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Parses HTML nodes that are in the body of the page or of a macro, where inline content
+    /// has to go in a paragraph
+    ///
+    /// # Arguments
+    ///
+    /// * `nodes` - the nodes to parse
+    fn parse_html_blocks(&mut self, nodes: &[NodeRef<Node>]) -> io::Result<()> {
+        let mut paragraph = false;
+        for &child in nodes {
+            let block = match child.value() {
+                Node::Element(elem) if elem.name() != "html" => {
+                    html::markup(elem.name()).is_some_and(Markup::is_block)
+                }
+                // the parser puts every fragment in an `<html>` element
+                Node::Element(_) | Node::Fragment | Node::Document => {
+                    if paragraph {
+                        self.write("</p>")?;
+                        paragraph = false;
+                    }
+                    self.parse_html_blocks(&child.children().collect::<Vec<_>>())?;
+                    continue;
+                }
+                // whitespace only matters between inline elements
+                Node::Text(text) if text.trim().is_empty() && !paragraph => continue,
+                Node::Text(_) => false,
+                // comments don't make it to the output
+                _ => continue,
+            };
+            if block && paragraph {
+                self.write("</p>")?;
+                paragraph = false;
+            } else if !block && !paragraph {
+                self.write("<p>")?;
+                paragraph = true;
+            }
+            self.parse_html(child)?;
+        }
+        if paragraph {
+            self.write("</p>")?;
+        }
+        Ok(())
+    }
+
+    /// Parses the children of an HTML node to storage format
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - node whose children to parse
+    fn parse_html_children(&mut self, node: NodeRef<Node>) -> io::Result<()> {
+        for child in node.children() {
+            self.parse_html(child)?;
+        }
+        Ok(())
+    }
+
+    /// Warns about an HTML element that isn't understood, once per element name
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - the element name
+    fn warn_html(&mut self, name: &str) {
+        if self.unknown_html.insert(name.to_string()) {
+            log::warn!("<{}> isn't supported, so only its content is written", name);
+        }
+    }
+
+    /// Writes an inline HTML tag as the element storage format has for it
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - a single opening or closing tag, or a comment
+    fn write_inline_html(&mut self, tag: &str) -> io::Result<()> {
+        // comments aren't written
+        let Some((name, closing)) = html::parse_tag(tag) else {
+            return Ok(());
+        };
+        let Some(markup) = html::markup(&name) else {
+            if !closing {
+                self.warn_html(&name);
+            }
+            return Ok(());
+        };
+        if closing {
+            // only an element opened in the same markdown tag can be closed, along with the
+            // elements inside it. Other closing tags have nothing to close
+            let depth = self.tag_depth;
+            let Some(index) = self
+                .inline_html
+                .iter()
+                .rposition(|(open, open_depth, _)| *open == name && *open_depth == depth)
+            else {
+                return Ok(());
+            };
+            while self.inline_html.len() > index {
+                self.close_inline_html_element()?;
+            }
+            return Ok(());
+        }
+        match markup {
+            Markup::Wrap(open, _) => {
+                let (open, close) = wrap_tags(open);
+                self.inline_html.push((name, self.tag_depth, Some(close)));
+                self.write(open)
+            }
+            Markup::Break => self.write("<br />"),
+            Markup::Image => match Image::from_html(tag) {
+                Some(image) => self.write_image(&image),
+                None => Ok(()),
+            },
+            Markup::Link => match html::attribute(tag, "href") {
+                Some(href) => {
+                    self.inline_html.push((name, self.tag_depth, None));
+                    self.start_tag(Tag::Link {
+                        link_type: LinkType::Inline,
+                        dest_url: CowStr::from(href),
+                        title: CowStr::from(""),
+                        id: CowStr::from(""),
+                    })
+                }
+                // `<a name="...">` is only somewhere to link to
+                None => {
+                    self.inline_html.push((name, self.tag_depth, Some("")));
+                    Ok(())
+                }
+            },
+            // blocks can't be written in a line, so only their content is
+            _ => Ok(()),
+        }
+    }
+
+    /// Closes the innermost inline HTML element that is open
+    fn close_inline_html_element(&mut self) -> io::Result<()> {
+        match self.inline_html.pop() {
+            Some((_, _, Some(close))) => self.write(close),
+            Some((_, _, None)) => self.end_tag(TagEnd::Link),
+            None => Ok(()),
+        }
+    }
+
+    /// Closes the inline HTML elements opened inside a markdown tag, like a `<b>` that is never
+    /// closed in a paragraph, so the output stays balanced
+    ///
+    /// # Arguments
+    ///
+    /// * `depth` - how many markdown tags are open, including the one being closed
+    fn close_inline_html(&mut self, depth: usize) -> io::Result<()> {
+        while self
+            .inline_html
+            .last()
+            .is_some_and(|(_, open_depth, _)| *open_depth >= depth)
+        {
+            self.close_inline_html_element()?;
+        }
+        Ok(())
+    }
+
+    /// Parses HTML to storage format. Elements storage format has are kept, and others only
+    /// keep their content
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - node to parse
+    fn parse_html(&mut self, node: NodeRef<Node>) -> io::Result<()> {
+        let elem = match node.value() {
+            Node::Element(elem) => elem,
+            Node::Text(text) => {
+                // whitespace between rows and cells isn't content
+                let parent = node.parent().and_then(|parent| match parent.value() {
+                    Node::Element(parent) => html::markup(parent.name()),
+                    _ => None,
+                });
+                if matches!(
+                    parent,
+                    Some(Markup::Table | Markup::TableSection | Markup::TableRow)
+                ) {
+                    return Ok(());
+                }
+                let str_text = text.trim_start_matches('\n').trim_start_matches(' ');
+                return self.write(&escape_xml(str_text));
+            }
+            Node::Fragment | Node::Document => return self.parse_html_children(node),
+            // comments don't make it to the output
+            _ => return Ok(()),
+        };
+        let Some(markup) = html::markup(elem.name()) else {
+            self.warn_html(elem.name());
+            return self.parse_html_children(node);
+        };
+        match markup {
+            Markup::Wrap(open, _) => {
+                let (open, close) = wrap_tags(open);
+                self.write(open)?;
+                self.parse_html_children(node)?;
+                self.write(close)
+            }
+            Markup::Break => self.write("<br />"),
+            Markup::Rule => self.write("<hr />"),
+            Markup::Link => match elem.attr("href") {
+                Some(href) => {
+                    self.start_tag(Tag::Link {
+                        link_type: LinkType::Inline,
+                        dest_url: CowStr::from(href.to_string()),
+                        title: CowStr::from(""),
+                        id: CowStr::from(""),
+                    })?;
+                    self.parse_html_children(node)?;
+                    self.end_tag(TagEnd::Link)
+                }
+                None => self.parse_html_children(node),
+            },
+            Markup::Image => self.write_image(&Image::from_element(elem)),
+            Markup::Preformatted => {
+                let mut code = String::new();
+                for descendant in node.descendants() {
+                    if let Node::Text(text) = descendant.value() {
+                        code.push_str(text);
+                    }
+                }
+                self.start_tag(Tag::CodeBlock(CodeBlockKind::Indented))?;
+                self.write_text(code.trim_start_matches('\n'))?;
+                self.end_tag(TagEnd::CodeBlock)
+            }
+            Markup::Paragraph => {
+                // a `<div>` can hold blocks, which can't go in a paragraph
+                let blocks = node.children().any(|child| {
+                    matches!(child.value(), Node::Element(child)
+                        if html::markup(child.name()).is_some_and(Markup::is_block))
+                });
+                if blocks {
+                    self.parse_html_blocks(&node.children().collect::<Vec<_>>())
+                } else {
+                    self.write("<p>")?;
+                    self.parse_html_children(node)?;
+                    self.write("</p>")
+                }
+            }
+            Markup::Table => {
+                self.write("<table><tbody>")?;
+                self.parse_html_children(node)?;
+                self.write("</tbody></table>")
+            }
+            Markup::TableRow => {
+                self.write("<tr>")?;
+                self.parse_html_children(node)?;
+                self.write("</tr>")
+            }
+            Markup::TableCell { header } => {
+                let cell = if header { "th" } else { "td" };
+                self.write(&format!("<{}>", cell))?;
+                self.parse_html_children(node)?;
+                self.write(&format!("</{}>", cell))
+            }
+            Markup::Details => {
+                self.write(r#"<ac:structured-macro ac:name="expand">"#)?;
+                // the summary becomes the title parameter, everything else is the body
+                let summary = node.children().find(|child| {
+                    matches!(child.value(), Node::Element(child) if child.name() == "summary")
+                });
+                if let Some(summary) = summary {
+                    let mut title = String::new();
+                    Self::collect_text(summary, &mut title);
+                    self.write(&format!(
+                        r#"<ac:parameter ac:name="title">{}</ac:parameter>"#,
+                        escape_xml(title.trim())
+                    ))?;
+                }
+                self.write("<ac:rich-text-body>")?;
+                let body: Vec<_> = node
+                    .children()
+                    .filter(|child| Some(*child) != summary)
+                    .collect();
+                self.parse_html_blocks(&body)?;
+                self.write("</ac:rich-text-body></ac:structured-macro>")
+            }
+            // a summary outside of details is only text
+            Markup::Summary | Markup::TableSection | Markup::Content => {
+                self.parse_html_children(node)
+            }
+        }
+    }
+
+    /// Reads ahead to the end of the list that just started, and checks if every item in it is a
+    /// task, so it can be written as a task list. The events are handled as usual afterwards.
+    fn is_task_list(&mut self) -> bool {
+        let mut i = 0;
+        let mut depth = 1;
+        let mut item_start = false;
+        while depth > 0 {
+            if i == self.lookahead.len() {
+                match self.iter.next() {
+                    Some(event) => self.lookahead.push_back(event),
+                    None => break,
+                }
+            }
+            let event = &self.lookahead[i];
+            i += 1;
+            match event {
+                Event::TaskListMarker(_) | Event::Start(Tag::Paragraph) if item_start => (),
+                _ if item_start => return false,
+                Event::Start(Tag::List(_)) => depth += 1,
+                Event::End(TagEnd::List(_)) => depth -= 1,
+                _ => (),
+            }
+            // the marker of a loose item comes after the start of its paragraph
+            item_start = depth == 1
+                && (matches!(event, Event::Start(Tag::Item))
+                    || item_start && matches!(event, Event::Start(Tag::Paragraph)));
+        }
+        true
+    }
+
+    /// Main part of the parser, outputting to underlying `writer`.
+    ///
+    /// Passes start/end tags out to `start_tag` and `end_tag`, respectively.
+    /// Writes out the rest of the inline content as necessary.
+    fn run(&mut self) -> io::Result<()> {
+        while let Some(event) = self.lookahead.pop_front().or_else(|| self.iter.next()) {
+            // HTML that is still open is written where it was, before the markdown after it
+            if html::ends_html(&event) {
+                if let Some(html) = self.html_buffer.interrupt() {
+                    self.write_block_html(&html)?;
+                }
+            }
+            match event {
+                Event::Start(Tag::MetadataBlock(_)) => {
+                    // front matter isn't content
+                    while let Some(event) = self.lookahead.pop_front().or_else(|| self.iter.next())
+                    {
+                        if matches!(event, Event::End(TagEnd::MetadataBlock(_))) {
+                            break;
+                        }
+                    }
+                }
+                Event::Start(tag) => {
+                    self.tag_depth += 1;
+                    self.start_tag(tag)?;
+                }
+                Event::End(tag) => {
+                    self.close_inline_html(self.tag_depth)?;
+                    self.tag_depth = self.tag_depth.saturating_sub(1);
+                    self.end_tag(tag)?;
+                }
+                Event::Text(text) => self.write_text(&text)?,
+                Event::Code(text) => {
+                    self.write("<code>")?;
+                    self.write(&escape_xml(&text))?;
+                    self.write("</code>")?;
+                }
+                Event::SoftBreak => self.write_text(" ")?,
+                Event::HardBreak => self.write("<br />")?,
+                Event::InlineHtml(html) => self.write_inline_html(&html)?,
+                Event::Rule => self.write("<hr />")?,
+                Event::FootnoteReference(label) => {
+                    let (number, first) = self.footnotes.reference(&label);
+                    let anchor = footnotes::anchor(&label);
+                    if first {
+                        // only the first reference can be jumped back to
+                        self.write(&anchor_macro(&format!("fnref-{}", anchor)))?;
+                    }
+                    self.write(&format!(
+                        "<sup>{}</sup>",
+                        anchor_link(&format!("fn-{}", anchor), &number.to_string())
+                    ))?;
+                }
+                Event::TaskListMarker(checked) => {
+                    self.task_start = false;
+                    if self.list_stack.last() == Some(&true) {
+                        self.write(&format!(
+                            "<ac:task-status>{}</ac:task-status><ac:task-body>",
+                            if checked { "complete" } else { "incomplete" }
+                        ))?;
+                    } else {
+                        // a task in a list with normal items, which a task list can't hold
+                        self.write(if checked { "[x] " } else { "[ ] " })?;
+                    }
+                }
+                Event::Html(string) => {
+                    self.html_buffer.push(&string);
+                    if html::has_details(&string) {
+                        // the summary can be on the next line, so take the rest of the block too
+                        while let Some(event) =
+                            self.lookahead.pop_front().or_else(|| self.iter.next())
+                        {
+                            match event {
+                                Event::Html(more) => self.html_buffer.push(&more),
+                                event => {
+                                    self.lookahead.push_front(event);
+                                    break;
+                                }
+                            }
+                        }
+                        let html = self.html_buffer.take();
+                        self.write_details_html(&html)?;
+                    } else if let Some(html) = self.html_buffer.complete() {
+                        self.write_block_html(&html)?;
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if let Some(html) = self.html_buffer.finish() {
+            self.write_block_html(&html)?;
+        }
+        self.close_inline_html(0)?;
+        // details that were never closed
+        while self.open_details > 0 {
+            self.open_details -= 1;
+            self.write("</ac:rich-text-body></ac:structured-macro>")?;
+        }
+        self.write_footnotes()
+    }
+
+    /// Stores the footnote definition being written, if there is one, and goes back to writing
+    /// the document
+    fn end_footnote(&mut self) {
+        if let Some((label, content)) = self.footnote_buffer.take() {
+            self.footnotes.define(label, content);
+        }
+    }
+
+    /// Writes the collected footnote definitions as a numbered list under a "Notes" heading, in
+    /// the order they were referenced. Definitions that were never referenced go last.
+    fn write_footnotes(&mut self) -> io::Result<()> {
+        let notes = self.footnotes.take_notes();
+        if notes.is_empty() {
+            return Ok(());
+        }
+        let level = 2 + self.modify_headers;
+        if (1..7).contains(&level) {
+            self.write(&format!("<h{}>Notes</h{}>", level, level))?;
+        } else {
+            self.write("<p><strong>Notes</strong></p>")?;
+        }
+        self.write("<ol>")?;
+        for note in notes {
+            let anchor = anchor_macro(&format!("fn-{}", note.anchor));
+            let back = if note.referenced {
+                format!(" {}", anchor_link(&format!("fnref-{}", note.anchor), "↩"))
+            } else {
+                String::new()
+            };
+            // the anchor and back link go in the first paragraph, if the note starts with one
+            let item = match (note.content.strip_prefix("<p>"), note.content.find("</p>")) {
+                (Some(_), Some(end)) => format!(
+                    "<p>{}{}{}{}",
+                    anchor,
+                    &note.content[3..end],
+                    back,
+                    &note.content[end..]
+                ),
+                _ if back.is_empty() => format!("{}{}", anchor, note.content),
+                _ => format!("{}{}<p>{}</p>", anchor, note.content, back.trim_start()),
+            };
+            self.write(&format!("<li>{}</li>", item))?;
+        }
+        self.write("</ol>")
+    }
+
+    /// Handles opening tags
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - tag to open
+    fn start_tag(&mut self, tag: Tag<'a>) -> io::Result<()> {
+        if self.properties.is_some() && !matches!(tag, Tag::Table(_) | Tag::HtmlBlock) {
+            self.properties = None;
+            log::warn!("`<!-- details -->` isn't followed by a table, so it is left out");
+        }
+        match tag {
+            Tag::Paragraph if self.task_start => {
+                self.task_paragraph = true;
+                Ok(())
+            }
+            Tag::Paragraph => self.write("<p>"),
+            Tag::Heading { level, .. } => {
+                let parsed_level = level as i8 + self.modify_headers;
+                let anchor = self.anchors.heading(self.heading_count).map(anchor_macro);
+                self.heading_count += 1;
+                if parsed_level <= 0 {
+                    // skip header contents if header level <= 0
+                    self.should_output_line = false;
+                    Ok(())
+                } else if parsed_level < 7 {
+                    self.heading_close = match parsed_level {
+                        1 => "</h1>",
+                        2 => "</h2>",
+                        3 => "</h3>",
+                        4 => "</h4>",
+                        5 => "</h5>",
+                        _ => "</h6>",
+                    };
+                    self.write(&format!("<h{}>", parsed_level))?;
+                    self.write(&anchor.unwrap_or_default())
+                } else {
+                    // if the header is > 6, then just treat it as regular text.
+                    self.heading_close = "</p>";
+                    self.write("<p>")?;
+                    self.write(&anchor.unwrap_or_default())
+                }
+            }
+            Tag::BlockQuote(None) => {
+                self.quote_stack.push(false);
+                self.write("<blockquote>")
+            }
+            Tag::BlockQuote(Some(kind)) => {
+                self.quote_stack.push(true);
+                let spec = self.alert_map[&kind].clone();
+                self.write(&format!(
+                    "<ac:structured-macro ac:name=\"{}\">",
+                    escape_xml(macro_name(&spec))
+                ))?;
+                // wiki style parameters, like `panel:title=Note|bgColor=#fff`
+                let params = spec.split_once(':').map_or("", |(_, params)| params);
+                for param in params.split('|').filter(|param| !param.is_empty()) {
+                    let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                    self.write(&format!(
+                        "<ac:parameter ac:name=\"{}\">{}</ac:parameter>",
+                        escape_xml(name),
+                        escape_xml(value)
+                    ))?;
+                }
+                self.write("<ac:rich-text-body>")
+            }
+            Tag::CodeBlock(code_block_kind) => {
+                self.write(r#"<ac:structured-macro ac:name="code">"#)?;
+                if let CodeBlockKind::Fenced(language) = code_block_kind {
+                    let lang = self.languages.map(&language);
+                    self.write(&format!(
+                        r#"<ac:parameter ac:name="language">{}</ac:parameter>"#,
+                        escape_xml(&lang)
+                    ))?;
+                }
+                self.in_code_block = true;
+                self.write("<ac:plain-text-body><![CDATA[")
+            }
+            Tag::List(first_number) => {
+                let task_list = self.is_task_list();
+                self.list_stack.push(task_list);
+                match (task_list, first_number) {
+                    (true, _) => self.write("<ac:task-list>"),
+                    (false, Some(1)) => self.write("<ol>"),
+                    (false, Some(start)) => self.write(&format!("<ol start=\"{}\">", start)),
+                    (false, None) => self.write("<ul>"),
+                }
+            }
+            Tag::Item if self.list_stack.last() == Some(&true) => {
+                self.task_start = true;
+                self.task_id += 1;
+                self.write(&format!(
+                    "<ac:task><ac:task-id>{}</ac:task-id>",
+                    self.task_id
+                ))
+            }
+            Tag::Item => self.write("<li>"),
+            Tag::Table(alignments) => {
+                self.table_alignments = alignments;
+                if let Some(directive) = self.properties.take() {
+                    self.write_macro_start(&directive)?;
+                    self.write("<ac:rich-text-body>")?;
+                    self.properties_table = true;
+                }
+                self.write("<table><tbody>")
+            }
+            Tag::TableHead => {
+                self.table_header = true;
+                self.table_cell_index = 0;
+                self.write("<tr>")
+            }
+            Tag::TableRow => {
+                self.table_cell_index = 0;
+                self.write("<tr>")
+            }
+            Tag::TableCell => {
+                let style = match self.table_alignments.get(self.table_cell_index) {
+                    Some(Alignment::Left) => r#" style="text-align: left;""#,
+                    Some(Alignment::Center) => r#" style="text-align: center;""#,
+                    Some(Alignment::Right) => r#" style="text-align: right;""#,
+                    _ => "",
+                };
+                self.table_cell_index += 1;
+                let cell = if self.table_header { "th" } else { "td" };
+                self.write(&format!("<{}{}>", cell, style))
+            }
+            // an image's alt text is plain
+            Tag::Emphasis | Tag::Strong | Tag::Strikethrough if self.image.is_some() => Ok(()),
+            Tag::Emphasis => self.write("<em>"),
+            Tag::Strong => self.write("<strong>"),
+            Tag::Strikethrough => self.write(r#"<span style="text-decoration: line-through;">"#),
+            Tag::Link { dest_url, .. } => {
+                // in-page links go to the heading's anchor
+                let target = match dest_url
+                    .strip_prefix('#')
+                    .and_then(|f| self.anchors.link(f))
+                {
+                    Some(anchor) => Target::Page {
+                        title: String::new(),
+                        anchor: Some(anchor.to_string()),
+                    },
+                    None => self.resolver.link(&dest_url),
+                };
+                match target {
+                    Target::Page { title, anchor } => {
+                        self.link_close = "</ac:link-body></ac:link>";
+                        let anchor = anchor
+                            .map(|anchor| format!(r#" ac:anchor="{}""#, escape_xml(&anchor)))
+                            .unwrap_or_default();
+                        // a link without a page is to this one
+                        let page = if title.is_empty() {
+                            String::new()
+                        } else {
+                            format!(r#"<ri:page ri:content-title="{}" />"#, escape_xml(&title))
+                        };
+                        self.write(&format!("<ac:link{}>{}<ac:link-body>", anchor, page))
+                    }
+                    Target::Url(url) | Target::Attachment(url) => {
+                        self.link_close = "</a>";
+                        self.write(&format!(r#"<a href="{}">"#, escape_xml(&url)))
+                    }
+                }
+            }
+            Tag::FootnoteDefinition(label) => {
+                // the parser can start a definition before the last one has ended
+                self.end_footnote();
+                self.footnote_buffer = Some((label.to_string(), String::new()));
+                Ok(())
+            }
+            Tag::Image {
+                dest_url, title, ..
+            } => {
+                // the alt text comes before the end tag, so the whole image is written there
+                self.image = Some(Image::new(&dest_url, "", &title));
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Handles closing tags
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - tag to close
+    fn end_tag(&mut self, tag: TagEnd) -> io::Result<()> {
+        match tag {
+            TagEnd::Paragraph if self.task_paragraph => {
+                self.task_paragraph = false;
+                Ok(())
+            }
+            TagEnd::Paragraph => self.write("</p>"),
+            TagEnd::Heading(..) => {
+                if !self.should_output_line {
+                    self.should_output_line = true;
+                    Ok(())
+                } else {
+                    self.write(self.heading_close)
+                }
+            }
+            TagEnd::BlockQuote => {
+                if self.quote_stack.pop().unwrap_or_default() {
+                    self.write("</ac:rich-text-body></ac:structured-macro>")
+                } else {
+                    self.write("</blockquote>")
+                }
+            }
+            TagEnd::CodeBlock => {
+                self.in_code_block = false;
+                self.write("]]></ac:plain-text-body></ac:structured-macro>")
+            }
+            TagEnd::List(ordered) => match (self.list_stack.pop(), ordered) {
+                (Some(true), _) => self.write("</ac:task-list>"),
+                (_, true) => self.write("</ol>"),
+                (_, false) => self.write("</ul>"),
+            },
+            TagEnd::Item if self.list_stack.last() == Some(&true) => {
+                self.write("</ac:task-body></ac:task>")
+            }
+            TagEnd::Item => self.write("</li>"),
+            TagEnd::Table => {
+                self.write("</tbody></table>")?;
+                if std::mem::take(&mut self.properties_table) {
+                    self.write("</ac:rich-text-body></ac:structured-macro>")?;
+                }
+                Ok(())
+            }
+            TagEnd::TableHead => {
+                self.table_header = false;
+                self.write("</tr>")
+            }
+            TagEnd::TableRow => self.write("</tr>"),
+            TagEnd::TableCell => {
+                if self.table_header {
+                    self.write("</th>")
+                } else {
+                    self.write("</td>")
+                }
+            }
+            TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough if self.image.is_some() => {
+                Ok(())
+            }
+            TagEnd::Emphasis => self.write("</em>"),
+            TagEnd::Strong => self.write("</strong>"),
+            TagEnd::Strikethrough => self.write("</span>"),
+            TagEnd::Link => self.write(self.link_close),
+            TagEnd::HtmlBlock => {
+                // elements like `<p>` end with the block
+                self.html_buffer.end_block();
+                match self.html_buffer.complete() {
+                    Some(html) => self.write_block_html(&html),
+                    None => Ok(()),
+                }
+            }
+            TagEnd::FootnoteDefinition => {
+                self.end_footnote();
+                Ok(())
+            }
+            TagEnd::Image => {
+                let Some(mut image) = self.image.take() else {
+                    return Ok(());
+                };
+                // `{width=300}` right after the image
+                match self.lookahead.pop_front().or_else(|| self.iter.next()) {
+                    Some(Event::Text(text)) => {
+                        let read = image.read_attributes(&text);
+                        if read < text.len() {
+                            self.lookahead
+                                .push_front(Event::Text(CowStr::from(text[read..].to_string())));
+                        }
+                    }
+                    Some(event) => self.lookahead.push_front(event),
+                    None => (),
+                }
+                self.write_image(&image)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes Confluence storage format output
+///
+/// # Arguments
+///
+/// * `writer` - something implementing the Write trait
+/// * `iter` - an iterator of Events from pulldown-cmark
+/// * `options` - the render options to use
+///
+/// # Returns
+///
+/// * `Result` - if the StorageWriter wrote successfully to `writer`
+pub fn write<'a, I, W>(writer: W, iter: I, options: &RenderOptions) -> io::Result<()>
+where
+    I: Iterator<Item = Event<'a>>,
+    W: Write,
+{
+    // links can point at headings further down, so find them all first
+    let events: Vec<Event<'a>> = iter.collect();
+    let anchors = Anchors::new(
+        &events,
+        options.modify_headers,
+        options.page_title.as_deref(),
+    );
+    StorageWriter::new(events.into_iter(), writer, options, anchors).run()
+}
+
+/// Writes the table of contents macro
+///
+/// # Arguments
+///
+/// * `writer` - something implementing the Write trait
+///
+/// # Returns
+///
+/// * `Result` - if wrote successfully to `writer`
+pub fn write_toc<W>(mut writer: W) -> io::Result<()>
+where
+    W: Write,
+{
+    write!(writer, r#"<ac:structured-macro ac:name="toc" />"#)
+}
+
+/// Elements that start blocks, so whitespace around them isn't content
+const BLOCK_ELEMENTS: [&str; 32] = [
+    "p",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "ul",
+    "ol",
+    "li",
+    "blockquote",
+    "pre",
+    "table",
+    "thead",
+    "tbody",
+    "tfoot",
+    "tr",
+    "th",
+    "td",
+    "hr",
+    "div",
+    "section",
+    "details",
+    "summary",
+    "ac:structured-macro",
+    "ac:rich-text-body",
+    "ac:plain-text-body",
+    "ac:task-list",
+    "ac:task",
+    "ac:layout",
+    "ac:layout-section",
+    "ac:layout-cell",
+];
+
+/// Macros that are written inline with text
+const INLINE_MACROS: [&str; 3] = ["status", "jira", "anchor"];
+
+/// Makes storage format parseable as HTML: CDATA sections become escaped text, and
+/// self-closing namespaced elements like `<ri:page ... />` get explicit end tags
+///
+/// # Arguments
+///
+/// * `input` - storage format
+///
+/// # Returns
+///
+/// * `String` - HTML that parses into the same tree
+fn normalize_storage(input: &str) -> String {
+    let mut r = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find('<') {
+        r.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+            let end = cdata.find("]]>").unwrap_or(cdata.len());
+            r.push_str(&escape_xml(&cdata[..end]));
+            rest = cdata.get(end + 3..).unwrap_or_default();
+            continue;
+        }
+        let tag_end = rest.find('>').map_or(rest.len(), |i| i + 1);
+        let tag = &rest[..tag_end];
+        let name: String = tag[1..]
+            .chars()
+            .take_while(|c| !c.is_whitespace() && *c != '/' && *c != '>')
+            .collect();
+        if name.contains(':') && tag.ends_with("/>") {
+            r.push_str(tag[..tag.len() - 2].trim_end());
+            r.push_str(&format!("></{}>", name));
+        } else {
+            r.push_str(tag);
+        }
+        rest = &rest[tag_end..];
+    }
+    r.push_str(rest);
+    r
+}
+
+/// Returns the tag name of an element node
+///
+/// # Arguments
+///
+/// * `node` - the node
+fn element_name<'n>(node: &NodeRef<'n, Node>) -> Option<&'n str> {
+    match node.value() {
+        Node::Element(elem) => Some(&elem.name.local),
+        _ => None,
+    }
+}
+
+/// Returns an attribute of an element node
+///
+/// # Arguments
+///
+/// * `node` - the node
+/// * `name` - the attribute name
+fn attribute<'n>(node: &NodeRef<'n, Node>, name: &str) -> Option<&'n str> {
+    match node.value() {
+        Node::Element(elem) => elem.attr(name),
+        _ => None,
+    }
+}
+
+/// Finds the first descendant element with the given name
+///
+/// # Arguments
+///
+/// * `node` - where to start looking
+/// * `name` - the tag name
+fn find_descendant<'n>(node: &NodeRef<'n, Node>, name: &str) -> Option<NodeRef<'n, Node>> {
+    node.descendants()
+        .skip(1)
+        .find(|descendant| element_name(descendant) == Some(name))
+}
+
+/// If a node starts a block, so whitespace next to it is insignificant
+///
+/// # Arguments
+///
+/// * `node` - the node
+fn is_block(node: &NodeRef<Node>) -> bool {
+    match element_name(node) {
+        Some("ac:structured-macro") => {
+            !INLINE_MACROS.contains(&attribute(node, "ac:name").unwrap_or_default())
+        }
+        Some(name) => BLOCK_ELEMENTS.contains(&name),
+        None => false,
+    }
+}
+
+/// Reads column alignments from the `text-align` style of the cells in a row
+///
+/// # Arguments
+///
+/// * `row` - the first row of the table, if there is one
+/// * `columns` - how many columns the table has
+fn alignments(row: Option<&NodeRef<Node>>, columns: usize) -> Vec<Alignment> {
+    let mut alignments = vec![Alignment::None; columns];
+    let cells = row.into_iter().flat_map(|row| {
+        row.children()
+            .filter(|cell| matches!(element_name(cell), Some("td") | Some("th")))
+    });
+    for (alignment, cell) in alignments.iter_mut().zip(cells) {
+        let style = attribute(&cell, "style")
+            .unwrap_or_default()
+            .replace(' ', "");
+        *alignment = if style.contains("text-align:left") {
+            Alignment::Left
+        } else if style.contains("text-align:center") {
+            Alignment::Center
+        } else if style.contains("text-align:right") {
+            Alignment::Right
+        } else {
+            Alignment::None
+        };
+    }
+    alignments
+}
+
+/// Parses Confluence storage format into pulldown-cmark events
+struct StorageParser {
+    events: Vec<Event<'static>>,
+    // map between confluence/markdown code block langs
+    lang_map: HashMap<String, String>,
+}
+
+impl StorageParser {
+    /// return a new StorageParser
+    ///
+    /// # Arguments
+    ///
+    /// * `lang_map` - map from markdown to Atlassian code block langs, which is inverted
+    fn new(lang_map: &HashMap<String, String>) -> Self {
+        StorageParser {
+            events: vec![],
+            lang_map: invert_lang_map(lang_map),
+        }
+    }
+
+    /// Makes an owned text event
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - the text
+    fn text(&mut self, s: &str) {
+        if !s.is_empty() {
+            self.events.push(Event::Text(CowStr::from(s.to_string())));
+        }
+    }
+
+    /// Parses all children of a node
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the parent node
+    fn children(&mut self, node: NodeRef<Node>) {
+        for child in node.children() {
+            self.node(child);
+        }
+    }
+
+    /// Parses the children of a list item or table cell, unwrapping a lone paragraph so the list
+    /// stays tight. A cell's paragraphs are always unwrapped and joined with line breaks, so it
+    /// stays on one line
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the `li`, `td` or `th` element
+    fn unwrapped(&mut self, node: NodeRef<Node>) {
+        let paragraphs = node
+            .children()
+            .filter(|child| element_name(child) == Some("p"))
+            .count();
+        let in_cell = matches!(element_name(&node), Some("td") | Some("th"));
+        if paragraphs > 1 && !in_cell {
+            return self.children(node);
+        }
+        let mut first = true;
+        for child in node.children() {
+            if element_name(&child) == Some("p") {
+                if !first {
+                    self.events.push(Event::HardBreak);
+                }
+                self.children(child);
+                first = false;
+            } else {
+                self.node(child);
+            }
+        }
+    }
+
+    /// Parses a table
+    ///
+    /// # Arguments
+    ///
+    /// * `table` - the table element
+    fn table(&mut self, table: NodeRef<Node>) {
+        let rows: Vec<NodeRef<Node>> = table
+            .descendants()
+            .filter(|descendant| element_name(descendant) == Some("tr"))
+            .collect();
+        fn cells<'n>(row: &NodeRef<'n, Node>) -> Vec<NodeRef<'n, Node>> {
+            row.children()
+                .filter(|cell| matches!(element_name(cell), Some("td") | Some("th")))
+                .collect()
+        }
+        let columns = rows.iter().map(|row| cells(row).len()).max().unwrap_or(0);
+        self.events
+            .push(Event::Start(Tag::Table(alignments(rows.first(), columns))));
+        let header = rows.first().is_some_and(|row| {
+            cells(row)
+                .iter()
+                .all(|cell| element_name(cell) == Some("th"))
+        });
+        self.events.push(Event::Start(Tag::TableHead));
+        for i in 0..columns {
+            self.events.push(Event::Start(Tag::TableCell));
+            // markdown tables need a header, so give it an empty one if there isn't one
+            if let Some(cell) = rows
+                .first()
+                .filter(|_| header)
+                .and_then(|row| cells(row).get(i).copied())
+            {
+                self.unwrapped(cell);
+            }
+            self.events.push(Event::End(TagEnd::TableCell));
+        }
+        self.events.push(Event::End(TagEnd::TableHead));
+        for row in rows.iter().skip(if header { 1 } else { 0 }) {
+            self.events.push(Event::Start(Tag::TableRow));
+            let row_cells = cells(row);
+            for i in 0..columns {
+                self.events.push(Event::Start(Tag::TableCell));
+                if let Some(&cell) = row_cells.get(i) {
+                    self.unwrapped(cell);
+                }
+                self.events.push(Event::End(TagEnd::TableCell));
+            }
+            self.events.push(Event::End(TagEnd::TableRow));
+        }
+        self.events.push(Event::End(TagEnd::Table));
+    }
+
+    /// Parses a macro
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the `ac:structured-macro` element
+    fn structured_macro(&mut self, node: NodeRef<Node>) {
+        let name = attribute(&node, "ac:name").unwrap_or_default();
+        let params: HashMap<&str, String> = node
+            .children()
+            .filter(|child| element_name(child) == Some("ac:parameter"))
+            .map(|param| {
+                let mut value = String::new();
+                for descendant in param.descendants() {
+                    if let Node::Text(t) = descendant.value() {
+                        value.push_str(t);
+                    }
+                }
+                (attribute(&param, "ac:name").unwrap_or_default(), value)
+            })
+            .collect();
+        let plain_body = find_descendant(&node, "ac:plain-text-body").map(|body| {
+            let mut text = String::new();
+            for descendant in body.descendants() {
+                if let Node::Text(t) = descendant.value() {
+                    text.push_str(t);
+                }
+            }
+            text
+        });
+        let rich_body = find_descendant(&node, "ac:rich-text-body");
+        let title = params.get("title").filter(|title| !title.is_empty());
+        match name {
+            "code" | "noformat" => {
+                let lang = match (name, params.get("language")) {
+                    ("code", Some(lang)) => {
+                        let lang = lang.to_lowercase();
+                        self.lang_map.get(&lang).cloned().unwrap_or(lang)
+                    }
+                    _ => String::new(),
+                };
+                self.code_block(&lang, &plain_body.unwrap_or_default());
+            }
+            "info" | "tip" | "note" | "warning" | "panel" => {
+                let kind = match name {
+                    "info" => Some(BlockQuoteKind::Note),
+                    "tip" => Some(BlockQuoteKind::Tip),
+                    "note" => Some(BlockQuoteKind::Important),
+                    "warning" => Some(BlockQuoteKind::Warning),
+                    _ => None,
+                };
+                self.events.push(Event::Start(Tag::BlockQuote(kind)));
+                if let Some(title) = title {
+                    self.events.push(Event::Start(Tag::Paragraph));
+                    self.events.push(Event::Start(Tag::Strong));
+                    self.text(title);
+                    self.events.push(Event::End(TagEnd::Strong));
+                    self.events.push(Event::End(TagEnd::Paragraph));
+                }
+                if let Some(body) = rich_body {
+                    self.children(body);
+                }
+                self.events.push(Event::End(TagEnd::BlockQuote));
+            }
+            "expand" => {
+                self.events.push(Event::Html(CowStr::from(format!(
+                    "<details>\n<summary>{}</summary>\n",
+                    escape_xml(title.map_or("", |title| title.as_str()))
+                ))));
+                if let Some(body) = rich_body {
+                    self.children(body);
+                }
+                self.events.push(Event::Html(CowStr::from("</details>\n")));
+            }
+            "toc" => self
+                .events
+                .push(Event::Html(CowStr::from("<!-- toc -->\n"))),
+            "details" | "detailssummary" => {
+                let mut params: Vec<(String, String)> = params
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.clone()))
+                    .collect();
+                params.sort();
+                let directive = match name {
+                    "details" => Directive::Details(params),
+                    _ => Directive::Report(params),
+                };
+                self.events
+                    .push(Event::Html(CowStr::from(directive.to_html())));
+                if let Some(body) = rich_body {
+                    self.children(body);
+                }
+            }
+            "status" => {
+                let status = params.get("title").cloned().unwrap_or_default();
+                self.events.push(Event::Start(Tag::Strong));
+                self.text(&status.to_uppercase());
+                self.events.push(Event::End(TagEnd::Strong));
+            }
+            "jira" => {
+                let key = params.get("key").cloned().unwrap_or_default();
+                self.text(&key);
+            }
+            "anchor" => (),
+            _ => match plain_body {
+                // keep the content of unknown macros, labelled with the macro name
+                Some(body) => self.code_block(name, &body),
+                None => {
+                    let raw = scraper::ElementRef::wrap(node)
+                        .map(|elem| elem.html())
+                        .unwrap_or_default();
+                    self.events.push(Event::Html(CowStr::from(format!(
+                        "<!-- {} -->\n",
+                        raw.replace("--", "&#45;&#45;")
+                    ))));
+                }
+            },
+        }
+    }
+
+    /// Adds a fenced code block
+    ///
+    /// # Arguments
+    ///
+    /// * `lang` - the markdown code block language
+    /// * `code` - the code
+    fn code_block(&mut self, lang: &str, code: &str) {
+        let mut code = code.to_string();
+        if !code.ends_with('\n') {
+            code.push('\n');
+        }
+        self.events
+            .push(Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(
+                CowStr::from(lang.to_string()),
+            ))));
+        self.text(&code);
+        self.events.push(Event::End(TagEnd::CodeBlock));
+    }
+
+    /// Parses an attached or external image
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the `ac:image` element
+    fn image(&mut self, node: NodeRef<Node>) {
+        let url = find_descendant(&node, "ri:attachment")
+            .and_then(|attachment| attribute(&attachment, "ri:filename"))
+            .or_else(|| {
+                find_descendant(&node, "ri:url").and_then(|url| attribute(&url, "ri:value"))
+            })
+            .unwrap_or_default();
+        let mut image = Image::new(
+            url,
+            attribute(&node, "ac:alt").unwrap_or_default(),
+            attribute(&node, "ac:title").unwrap_or_default(),
+        );
+        for key in ["width", "height", "align"] {
+            image.set(
+                key,
+                attribute(&node, &format!("ac:{}", key)).unwrap_or_default(),
+            );
+        }
+        // markdown images can't have a size or alignment, but HTML ones can
+        if image.has_attributes() {
+            self.events
+                .push(Event::InlineHtml(CowStr::from(image.to_html())));
+            return;
+        }
+        self.events.push(Event::Start(Tag::Image {
+            link_type: LinkType::Inline,
+            dest_url: CowStr::from(image.source),
+            title: CowStr::from(image.title),
+            id: CowStr::from(""),
+        }));
+        self.text(&image.alt);
+        self.events.push(Event::End(TagEnd::Image));
+    }
+
+    /// Parses a link to a page, attachment or anchor
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the `ac:link` element
+    fn link(&mut self, node: NodeRef<Node>) {
+        let page =
+            find_descendant(&node, "ri:page").and_then(|page| attribute(&page, "ri:content-title"));
+        let attachment = find_descendant(&node, "ri:attachment")
+            .and_then(|attachment| attribute(&attachment, "ri:filename"));
+        let anchor = attribute(&node, "ac:anchor");
+        let mut url = page.or(attachment).unwrap_or_default().to_string();
+        if let Some(anchor) = anchor {
+            url = format!("{}#{}", url, anchor);
+        }
+        self.events.push(Event::Start(Tag::Link {
+            link_type: LinkType::Inline,
+            dest_url: CowStr::from(url.clone()),
+            title: CowStr::from(""),
+            id: CowStr::from(""),
+        }));
+        let body = find_descendant(&node, "ac:plain-text-link-body")
+            .or_else(|| find_descendant(&node, "ac:link-body"));
+        match body {
+            Some(body) => self.children(body),
+            None => self.text(page.or(attachment).or(anchor).unwrap_or(&url)),
+        }
+        self.events.push(Event::End(TagEnd::Link));
+    }
+
+    /// Wraps the children of a node in a start and end event
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the node
+    /// * `tag` - the tag to wrap the children in
+    fn wrapped(&mut self, node: NodeRef<Node>, tag: Tag<'static>) {
+        let end = tag.to_end();
+        self.events.push(Event::Start(tag));
+        self.children(node);
+        self.events.push(Event::End(end));
+    }
+
+    /// Parses a node and its children
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the node
+    fn node(&mut self, node: NodeRef<Node>) {
+        if let Node::Text(t) = node.value() {
+            // whitespace between blocks is formatting, not content
+            let after_block = node.prev_sibling().is_none_or(|s| is_block(&s));
+            let before_block = node.next_sibling().is_none_or(|s| is_block(&s));
+            let mut collapsed = t.split_whitespace().collect::<Vec<_>>().join(" ");
+            if t.starts_with(char::is_whitespace) && !after_block && !collapsed.is_empty() {
+                collapsed.insert(0, ' ');
+            }
+            if t.ends_with(char::is_whitespace) && !before_block && !collapsed.is_empty() {
+                collapsed.push(' ');
+            }
+            if collapsed.is_empty() && !after_block && !before_block {
+                collapsed.push(' ');
+            }
+            self.text(&collapsed);
+            return;
+        }
+        let Some(name) = element_name(&node) else {
+            return self.children(node);
+        };
+        match name {
+            "p" => self.wrapped(node, Tag::Paragraph),
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                let level = HeadingLevel::try_from(name[1..].parse::<usize>().unwrap()).unwrap();
+                self.wrapped(
+                    node,
+                    Tag::Heading {
+                        level,
+                        id: None,
+                        classes: vec![],
+                        attrs: vec![],
+                    },
+                );
+            }
+            "ul" | "ac:task-list" => self.wrapped(node, Tag::List(None)),
+            "ol" => {
+                let start = attribute(&node, "start")
+                    .and_then(|start| start.parse().ok())
+                    .unwrap_or(1);
+                self.wrapped(node, Tag::List(Some(start)));
+            }
+            "li" => {
+                self.events.push(Event::Start(Tag::Item));
+                self.unwrapped(node);
+                self.events.push(Event::End(TagEnd::Item));
+            }
+            "ac:task" => {
+                self.events.push(Event::Start(Tag::Item));
+                let status = find_descendant(&node, "ac:task-status");
+                let complete = status.is_some_and(|status| {
+                    status
+                        .children()
+                        .any(|t| matches!(t.value(), Node::Text(t) if t.trim() == "complete"))
+                });
+                self.events.push(Event::TaskListMarker(complete));
+                if let Some(body) = find_descendant(&node, "ac:task-body") {
+                    self.unwrapped(body);
+                }
+                self.events.push(Event::End(TagEnd::Item));
+            }
+            "blockquote" => self.wrapped(node, Tag::BlockQuote(None)),
+            "pre" => {
+                let mut code = String::new();
+                for descendant in node.descendants() {
+                    if let Node::Text(t) = descendant.value() {
+                        code.push_str(t);
+                    }
+                }
+                self.code_block("", &code);
+            }
+            "table" => self.table(node),
+            "hr" => self.events.push(Event::Rule),
+            "br" => self.events.push(Event::HardBreak),
+            "strong" | "b" => self.wrapped(node, Tag::Strong),
+            "em" | "i" => self.wrapped(node, Tag::Emphasis),
+            "s" | "del" => self.wrapped(node, Tag::Strikethrough),
+            "span"
+                if attribute(&node, "style")
+                    .is_some_and(|style| style.contains("line-through")) =>
+            {
+                self.wrapped(node, Tag::Strikethrough)
+            }
+            "u" | "sup" | "sub" => {
+                let tag = if name == "u" { "ins" } else { name };
+                self.events
+                    .push(Event::InlineHtml(CowStr::from(format!("<{}>", tag))));
+                self.children(node);
+                self.events
+                    .push(Event::InlineHtml(CowStr::from(format!("</{}>", tag))));
+            }
+            "code" => {
+                let mut code = String::new();
+                for descendant in node.descendants() {
+                    if let Node::Text(t) = descendant.value() {
+                        code.push_str(t);
+                    }
+                }
+                self.events.push(Event::Code(CowStr::from(code)));
+            }
+            "a" => {
+                let href = attribute(&node, "href").unwrap_or_default().to_string();
+                self.wrapped(
+                    node,
+                    Tag::Link {
+                        link_type: LinkType::Inline,
+                        dest_url: CowStr::from(href),
+                        title: CowStr::from(""),
+                        id: CowStr::from(""),
+                    },
+                );
+            }
+            "ac:structured-macro" => self.structured_macro(node),
+            "ac:image" => self.image(node),
+            "ac:link" => self.link(node),
+            "ac:emoticon" => {
+                let emoticon = attribute(&node, "ac:name").unwrap_or_default();
+                self.text(&format!(":{}:", emoticon));
+            }
+            "time" => self.text(attribute(&node, "datetime").unwrap_or_default()),
+            // layouts, divs and other containers only matter for their content
+            _ => self.children(node),
+        }
+    }
+}
+
+/// Wraps inline content that isn't in a block, like an image or a macro between paragraphs, in a
+/// paragraph of its own, so it isn't joined onto the paragraph before it
+///
+/// # Arguments
+///
+/// * `events` - the parsed events
+///
+/// # Returns
+///
+/// * `Vec<Event>` - the events, with each run of inline events outside a block in a paragraph
+fn implicit_paragraphs(events: Vec<Event<'static>>) -> Vec<Event<'static>> {
+    let mut wrapped = Vec::with_capacity(events.len());
+    // for each open tag, if it holds blocks
+    let mut open: Vec<bool> = vec![];
+    let mut in_paragraph = false;
+    for event in events {
+        if open.last().copied().unwrap_or(true) {
+            let inline = matches!(
+                event,
+                Event::Text(_)
+                    | Event::Code(_)
+                    | Event::InlineHtml(_)
+                    | Event::SoftBreak
+                    | Event::HardBreak
+                    | Event::FootnoteReference(_)
+                    | Event::Start(
+                        Tag::Emphasis
+                            | Tag::Strong
+                            | Tag::Strikethrough
+                            | Tag::Link { .. }
+                            | Tag::Image { .. }
+                    )
+            );
+            if inline != in_paragraph {
+                wrapped.push(if inline {
+                    Event::Start(Tag::Paragraph)
+                } else {
+                    Event::End(TagEnd::Paragraph)
+                });
+                in_paragraph = inline;
+            }
+        }
+        match &event {
+            Event::Start(tag) => open.push(matches!(tag, Tag::BlockQuote(_))),
+            Event::End(_) => {
+                open.pop();
+            }
+            _ => (),
+        }
+        wrapped.push(event);
+    }
+    if in_paragraph {
+        wrapped.push(Event::End(TagEnd::Paragraph));
+    }
+    wrapped
+}
+
+/// Parses Confluence storage format (XHTML) into pulldown-cmark events
+///
+/// # Arguments
+///
+/// * `input` - storage format
+/// * `lang_map` - map from markdown to Atlassian code block langs, used in reverse
+///
+/// # Returns
+///
+/// * `Vec<Event>` - events that can be given to any of the writers
+pub fn parse(input: &str, lang_map: &HashMap<String, String>) -> Vec<Event<'static>> {
+    let html = Html::parse_fragment(&normalize_storage(input));
+    let mut parser = StorageParser::new(lang_map);
+    parser.children(html.tree.root());
+    implicit_paragraphs(parser.events)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Renders `input` to storage format with default options
+    fn render(input: &str) -> String {
+        let mut output = Vec::new();
+        assert!(write(
+            &mut output,
+            Parser::new_ext(input, Options::all()),
+            &RenderOptions::new()
+        )
+        .is_ok());
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_headings() {
+        assert_eq!("<h1>hello world</h1>", render("# hello world"));
+        assert_eq!("<h2>hello world</h2>", render("## hello world"));
+    }
+
+    #[test]
+    fn test_modified_headings() {
+        let mut output = Vec::new();
+        let options = RenderOptions::new().modify_headers(-1);
+        assert!(write(
+            &mut output,
+            Parser::new_ext("# gone\n## kept", Options::all()),
+            &options
+        )
+        .is_ok());
+        assert_eq!("<h1>kept</h1>", String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn test_paragraph_escaping() {
+        assert_eq!("<p>a &lt;b&gt; &amp; c</p>", render(r#"a \<b\> & c"#));
+    }
+
+    #[test]
+    fn test_codeblock() {
+        let input = "\
+        ```console\n\
+        $ echo ']]>' && true\n\
+        ```";
+        assert_eq!(
+            "<ac:structured-macro ac:name=\"code\">\
+            <ac:parameter ac:name=\"language\">bash</ac:parameter>\
+            <ac:plain-text-body><![CDATA[$ echo ']]]]><![CDATA[>' && true\n]]></ac:plain-text-body>\
+            </ac:structured-macro>",
+            render(input)
+        );
+    }
+
+    #[test]
+    fn test_lists() {
+        assert_eq!(
+            "<ul><li>one<ol><li>nested</li></ol></li><li>two</li></ul>",
+            render("* one\n\t1. nested\n* two")
+        );
+    }
+
+    #[test]
+    fn test_table() {
+        let input = "\
+        | header 1 | header 2 |\n\
+        |----------|----------|\n\
+        | item 1   | item 2   |";
+        assert_eq!(
+            "<table><tbody><tr><th>header 1</th><th>header 2</th></tr>\
+            <tr><td>item 1</td><td>item 2</td></tr></tbody></table>",
+            render(input)
+        );
+    }
+
+    #[test]
+    fn test_heading_links() {
+        assert_eq!(
+            "<h1><ac:structured-macro ac:name=\"anchor\">\
+             <ac:parameter ac:name=\"\">setup</ac:parameter></ac:structured-macro>Setup</h1>\
+             <p><ac:link ac:anchor=\"setup\"><ac:link-body>see <em>setup</em></ac:link-body>\
+             </ac:link> <a href=\"#other\">other</a></p>",
+            render("# Setup\n[see _setup_](#setup) [other](#other)")
+        );
+        let mut output = Vec::new();
+        let options = RenderOptions::new().page_title("Ops Runbook");
+        assert!(write(
+            &mut output,
+            Parser::new_ext("# Setup\n[see setup](#setup)", Options::all()),
+            &options
+        )
+        .is_ok());
+        assert_eq!(
+            "<h1>Setup</h1><p><ac:link ac:anchor=\"OpsRunbook-Setup\">\
+             <ac:link-body>see setup</ac:link-body></ac:link></p>",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_relative_links() {
+        let mut output = Vec::new();
+        let options = RenderOptions::new()
+            .base_url("https://example.com/repo/docs")
+            .page("other.md", "Other Page");
+        assert!(write(
+            &mut output,
+            Parser::new_ext(
+                "[a](./other.md#setup) [b](../api.md) ![c](img/c.png)",
+                Options::all()
+            ),
+            &options
+        )
+        .is_ok());
+        assert_eq!(
+            "<p><ac:link ac:anchor=\"setup\"><ri:page ri:content-title=\"Other Page\" />\
+             <ac:link-body>a</ac:link-body></ac:link> <a href=\"https://example.com/repo/api.md\">b</a> \
+             <ac:image ac:alt=\"c\"><ri:attachment ri:filename=\"c.png\" /></ac:image></p>",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_ordered_list_start() {
+        assert_eq!(
+            "<ol><li>one</li></ol><p>between</p><ol start=\"2\"><li>two</li></ol>",
+            render("1. one\n\nbetween\n\n2. two")
+        );
+    }
+
+    #[test]
+    fn test_table_alignment_and_breaks() {
+        let input = "\
+        | left | center | right |\n\
+        |:-----|:------:|------:|\n\
+        | a<br>b | c | d |";
+        assert_eq!(
+            "<table><tbody><tr><th style=\"text-align: left;\">left</th>\
+            <th style=\"text-align: center;\">center</th>\
+            <th style=\"text-align: right;\">right</th></tr>\
+            <tr><td style=\"text-align: left;\">a<br />b</td>\
+            <td style=\"text-align: center;\">c</td>\
+            <td style=\"text-align: right;\">d</td></tr></tbody></table>",
+            render(input)
+        );
+    }
+
+    #[test]
+    fn test_inline() {
+        assert_eq!(
+            "<p><em>a</em> <strong>b</strong> \
+            <span style=\"text-decoration: line-through;\">c</span> <code>d&lt;</code> \
+            <a href=\"https://example.com?a&amp;b\">e</a></p>",
+            render("_a_ **b** ~~c~~ `d<` [e](https://example.com?a&b)")
+        );
+    }
+
+    #[test]
+    fn test_html() {
+        assert_eq!(
+            "<p>Press <code>Ctrl</code> for H<sub>2</sub>O, <a href=\"https://example.com\">\
+            a link</a><br />and mc<sup>2</sup></p>",
+            render(
+                "Press <kbd>Ctrl</kbd> for H<sub>2</sub>O, <a href=\"https://example.com\">a link\
+                </a><br>and mc<sup>2</sup>"
+            )
+        );
+        // blocks stay blocks, and loose inline content goes in a paragraph
+        assert_eq!(
+            "<table><tbody><tr><td><strong>a</strong></td></tr></tbody></table>\
+            <ac:structured-macro ac:name=\"code\"><ac:plain-text-body><![CDATA[x < y\n]]>\
+            </ac:plain-text-body></ac:structured-macro><p>loose <em>text</em></p>",
+            render(
+                "<table>\n<tr><td><b>a</b></td></tr>\n</table>\n\n<pre>\nx &lt; y\n</pre>\n\n\
+                <span>loose <i>text</i></span>"
+            )
+        );
+    }
+
+    #[test]
+    fn test_unbalanced_inline_html() {
+        // elements left open are closed with their block, and closing tags without an opening
+        // tag in the same markdown tag are left out
+        assert_eq!(
+            "<p>a <strong>bold</strong></p><p>b  c</p>\
+            <p><strong>x <em>y z</em> <a href=\"u\"><em>l</em></a></strong></p>",
+            render("a <b>bold\n\nb </kbd> c\n\n<b>x *y</b> z* <a href=\"u\"><i>l</a>")
+        );
+    }
+
+    #[test]
+    fn test_image() {
+        assert_eq!(
+            "<p><ac:image ac:alt=\"alt text\"><ri:url ri:value=\"https://example.com/image.jpg\" /></ac:image></p>",
+            render("![alt text](https://example.com/image.jpg)")
+        );
+        assert_eq!(
+            "<p><ac:image ac:alt=\"x y z\"><ri:attachment ri:filename=\"a.png\" /></ac:image></p>",
+            render("![*x* **y** ~~z~~](a.png)")
+        );
+    }
+
+    #[test]
+    fn test_image_attributes() {
+        assert_eq!(
+            "<p><ac:image ac:align=\"center\" ac:width=\"300\" ac:alt=\"a &amp; b\" ac:title=\"T\">\
+             <ri:url ri:value=\"https://example.com/a.png\" /></ac:image> and \
+             <ac:image ac:height=\"40\" ac:alt=\"\"><ri:url ri:value=\"https://example.com/b.png\" /></ac:image></p>",
+            render(
+                "![a & b](https://example.com/a.png \"T\"){width=300px align=center} and \
+                 <img src=\"https://example.com/b.png\" height=\"40\">"
+            )
+        );
+        // braces that aren't attributes are kept
+        assert_eq!(
+            "<p><ac:image ac:alt=\"\"><ri:url ri:value=\"https://example.com/a.png\" /></ac:image>{not this}</p>",
+            render("![](https://example.com/a.png){not this}")
+        );
+    }
+
+    #[test]
+    fn test_details_with_summary() {
+        assert_eq!(
+            "<ac:structured-macro ac:name=\"expand\">\
+            <ac:parameter ac:name=\"title\">Summary</ac:parameter>\
+            <ac:rich-text-body><p>Content</p></ac:rich-text-body></ac:structured-macro>",
+            render("<details><summary>Summary</summary>Content</details>")
+        );
+    }
+
+    #[test]
+    fn test_details_markdown() {
+        assert_eq!(
+            "<ac:structured-macro ac:name=\"expand\">\
+            <ac:parameter ac:name=\"title\">Steps</ac:parameter><ac:rich-text-body>\
+            <ul><li>one</li></ul><ac:structured-macro ac:name=\"expand\"><ac:rich-text-body>\
+            <p><strong>bold</strong></p></ac:rich-text-body></ac:structured-macro>\
+            </ac:rich-text-body></ac:structured-macro><p>after</p>",
+            render(
+                "<details>\n<summary>Steps</summary>\n\n- one\n\n<details>\n\n**bold**\n\n\
+                </details>\n</details>\n\nafter"
+            )
+        );
+    }
+
+    #[test]
+    fn test_task_lists() {
+        assert_eq!(
+            "<ac:task-list><ac:task><ac:task-id>1</ac:task-id><ac:task-status>incomplete</ac:task-status>\
+            <ac:task-body>todo<ac:task-list><ac:task><ac:task-id>2</ac:task-id>\
+            <ac:task-status>complete</ac:task-status><ac:task-body>nested</ac:task-body></ac:task>\
+            </ac:task-list></ac:task-body></ac:task></ac:task-list>",
+            render("- [ ] todo\n  - [x] nested")
+        );
+        // task lists can't hold normal items
+        assert_eq!(
+            "<ul><li>[x] done</li><li>plain</li></ul>",
+            render("- [x] done\n- plain")
+        );
+        // an item starting with a list isn't a task, even when the nested item is
+        assert_eq!(
+            "<ul><li><ac:task-list><ac:task><ac:task-id>1</ac:task-id><ac:task-status>complete\
+            </ac:task-status><ac:task-body>a</ac:task-body></ac:task></ac:task-list></li></ul>",
+            render("- [x] - [x] a")
+        );
+        // the paragraphs of a loose list aren't written around the task markers
+        assert_eq!(
+            "<ac:task-list><ac:task><ac:task-id>1</ac:task-id><ac:task-status>incomplete</ac:task-status>\
+            <ac:task-body>a<p>more</p></ac:task-body></ac:task><ac:task><ac:task-id>2</ac:task-id>\
+            <ac:task-status>complete</ac:task-status><ac:task-body>b</ac:task-body></ac:task></ac:task-list>",
+            render("- [ ] a\n\n  more\n- [x] b")
+        );
+    }
+
+    #[test]
+    fn test_alerts() {
+        assert_eq!(
+            "<ac:structured-macro ac:name=\"tip\"><ac:rich-text-body><p>hint</p></ac:rich-text-body>\
+            </ac:structured-macro>",
+            render("> [!TIP]\n> hint")
+        );
+        let mut output = Vec::new();
+        let options = RenderOptions::new().alert(BlockQuoteKind::Warning, "panel:title=Careful");
+        assert!(write(
+            &mut output,
+            Parser::new_ext("> [!WARNING]\n> hot", Options::all()),
+            &options
+        )
+        .is_ok());
+        assert_eq!(
+            "<ac:structured-macro ac:name=\"panel\"><ac:parameter ac:name=\"title\">Careful</ac:parameter>\
+            <ac:rich-text-body><p>hot</p></ac:rich-text-body></ac:structured-macro>",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_footnotes() {
+        let anchor = |name: &str| {
+            format!(
+                "<ac:structured-macro ac:name=\"anchor\">\
+                 <ac:parameter ac:name=\"\">{}</ac:parameter></ac:structured-macro>",
+                name
+            )
+        };
+        assert_eq!(
+            format!(
+                "<p>claim{}<sup><ac:link ac:anchor=\"fn-a\"><ac:link-body>1</ac:link-body>\
+                 </ac:link></sup> again<sup><ac:link ac:anchor=\"fn-a\"><ac:link-body>1\
+                 </ac:link-body></ac:link></sup></p><h2>Notes</h2><ol><li><p>{}source \
+                 <ac:link ac:anchor=\"fnref-a\"><ac:link-body>↩</ac:link-body></ac:link></p>\
+                 </li><li>{}<ul><li>x</li></ul></li></ol>",
+                anchor("fnref-a"),
+                anchor("fn-a"),
+                anchor("fn-b")
+            ),
+            render("claim[^a] again[^a]\n\n[^a]: source\n\n[^b]: - x\n")
+        );
+    }
+
+    #[test]
+    fn test_toc() {
+        let mut output = Vec::new();
+        assert!(write_toc(&mut output).is_ok());
+        assert_eq!(
+            "<ac:structured-macro ac:name=\"toc\" />",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    /// Converts storage format back to markdown with default options
+    fn import(input: &str) -> String {
+        let mut output = Vec::new();
+        let events = parse(input, &RenderOptions::new().language_map());
+        assert!(crate::markdown::write(&mut output, events.into_iter()).is_ok());
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_parse_inline() {
+        assert_eq!(
+            "**bold** _em_ ~~strike~~ `code` [link](https://example.com) <ins>u</ins>\n",
+            import(
+                "<p><strong>bold</strong> <em>em</em> <span style=\"text-decoration: line-through;\">strike</span> \
+                 <code>code</code> <a href=\"https://example.com\">link</a> <u>u</u></p>"
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_blocks() {
+        assert_eq!(
+            "## title\n\n```bash\nif a < b; then\n  ls\nfi\n```\n\n> [!WARNING]\n> careful\n\n---\n",
+            import(
+                "<h2>title</h2>\n<ac:structured-macro ac:name=\"code\" ac:schema-version=\"1\">\
+                 <ac:parameter ac:name=\"language\">bash</ac:parameter>\
+                 <ac:plain-text-body><![CDATA[if a < b; then\n  ls\nfi]]></ac:plain-text-body>\
+                 </ac:structured-macro>\n\
+                 <ac:structured-macro ac:name=\"warning\"><ac:rich-text-body><p>careful</p></ac:rich-text-body></ac:structured-macro>\n\
+                 <hr />"
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_top_level_inline() {
+        // images and macros between paragraphs are paragraphs of their own
+        assert_eq!(
+            "a\n\n![](d.png)\n\nb\n\nA-1 **DONE**\n\n> quoted\n",
+            import(
+                "<p>a</p><ac:image><ri:attachment ri:filename=\"d.png\"/></ac:image><p>b</p>\
+                 <ac:structured-macro ac:name=\"jira\"><ac:parameter ac:name=\"key\">A-1</ac:parameter></ac:structured-macro> \
+                 <ac:structured-macro ac:name=\"status\"><ac:parameter ac:name=\"title\">done</ac:parameter></ac:structured-macro>\
+                 <blockquote>quoted</blockquote>"
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_lists() {
+        assert_eq!(
+            "* one\n  1. nested\n* two\n\n* [x] done\n* [ ] todo\n",
+            import(
+                "<ul><li><p>one</p><ol><li>nested</li></ol></li><li>two</li></ul>\
+                 <ac:task-list>\
+                 <ac:task><ac:task-id>1</ac:task-id><ac:task-status>complete</ac:task-status><ac:task-body>done</ac:task-body></ac:task>\
+                 <ac:task><ac:task-id>2</ac:task-id><ac:task-status>incomplete</ac:task-status><ac:task-body>todo</ac:task-body></ac:task>\
+                 </ac:task-list>"
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_table() {
+        assert_eq!(
+            "| a | b |\n| --- | --- |\n| 1 | 2 |\n",
+            import(
+                "<table><tbody><tr><th><p>a</p></th><th>b</th></tr>\
+                 <tr><td>1</td><td><p>2</p></td></tr></tbody></table>"
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_table_alignment() {
+        assert_eq!(
+            "| a | b | c |\n| --- | :---: | ---: |\n| 1 | 2 | 3 |\n",
+            import(
+                "<table><tbody><tr><th>a</th><th style=\"text-align: center;\">b</th>\
+                 <th style=\"text-align: right\">c</th></tr>\
+                 <tr><td>1</td><td>2</td><td>3</td></tr></tbody></table>"
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_links_and_images() {
+        assert_eq!(
+            "[the page](<Other Page#setup>) ![diagram](arch.png) ![](https://example.com/x.png)\n",
+            import(
+                "<p><ac:link ac:anchor=\"setup\"><ri:page ri:content-title=\"Other Page\" />\
+                 <ac:plain-text-link-body><![CDATA[the page]]></ac:plain-text-link-body></ac:link> \
+                 <ac:image ac:alt=\"diagram\"><ri:attachment ri:filename=\"arch.png\" /></ac:image> \
+                 <ac:image><ri:url ri:value=\"https://example.com/x.png\" /></ac:image></p>"
+            )
+        );
+        assert_eq!(
+            "<img src=\"arch.png\" alt=\"diagram\" width=\"300\" align=\"right\" />\n",
+            import(
+                "<p><ac:image ac:align=\"right\" ac:width=\"300\" ac:alt=\"diagram\">\
+                 <ri:attachment ri:filename=\"arch.png\" /></ac:image></p>"
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_macros() {
+        assert_eq!(
+            "<details>\n<summary>More</summary>\n\nhidden\n\n</details>\n\n**DONE** PROJ-1\n\n```mermaid\ngraph TD\n```\n\n<!-- <ac:structured-macro ac:name=\"children\"></ac:structured-macro> -->\n",
+            import(
+                "<ac:structured-macro ac:name=\"expand\"><ac:parameter ac:name=\"title\">More</ac:parameter>\
+                 <ac:rich-text-body><p>hidden</p></ac:rich-text-body></ac:structured-macro>\
+                 <p><ac:structured-macro ac:name=\"status\"><ac:parameter ac:name=\"title\">done</ac:parameter></ac:structured-macro> \
+                 <ac:structured-macro ac:name=\"jira\"><ac:parameter ac:name=\"key\">PROJ-1</ac:parameter></ac:structured-macro></p>\
+                 <ac:structured-macro ac:name=\"mermaid\"><ac:plain-text-body><![CDATA[graph TD]]></ac:plain-text-body></ac:structured-macro>\
+                 <ac:structured-macro ac:name=\"children\" />"
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_round_trip() {
+        let markdown = "# title\n\nsome **bold** text\n\n* a\n* b\n\n```python\nprint(1)\n```\n";
+        assert_eq!(markdown, import(&render(markdown)));
+    }
+
+    #[test]
+    fn test_page_properties() {
+        let storage = "<ac:structured-macro ac:name=\"details\">\
+            <ac:parameter ac:name=\"id\">adr</ac:parameter><ac:rich-text-body><table><tbody>\
+            <tr><th>a</th></tr><tr><td>1</td></tr></tbody></table></ac:rich-text-body>\
+            </ac:structured-macro><ac:structured-macro ac:name=\"detailssummary\">\
+            <ac:parameter ac:name=\"cql\">label = &quot;adr&quot;</ac:parameter>\
+            </ac:structured-macro>";
+        let markdown = "<!-- details id=adr -->\n\n| a |\n| --- |\n| 1 |\n\n\
+            <!-- detailssummary cql='label = \"adr\"' -->\n";
+        assert_eq!(storage, render(markdown));
+        assert_eq!(markdown, import(storage));
+    }
+}
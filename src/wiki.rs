@@ -0,0 +1,1112 @@
+use pulldown_cmark::{
+    Alignment, BlockQuoteKind, CodeBlockKind, CowStr, Event, LinkType, Tag, TagEnd,
+};
+use std::collections::HashMap;
+
+use crate::atlassian::invert_lang_map;
+use crate::image::Image;
+use crate::properties::Directive;
+
+/// Block macros whose content is parsed as wiki markup
+const CONTAINER_MACROS: [&str; 8] = [
+    "quote", "panel", "info", "note", "tip", "warning", "expand", "details",
+];
+
+/// Inline formatting markers, and what they turn into
+const INLINE_MARKERS: [(&str, &str, &str); 7] = [
+    ("*", "**", "**"),
+    ("_", "_", "_"),
+    ("??", "_", "_"),
+    ("-", "~~", "~~"),
+    ("+", "<ins>", "</ins>"),
+    ("^", "<sup>", "</sup>"),
+    ("~", "<sub>", "</sub>"),
+];
+
+/// A block macro like `{code:java}`, split into its name and parameters
+struct Macro {
+    name: String,
+    // the parameter without a name, like `java` in `{code:java}`
+    default: Option<String>,
+    params: HashMap<String, String>,
+}
+
+impl Macro {
+    /// Parses the macro at the start of `line`
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - line starting with `{`
+    ///
+    /// # Returns
+    ///
+    /// * `Option` - the macro and the rest of the line, if `line` starts with a macro
+    fn parse(line: &str) -> Option<(Macro, &str)> {
+        let inner = line.strip_prefix('{')?;
+        let end = inner.find('}')?;
+        let (header, rest) = (&inner[..end], &inner[end + 1..]);
+        let name_end = header.find([':', '|']).unwrap_or(header.len());
+        let name = header[..name_end].trim().to_lowercase();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        let mut default = None;
+        let mut params = HashMap::new();
+        if name_end < header.len() {
+            for param in header[name_end + 1..].split('|') {
+                match param.split_once('=') {
+                    Some((key, value)) => {
+                        params.insert(key.trim().to_lowercase(), value.trim().to_string());
+                    }
+                    None if !param.trim().is_empty() => default = Some(param.trim().to_string()),
+                    None => (),
+                }
+            }
+        }
+        Some((
+            Macro {
+                name,
+                default,
+                params,
+            },
+            rest,
+        ))
+    }
+
+    /// Returns a named parameter, falling back to the unnamed one
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - the parameter name
+    fn param(&self, key: &str) -> Option<&str> {
+        self.params
+            .get(key)
+            .or(self.default.as_ref())
+            .map(|s| s.as_str())
+    }
+
+    /// Makes the page properties directive for a `{details}` or `{detailssummary}` macro, whose
+    /// parameter values may have entities, like `&#124;` for a pipe
+    fn directive(&self) -> Directive {
+        let mut params: Vec<(String, String)> = self
+            .params
+            .iter()
+            .map(|(key, value)| (key.clone(), unescape(value)))
+            .collect();
+        params.sort();
+        if self.name == "details" {
+            Directive::Details(params)
+        } else {
+            Directive::Report(params)
+        }
+    }
+}
+
+/// Makes an owned text event
+///
+/// # Arguments
+///
+/// * `s` - the text
+fn text(s: &str) -> Event<'static> {
+    Event::Text(CowStr::from(s.to_string()))
+}
+
+/// Decodes the escapes markrust and Atlassian products put into text
+///
+/// # Arguments
+///
+/// * `s` - escaped text
+///
+/// # Returns
+///
+/// * `String` - the original text
+fn unescape(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find(['\\', '&']) {
+        r.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(escaped) = rest.strip_prefix('\\') {
+            match escaped.chars().next() {
+                Some(c) => {
+                    r.push(c);
+                    rest = &escaped[c.len_utf8()..];
+                }
+                None => {
+                    r.push('\\');
+                    rest = escaped;
+                }
+            }
+        } else if let Some((entity, after)) = rest[1..].split_once(';') {
+            let decoded = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                _ => entity
+                    .strip_prefix('#')
+                    .and_then(|n| n.parse::<u32>().ok())
+                    .and_then(char::from_u32),
+            };
+            match decoded {
+                Some(c) => {
+                    r.push(c);
+                    rest = after;
+                }
+                None => {
+                    r.push('&');
+                    rest = &rest[1..];
+                }
+            }
+        } else {
+            r.push('&');
+            rest = &rest[1..];
+        }
+    }
+    r.push_str(rest);
+    r
+}
+
+/// Splits a table row into cells, skipping separators inside links and monospace
+///
+/// # Arguments
+///
+/// * `line` - the row, starting with `|` or `||`
+///
+/// # Returns
+///
+/// * `Vec` - each cell's content and if it is a header cell
+fn split_row(line: &str) -> Vec<(String, bool)> {
+    let mut cells = vec![];
+    let mut current = String::new();
+    let mut header = false;
+    let mut depth = 0;
+    let mut chars = line.trim_end().chars().peekable();
+    let mut started = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                current.push(c);
+                if let Some(next) = chars.next() {
+                    current.push(next);
+                }
+            }
+            '[' => {
+                depth += 1;
+                current.push(c);
+            }
+            ']' => {
+                depth -= 1;
+                current.push(c);
+            }
+            '{' if chars.peek() == Some(&'{') => {
+                depth += 1;
+                current.push(c);
+            }
+            '}' if chars.peek() == Some(&'}') && depth > 0 => {
+                depth -= 1;
+                current.push(c);
+            }
+            '|' if depth <= 0 => {
+                let next_header = chars.peek() == Some(&'|');
+                if next_header {
+                    chars.next();
+                }
+                if started {
+                    cells.push((current.trim().to_string(), header));
+                }
+                current = String::new();
+                header = next_header;
+                started = true;
+            }
+            _ => current.push(c),
+        }
+    }
+    if !current.trim().is_empty() {
+        cells.push((current.trim().to_string(), header));
+    }
+    cells
+}
+
+/// Finds where an inline formatting marker closes
+///
+/// # Arguments
+///
+/// * `s` - text after the opening marker
+/// * `marker` - the marker
+///
+/// # Returns
+///
+/// * `Option` - byte offset of the closing marker
+fn find_closing(s: &str, marker: &str) -> Option<usize> {
+    let mut start = 0;
+    while let Some(i) = s[start..].find(marker) {
+        let i = start + i;
+        let before = s[..i].chars().next_back();
+        let after = s[i + marker.len()..].chars().next();
+        if i > 0
+            && before.is_some_and(|c| !c.is_whitespace())
+            && !after.is_some_and(|c| c.is_alphanumeric())
+        {
+            return Some(i);
+        }
+        start = i + marker.len();
+    }
+    None
+}
+
+/// Parses wiki markup into pulldown-cmark events
+struct WikiParser {
+    events: Vec<Event<'static>>,
+    // map between confluence/markdown code block langs
+    lang_map: HashMap<String, String>,
+    // lines of the current paragraph
+    paragraph: Vec<String>,
+    // kinds of open lists, each with an open item
+    list_stack: Vec<char>,
+    // paragraphs of the current list item that haven't been written yet, as lines
+    item: Vec<Vec<String>>,
+    // if the current list item has more than one paragraph, so they need paragraph tags
+    loose_item: bool,
+    // ranges of `events` with the current item's text that was written without paragraph tags,
+    // before a block in the item, to be wrapped if a later paragraph makes the item loose
+    item_runs: Vec<(usize, usize)>,
+}
+
+impl WikiParser {
+    /// return a new WikiParser
+    ///
+    /// # Arguments
+    ///
+    /// * `lang_map` - map from markdown to Atlassian code block langs, which is inverted
+    fn new(lang_map: &HashMap<String, String>) -> Self {
+        WikiParser {
+            events: vec![],
+            lang_map: invert_lang_map(lang_map),
+            paragraph: vec![],
+            list_stack: vec![],
+            item: vec![],
+            loose_item: false,
+            item_runs: vec![],
+        }
+    }
+
+    /// Parses lines of inline markup, which are separated by line breaks
+    ///
+    /// # Arguments
+    ///
+    /// * `lines` - the lines
+    fn parse_lines(&mut self, lines: &[String]) {
+        for (i, line) in lines.iter().enumerate() {
+            if i > 0 {
+                // a newline in wiki markup is a line break
+                self.events.push(Event::HardBreak);
+            }
+            self.parse_inline(line);
+        }
+    }
+
+    /// Writes out the paragraphs of the current list item, if any
+    fn flush_item(&mut self) {
+        if self.loose_item {
+            for (start, end) in std::mem::take(&mut self.item_runs).into_iter().rev() {
+                self.events.insert(end, Event::End(TagEnd::Paragraph));
+                self.events.insert(start, Event::Start(Tag::Paragraph));
+            }
+        }
+        for lines in std::mem::take(&mut self.item) {
+            if self.loose_item {
+                self.events.push(Event::Start(Tag::Paragraph));
+                self.parse_lines(&lines);
+                self.events.push(Event::End(TagEnd::Paragraph));
+            } else {
+                let start = self.events.len();
+                self.parse_lines(&lines);
+                self.item_runs.push((start, self.events.len()));
+            }
+        }
+    }
+
+    /// Adds a line following a list item to it, since only a blank line ends a list
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - the line, which starts a new paragraph if it starts with `\\`
+    fn continue_item(&mut self, line: &str) {
+        match line.strip_prefix("\\\\") {
+            Some(rest) => {
+                self.loose_item = true;
+                self.item.push(vec![rest.trim_start().to_string()]);
+            }
+            None => match self.item.last_mut() {
+                Some(lines) => lines.push(line.to_string()),
+                None => self.item.push(vec![line.to_string()]),
+            },
+        }
+    }
+
+    /// Writes out the current paragraph, if any
+    fn flush_paragraph(&mut self) {
+        if self.paragraph.is_empty() {
+            return;
+        }
+        let lines = std::mem::take(&mut self.paragraph);
+        self.events.push(Event::Start(Tag::Paragraph));
+        self.parse_lines(&lines);
+        self.events.push(Event::End(TagEnd::Paragraph));
+    }
+
+    /// Closes lists until only `depth` are open
+    ///
+    /// # Arguments
+    ///
+    /// * `depth` - how many lists to leave open
+    fn close_lists(&mut self, depth: usize) {
+        self.flush_item();
+        while self.list_stack.len() > depth {
+            let kind = self.list_stack.pop().unwrap();
+            self.events.push(Event::End(TagEnd::Item));
+            self.events.push(Event::End(TagEnd::List(kind == '#')));
+        }
+    }
+
+    /// Ends whatever paragraph or list is open
+    fn flush(&mut self) {
+        self.flush_paragraph();
+        self.close_lists(0);
+    }
+
+    /// Parses a list item line
+    ///
+    /// # Arguments
+    ///
+    /// * `markers` - the bullets, like `*#`
+    /// * `content` - the item text
+    fn parse_list_item(&mut self, markers: &str, content: &str) {
+        self.flush_paragraph();
+        self.flush_item();
+        // `-` is just another way to write `*`
+        let markers: Vec<char> = markers
+            .chars()
+            .map(|c| if c == '-' { '*' } else { c })
+            .collect();
+        let common = self
+            .list_stack
+            .iter()
+            .zip(markers.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        self.close_lists(common);
+        if self.list_stack.len() == markers.len() {
+            // a sibling of the current item
+            self.events.push(Event::End(TagEnd::Item));
+            self.events.push(Event::Start(Tag::Item));
+        }
+        for &kind in &markers[self.list_stack.len()..] {
+            let first_number = if kind == '#' { Some(1) } else { None };
+            self.events.push(Event::Start(Tag::List(first_number)));
+            self.events.push(Event::Start(Tag::Item));
+            self.list_stack.push(kind);
+        }
+        // tasks are written as emoticons in lists, or as their own `[] ` lines
+        let task = [
+            ("(/) ", true),
+            ("(off) ", false),
+            ("[x] ", true),
+            ("[] ", false),
+        ]
+        .into_iter()
+        .find_map(|(marker, checked)| Some((content.strip_prefix(marker)?, checked)));
+        let content = match task {
+            Some((content, checked)) => {
+                self.events.push(Event::TaskListMarker(checked));
+                content
+            }
+            None => content,
+        };
+        // the item's text is written once we know if it has more paragraphs
+        self.loose_item = false;
+        self.item_runs.clear();
+        self.item = vec![vec![content.to_string()]];
+    }
+
+    /// Parses a table row
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - the row
+    /// * `first` - if this is the first row of the table
+    fn parse_table_row(&mut self, line: &str, first: bool) -> usize {
+        let cells = split_row(line);
+        if first {
+            self.events
+                .push(Event::Start(Tag::Table(vec![Alignment::None; cells.len()])));
+            if cells.iter().all(|(_, header)| *header) {
+                self.events.push(Event::Start(Tag::TableHead));
+                for (cell, _) in &cells {
+                    self.events.push(Event::Start(Tag::TableCell));
+                    self.parse_inline(cell);
+                    self.events.push(Event::End(TagEnd::TableCell));
+                }
+                self.events.push(Event::End(TagEnd::TableHead));
+                return cells.len();
+            }
+            // markdown tables need a header, so give it an empty one
+            self.events.push(Event::Start(Tag::TableHead));
+            for _ in &cells {
+                self.events.push(Event::Start(Tag::TableCell));
+                self.events.push(Event::End(TagEnd::TableCell));
+            }
+            self.events.push(Event::End(TagEnd::TableHead));
+        }
+        self.events.push(Event::Start(Tag::TableRow));
+        for (cell, header) in &cells {
+            self.events.push(Event::Start(Tag::TableCell));
+            if *header {
+                self.events.push(Event::Start(Tag::Strong));
+                self.parse_inline(cell);
+                self.events.push(Event::End(TagEnd::Strong));
+            } else {
+                self.parse_inline(cell);
+            }
+            self.events.push(Event::End(TagEnd::TableCell));
+        }
+        self.events.push(Event::End(TagEnd::TableRow));
+        cells.len()
+    }
+
+    /// Parses a code or noformat macro's content
+    ///
+    /// # Arguments
+    ///
+    /// * `code_macro` - the opening macro
+    /// * `body` - the raw content
+    fn parse_code(&mut self, code_macro: &Macro, body: &str) {
+        let lang = match code_macro.name.as_str() {
+            "code" => code_macro
+                .params
+                .get("language")
+                .or(code_macro.params.get("lang"))
+                .or(code_macro.default.as_ref())
+                .map(|lang| {
+                    let lang = lang.to_lowercase();
+                    self.lang_map.get(&lang).cloned().unwrap_or(lang)
+                })
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        let mut body = body.to_string();
+        if !body.ends_with('\n') {
+            body.push('\n');
+        }
+        self.events
+            .push(Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(
+                CowStr::from(lang),
+            ))));
+        self.events.push(text(&body));
+        self.events.push(Event::End(TagEnd::CodeBlock));
+    }
+
+    /// Parses a container macro like `{quote}` or `{info}`, whose content is wiki markup
+    ///
+    /// # Arguments
+    ///
+    /// * `container` - the opening macro
+    /// * `body` - the wiki markup content
+    fn parse_container(&mut self, container: &Macro, body: &str) {
+        let title = container.param("title").map(|s| s.to_string());
+        if container.name == "details" {
+            // the table in it is written in the macro again
+            self.events
+                .push(Event::Html(CowStr::from(container.directive().to_html())));
+            self.parse_blocks(body);
+            return;
+        }
+        if container.name == "expand" {
+            self.events.push(Event::Html(CowStr::from(format!(
+                "<details>\n<summary>{}</summary>\n",
+                title.unwrap_or_default()
+            ))));
+            let lists = std::mem::take(&mut self.list_stack);
+            self.parse_blocks(body);
+            self.list_stack = lists;
+            self.events.push(Event::Html(CowStr::from("</details>\n")));
+            return;
+        }
+        let mut title = title;
+        let kind = match container.name.as_str() {
+            "info" => Some(BlockQuoteKind::Note),
+            "tip" => Some(BlockQuoteKind::Tip),
+            "note" => Some(BlockQuoteKind::Important),
+            "warning" => Some(BlockQuoteKind::Warning),
+            // alerts are written as panels titled with their kind for jira
+            "panel" => {
+                let kind = match title.as_deref() {
+                    Some("Note") => Some(BlockQuoteKind::Note),
+                    Some("Tip") => Some(BlockQuoteKind::Tip),
+                    Some("Important") => Some(BlockQuoteKind::Important),
+                    Some("Warning") => Some(BlockQuoteKind::Warning),
+                    Some("Caution") => Some(BlockQuoteKind::Caution),
+                    _ => None,
+                };
+                if kind.is_some() {
+                    title = None;
+                }
+                kind
+            }
+            _ => None,
+        };
+        self.events.push(Event::Start(Tag::BlockQuote(kind)));
+        if let Some(title) = title {
+            self.events.push(Event::Start(Tag::Paragraph));
+            self.events.push(Event::Start(Tag::Strong));
+            self.events.push(text(&title));
+            self.events.push(Event::End(TagEnd::Strong));
+            self.events.push(Event::End(TagEnd::Paragraph));
+        }
+        // lists in the quote are separate from any the quote is in
+        let lists = std::mem::take(&mut self.list_stack);
+        self.parse_blocks(body);
+        self.list_stack = lists;
+        self.events.push(Event::End(TagEnd::BlockQuote));
+    }
+
+    /// Parses block-level wiki markup
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - wiki markup
+    fn parse_blocks(&mut self, input: &str) {
+        let lines: Vec<&str> = input.lines().collect();
+        let mut i = 0;
+        let mut table_columns = 0;
+        while i < lines.len() {
+            let line = lines[i].trim();
+            i += 1;
+            if !line.starts_with('|') && table_columns > 0 {
+                self.events.push(Event::End(TagEnd::Table));
+                table_columns = 0;
+            }
+            if line.is_empty() {
+                self.flush();
+                continue;
+            }
+            // headings
+            if let Some(level) = line
+                .strip_prefix('h')
+                .and_then(|rest| rest.get(..2))
+                .filter(|prefix| prefix.ends_with('.'))
+                .and_then(|prefix| prefix[..1].parse::<usize>().ok())
+                .filter(|level| (1..=6).contains(level))
+            {
+                self.flush();
+                let level = pulldown_cmark::HeadingLevel::try_from(level).unwrap();
+                self.events.push(Event::Start(Tag::Heading {
+                    level,
+                    id: None,
+                    classes: vec![],
+                    attrs: vec![],
+                }));
+                self.parse_inline(line[3..].trim());
+                self.events.push(Event::End(TagEnd::Heading(level)));
+                continue;
+            }
+            if let Some(quote) = line.strip_prefix("bq. ") {
+                self.flush();
+                self.events.push(Event::Start(Tag::BlockQuote(None)));
+                self.paragraph.push(quote.to_string());
+                self.flush_paragraph();
+                self.events.push(Event::End(TagEnd::BlockQuote));
+                continue;
+            }
+            if line == "----" {
+                self.flush();
+                self.events.push(Event::Rule);
+                continue;
+            }
+            // block macros, which run until the closing macro
+            if let Some((block_macro, rest)) = Macro::parse(line) {
+                if block_macro.name == "detailssummary" && rest.trim().is_empty() {
+                    self.flush();
+                    self.events
+                        .push(Event::Html(CowStr::from(block_macro.directive().to_html())));
+                    continue;
+                }
+                let is_code = matches!(block_macro.name.as_str(), "code" | "noformat");
+                if is_code || CONTAINER_MACROS.contains(&block_macro.name.as_str()) {
+                    // a macro right after a list item is part of it
+                    if self.list_stack.is_empty() {
+                        self.flush();
+                    } else {
+                        self.flush_item();
+                    }
+                    let closing = format!("{{{}}}", block_macro.name);
+                    let mut body = String::new();
+                    let mut rest = rest.to_string();
+                    loop {
+                        if let Some(end) = rest.find(&closing) {
+                            body.push_str(&rest[..end]);
+                            break;
+                        }
+                        if !rest.is_empty() || !body.is_empty() {
+                            body.push_str(&rest);
+                            body.push('\n');
+                        }
+                        match lines.get(i) {
+                            Some(next) => {
+                                rest = next.to_string();
+                                i += 1;
+                            }
+                            // unclosed macros run until the end of the document
+                            None => break,
+                        }
+                    }
+                    if is_code {
+                        self.parse_code(&block_macro, &body);
+                    } else {
+                        self.parse_container(&block_macro, &body);
+                    }
+                    continue;
+                }
+            }
+            // confluence task lists
+            if line.starts_with("[] ") || line.starts_with("[x] ") {
+                self.parse_list_item("*", line);
+                continue;
+            }
+            // lists
+            let markers_end = line
+                .find(|c: char| !matches!(c, '*' | '#' | '-'))
+                .unwrap_or(line.len());
+            if markers_end > 0
+                && line[markers_end..].starts_with(' ')
+                && (!line.starts_with('-') || markers_end == 1)
+            {
+                self.parse_list_item(&line[..markers_end], line[markers_end..].trim());
+                continue;
+            }
+            // tables
+            if line.starts_with('|') {
+                if self.list_stack.is_empty() {
+                    self.flush();
+                } else {
+                    self.flush_item();
+                }
+                let columns = self.parse_table_row(line, table_columns == 0);
+                table_columns = table_columns.max(columns);
+                continue;
+            }
+            // everything else is a paragraph, or more of the list item it follows
+            if !self.list_stack.is_empty() {
+                self.continue_item(line);
+                continue;
+            }
+            // a line break at the start of a line sets apart a new paragraph
+            match line.strip_prefix("\\\\") {
+                Some(rest) if !self.paragraph.is_empty() => {
+                    self.flush_paragraph();
+                    self.paragraph.push(rest.trim_start().to_string());
+                }
+                _ => self.paragraph.push(line.to_string()),
+            }
+        }
+        if table_columns > 0 {
+            self.events.push(Event::End(TagEnd::Table));
+        }
+        self.flush();
+    }
+
+    /// Parses inline wiki markup
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - inline wiki markup
+    fn parse_inline(&mut self, s: &str) {
+        let mut buffer = String::new();
+        let mut i = 0;
+        let mut previous: Option<char> = None;
+        while i < s.len() {
+            let rest = &s[i..];
+            let c = rest.chars().next().unwrap();
+            let word_start = !previous.is_some_and(|p| p.is_alphanumeric());
+
+            // line breaks and escapes
+            if rest.starts_with("\\\\") {
+                self.flush_text(&mut buffer);
+                self.events.push(Event::HardBreak);
+                i += 2;
+                previous = None;
+                continue;
+            }
+            if c == '\\' {
+                if let Some(escaped) = rest[1..].chars().next() {
+                    buffer.push(escaped);
+                    i += 1 + escaped.len_utf8();
+                    previous = Some(escaped);
+                    continue;
+                }
+            }
+            // monospace
+            if let Some(code) = rest.strip_prefix("{{") {
+                if let Some(end) = code.find("}}") {
+                    self.flush_text(&mut buffer);
+                    self.events
+                        .push(Event::Code(CowStr::from(unescape(&code[..end]))));
+                    i += 2 + end + 2;
+                    previous = Some('}');
+                    continue;
+                }
+            }
+            // inline macros like {color:red} are dropped, their content is kept
+            if c == '{' {
+                if let Some((inline_macro, _)) = Macro::parse(rest) {
+                    if matches!(inline_macro.name.as_str(), "color" | "anchor") {
+                        i += rest.find('}').unwrap() + 1;
+                        continue;
+                    }
+                }
+            }
+            // links
+            if c == '[' {
+                if let Some(end) = rest.find(']') {
+                    let content = &rest[1..end];
+                    if !content.is_empty() {
+                        self.flush_text(&mut buffer);
+                        self.parse_link(content);
+                        i += end + 1;
+                        previous = Some(']');
+                        continue;
+                    }
+                }
+            }
+            // images
+            if c == '!' && word_start {
+                if let Some(end) = rest[1..].find('!') {
+                    let content = &rest[1..end + 1];
+                    let url = content.split('|').next().unwrap_or_default();
+                    if !url.is_empty() && !url.contains(char::is_whitespace) {
+                        self.flush_text(&mut buffer);
+                        self.parse_image(content);
+                        i += end + 2;
+                        previous = Some('!');
+                        continue;
+                    }
+                }
+            }
+            // formatting
+            if word_start {
+                let marker = INLINE_MARKERS
+                    .iter()
+                    .find(|(marker, _, _)| rest.starts_with(marker));
+                if let Some(&(marker, open, close)) = marker {
+                    let inner = &rest[marker.len()..];
+                    if inner.chars().next().is_some_and(|c| !c.is_whitespace()) {
+                        if let Some(end) = find_closing(inner, marker) {
+                            self.flush_text(&mut buffer);
+                            self.start_format(open);
+                            self.parse_inline(&inner[..end]);
+                            self.end_format(close);
+                            i += marker.len() * 2 + end;
+                            previous = marker.chars().last();
+                            continue;
+                        }
+                    }
+                }
+            }
+            buffer.push(c);
+            i += c.len_utf8();
+            previous = Some(c);
+        }
+        self.flush_text(&mut buffer);
+    }
+
+    /// Writes out buffered plain text
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - the text, which is cleared
+    fn flush_text(&mut self, buffer: &mut String) {
+        if !buffer.is_empty() {
+            let decoded = unescape(&std::mem::take(buffer));
+            self.events.push(text(&decoded));
+        }
+    }
+
+    /// Opens inline formatting
+    ///
+    /// # Arguments
+    ///
+    /// * `open` - the markdown for the formatting
+    fn start_format(&mut self, open: &str) {
+        self.events.push(match open {
+            "**" => Event::Start(Tag::Strong),
+            "_" => Event::Start(Tag::Emphasis),
+            "~~" => Event::Start(Tag::Strikethrough),
+            html => Event::InlineHtml(CowStr::from(html.to_string())),
+        });
+    }
+
+    /// Closes inline formatting
+    ///
+    /// # Arguments
+    ///
+    /// * `close` - the markdown for the formatting
+    fn end_format(&mut self, close: &str) {
+        self.events.push(match close {
+            "**" => Event::End(TagEnd::Strong),
+            "_" => Event::End(TagEnd::Emphasis),
+            "~~" => Event::End(TagEnd::Strikethrough),
+            html => Event::InlineHtml(CowStr::from(html.to_string())),
+        });
+    }
+
+    /// Parses the content of a `[text|url]` link
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - everything between the brackets
+    fn parse_link(&mut self, content: &str) {
+        let parts: Vec<&str> = content.splitn(3, '|').collect();
+        let (label, target) = match parts.as_slice() {
+            [target] => (None, target.trim()),
+            [label, target, ..] => (Some(label.trim()), target.trim()),
+            _ => unreachable!(),
+        };
+        // user mentions don't have a url
+        if let Some(user) = target.strip_prefix('~') {
+            let user = user.strip_prefix("accountid:").unwrap_or(user);
+            self.events
+                .push(text(&format!("@{}", label.unwrap_or(user))));
+            return;
+        }
+        let url = target.strip_prefix('^').unwrap_or(target);
+        self.events.push(Event::Start(Tag::Link {
+            link_type: LinkType::Inline,
+            dest_url: CowStr::from(url.to_string()),
+            title: CowStr::from(""),
+            id: CowStr::from(""),
+        }));
+        match label {
+            Some(label) => self.parse_inline(label),
+            None => self.events.push(text(url)),
+        }
+        self.events.push(Event::End(TagEnd::Link));
+    }
+
+    /// Parses the content of a `!url|attributes!` image
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - everything between the exclamation marks
+    fn parse_image(&mut self, content: &str) {
+        let (url, attributes) = content.split_once('|').unwrap_or((content, ""));
+        let mut image = Image::new(url, "", "");
+        for attribute in attributes.split(',') {
+            if let Some((key, value)) = attribute.split_once('=') {
+                // quotes, commas and equals signs in values are entities
+                let value = unescape(value.trim().trim_matches('"'));
+                match key.trim() {
+                    "alt" => image.alt = value,
+                    "title" => image.title = value,
+                    key => image.set(key, &value),
+                }
+            }
+        }
+        // markdown images can't have a size or alignment, but HTML ones can
+        if image.has_attributes() {
+            self.events
+                .push(Event::InlineHtml(CowStr::from(image.to_html())));
+            return;
+        }
+        self.events.push(Event::Start(Tag::Image {
+            link_type: LinkType::Inline,
+            dest_url: CowStr::from(image.source),
+            title: CowStr::from(image.title),
+            id: CowStr::from(""),
+        }));
+        if !image.alt.is_empty() {
+            self.events.push(text(&image.alt));
+        }
+        self.events.push(Event::End(TagEnd::Image));
+    }
+}
+
+/// Parses Jira/Confluence wiki markup into pulldown-cmark events
+///
+/// # Arguments
+///
+/// * `input` - wiki markup
+/// * `lang_map` - map from markdown to Atlassian code block langs, used in reverse
+///
+/// # Returns
+///
+/// * `Vec<Event>` - events that can be given to any of the writers
+pub fn parse(input: &str, lang_map: &HashMap<String, String>) -> Vec<Event<'static>> {
+    let mut parser = WikiParser::new(lang_map);
+    parser.parse_blocks(input);
+    parser.events
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::markdown;
+    use crate::options::RenderOptions;
+
+    /// Converts wiki markup to markdown
+    fn render(input: &str) -> String {
+        let mut output = Vec::new();
+        let events = parse(input, &RenderOptions::new().language_map());
+        assert!(markdown::write(&mut output, events.into_iter()).is_ok());
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_headings() {
+        assert_eq!("# hello world\n", render("h1. hello world"));
+        assert_eq!("###### hello world\n", render("h6. hello world"));
+    }
+
+    #[test]
+    fn test_inline() {
+        assert_eq!(
+            "**bold** _em_ ~~strike~~ `{mono}` _cite_ <sup>up</sup> <ins>in</ins>\n",
+            render("*bold* _em_ -strike- {{&#123;mono&#125;}} ??cite?? ^up^ +in+")
+        );
+        // markers inside words and dashes aren't formatting
+        assert_eq!("snake_case_name a - b\n", render("snake_case_name a - b"));
+        // a number at the start of a line isn't a markdown list
+        assert_eq!("1\\. not a list\n", render("1. not a list"));
+    }
+
+    #[test]
+    fn test_links_and_images() {
+        assert_eq!(
+            "[text](https://example.com) [https://example.com](https://example.com) @jdoe\n",
+            render("[text|https://example.com] [https://example.com] [~jdoe]")
+        );
+        assert_eq!(
+            "![a diagram](diagram.png)\n",
+            render("!diagram.png|alt=\"a diagram\"!")
+        );
+        assert_eq!(
+            "![a, \"b\"](a.png \"T\")\n",
+            render("!a.png|alt=\"a&#44; &quot;b&quot;\",title=\"T\"!")
+        );
+        // markdown images have no size, so sized images come back as HTML
+        assert_eq!(
+            "<img src=\"a.png\" alt=\"a\" width=\"300\" align=\"center\" />\n",
+            render("!a.png|width=300,align=center,alt=\"a\"!")
+        );
+    }
+
+    #[test]
+    fn test_nested_lists() {
+        assert_eq!(
+            "* one\n  * nested\n    1. numbered\n* two\n",
+            render("* one\n** nested\n**# numbered\n- two")
+        );
+    }
+
+    #[test]
+    fn test_list_item_blocks() {
+        // lines up to a blank line belong to the item, and `\\` starts a new paragraph
+        assert_eq!(
+            "1. one\n\n   more\n\n   ```bash\n   ls\n   ```\n\n2. two\n",
+            render("# one\n\\\\more\n{code:bash}\nls\n{code}\n# two")
+        );
+        assert_eq!(
+            "* one\\\n  next line\n  > quoted\n\n* two\n",
+            render("* one\nnext line\n{quote}\nquoted\n{quote}\n* two")
+        );
+        assert_eq!("* one\n\nafter\n", render("* one\n\nafter"));
+    }
+
+    #[test]
+    fn test_task_lists() {
+        assert_eq!("* [ ] todo\n* [x] done\n", render("[] todo\n[x] done"));
+        assert_eq!(
+            "* [x] done\n  * [ ] nested\n* plain\n",
+            render("* (/) done\n** (off) nested\n* plain")
+        );
+    }
+
+    #[test]
+    fn test_table() {
+        assert_eq!(
+            "| h1 | h2 |\n| --- | --- |\n| a | [x](y) |\n",
+            render("||h1||h2||\n|a|[x|y]|")
+        );
+        // tables without a header row get an empty one
+        assert_eq!("|  |\n| --- |\n| a |\n", render("|a|"));
+    }
+
+    #[test]
+    fn test_code_blocks() {
+        // confluence and jira syntax both work, and the language is mapped back
+        assert_eq!(
+            "```bash\n$ ls\n```\n\n```java\nclass A {}\n```\n\n```\n*raw*\n```\n",
+            render(
+                "{code:language=bash}\n$ ls\n{code}\n{code:java}\nclass A {}\n{code}\n\
+                 {noformat}*raw*{noformat}"
+            )
+        );
+        // code with a fence in it gets a longer fence
+        assert_eq!(
+            "````\n```\ninner\n```\n````\n",
+            render("{code}\n```\ninner\n```\n{code}")
+        );
+    }
+
+    #[test]
+    fn test_macros() {
+        assert_eq!(
+            "> quoted **text**\n",
+            render("{quote}\nquoted *text*\n{quote}")
+        );
+        assert_eq!("> quick\n", render("bq. quick"));
+        assert_eq!(
+            "> [!WARNING]\n> **Careful**\n>\n> body\n",
+            render("{warning:title=Careful}body{warning}")
+        );
+        assert_eq!(
+            "> [!CAUTION]\n> body\n",
+            render("{panel:title=Caution|borderColor=#d1242f}\nbody\n{panel}")
+        );
+        assert_eq!(
+            "<details>\n<summary>More</summary>\n\nhidden\n\n</details>\n",
+            render("{expand:More}\nhidden\n{expand}")
+        );
+    }
+
+    #[test]
+    fn test_breaks_and_rules() {
+        assert_eq!(
+            "one\\\ntwo\\\nthree\n\n---\n",
+            render("one\ntwo\\\\three\n\n----")
+        );
+    }
+
+    #[test]
+    fn test_invert_lang_map() {
+        let inverted = invert_lang_map(&RenderOptions::new().language_map());
+        assert_eq!(Some(&"bash".to_string()), inverted.get("bash"));
+        assert_eq!(Some(&"c++".to_string()), inverted.get("c++"));
+    }
+
+    #[test]
+    fn test_page_properties() {
+        assert_eq!(
+            "<!-- details id=adr -->\n\n| a |\n| --- |\n| 1 |\n\n\
+            <!-- detailssummary headings=Status label=adr -->\n",
+            render(
+                "{details:id=adr}\n||a||\n|1|\n{details}\n\n\
+                {detailssummary:label=adr|headings=Status}"
+            )
+        );
+        assert_eq!(
+            "<!-- detailssummary cql=\"label = adr|x\" -->\n",
+            render("{detailssummary:cql=label = adr&#124;x}")
+        );
+    }
+}
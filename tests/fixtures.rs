@@ -0,0 +1,107 @@
+//! Corpus-driven tests over `tests/fixtures`
+//!
+//! Every `NAME.md` is converted with default options and compared against `NAME.jira` and
+//! `NAME.confluence`, when present. Each fixture is also taken Markdown -> wiki -> Markdown for both
+//! flavors, and the set of fixtures that don't survive the trip must match `lossy.txt`, so a change
+//! that makes a construct lossy (or fixes one) shows up in review.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use markrust::{Converter, Flavor, InputFormat, OutputFormat, RenderOptions};
+
+/// Flavors under test, with the extension of their expected output
+const FLAVORS: [(Flavor, &str); 2] = [(Flavor::Jira, "jira"), (Flavor::Confluence, "confluence")];
+
+/// Directory holding the fixtures
+fn fixture_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+/// Names of all markdown fixtures, sorted
+fn fixtures() -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(fixture_dir())
+        .unwrap()
+        .filter_map(|entry| {
+            let path = entry.unwrap().path();
+            match path.extension() {
+                Some(ext) if ext == "md" => Some(path.file_stem()?.to_str()?.to_string()),
+                _ => None,
+            }
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+/// Converts `input` with default options apart from the given formats and flavor
+fn convert(input: &str, from: InputFormat, to: OutputFormat, flavor: Flavor) -> String {
+    let options = RenderOptions::new().input(from).format(to).flavor(flavor);
+    Converter::new(options).convert(input).unwrap()
+}
+
+#[test]
+fn test_expected_output() {
+    let mut failures = vec![];
+    let mut checked = 0;
+    for name in fixtures() {
+        let input = fs::read_to_string(fixture_dir().join(format!("{}.md", name))).unwrap();
+        for (flavor, ext) in FLAVORS {
+            let path = fixture_dir().join(format!("{}.{}", name, ext));
+            let Ok(expected) = fs::read_to_string(&path) else {
+                continue;
+            };
+            checked += 1;
+            let actual = convert(&input, InputFormat::Markdown, OutputFormat::Wiki, flavor);
+            if actual != expected {
+                failures.push(format!(
+                    "{}.{}\n--- expected\n{}\n--- actual\n{}",
+                    name, ext, expected, actual
+                ));
+            }
+        }
+    }
+    assert!(checked > 0, "no fixtures found");
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn test_round_trip_lossiness() {
+    let mut lossy = BTreeSet::new();
+    for name in fixtures() {
+        let input = fs::read_to_string(fixture_dir().join(format!("{}.md", name))).unwrap();
+        // compare against markdown that has been through the writer, so formatting choices like
+        // list markers don't count as losses
+        let canonical = convert(
+            &input,
+            InputFormat::Markdown,
+            OutputFormat::Markdown,
+            Flavor::default(),
+        );
+        for (flavor, ext) in FLAVORS {
+            let wiki = convert(&input, InputFormat::Markdown, OutputFormat::Wiki, flavor);
+            let back = convert(&wiki, InputFormat::Wiki, OutputFormat::Markdown, flavor);
+            if back != canonical {
+                lossy.insert(format!("{} {}", ext, name));
+            }
+        }
+    }
+
+    let recorded: BTreeSet<String> = fs::read_to_string(fixture_dir().join("lossy.txt"))
+        .unwrap()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    let newly_lossy: Vec<_> = lossy.difference(&recorded).collect();
+    let now_lossless: Vec<_> = recorded.difference(&lossy).collect();
+    assert!(
+        newly_lossy.is_empty() && now_lossless.is_empty(),
+        "round trip lossiness changed, update tests/fixtures/lossy.txt\n\
+         newly lossy: {:?}\nnow lossless: {:?}",
+        newly_lossy,
+        now_lossless
+    );
+}